@@ -1,7 +1,9 @@
 use tonic_build::configure;
 
 fn main() {
-    configure().compile(&[
+    // the server half is only needed by the mock relay
+    let build_server = std::env::var_os("CARGO_FEATURE_RELAY_SERVER").is_some();
+    configure().build_server(build_server).compile(&[
         "protos/relay.proto",
         // "protos/types.proto",
     ], &["protos"]).unwrap();
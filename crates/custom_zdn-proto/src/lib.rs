@@ -4,4 +4,8 @@ pub mod relay {
 pub mod types {
     tonic::include_proto!("types");
 }
+pub mod send_back;
+#[cfg(feature = "relay-server")]
+pub mod mock;
+pub use prost;
 pub use prost_types;
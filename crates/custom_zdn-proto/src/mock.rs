@@ -0,0 +1,165 @@
+//! Scriptable in-process Relay for integration tests, enabled by the `relay-server` feature.
+//!
+//! Register calls are answered from a queue of scripted replies, falling back to a default
+//! reply once the queue is empty. Every request is recorded, and synthetic shreds can be
+//! pushed to the port the last client registered.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use prost::Message;
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Code, Request, Response, Status};
+
+use crate::relay::{
+    relay_server::{Relay, RelayServer},
+    RefreshTokenRequest, RefreshTokenResponse, RegisterErrorDetail, RegisterErrorReason,
+    RegisterRequest, RegisterResponse,
+};
+
+/// one scripted answer to Register.
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Ok(RegisterResponse),
+    Err(Status),
+}
+
+/// status as the production relay sends it: `code` plus an encoded `RegisterErrorDetail`.
+pub fn error_status(code: Code, reason: RegisterErrorReason, message: &str) -> Status {
+    let detail = RegisterErrorDetail {
+        reason: reason as i32,
+        message: message.to_string(),
+    };
+    Status::with_details(code, message, detail.encode_to_vec().into())
+}
+
+/// a successful Register reply carrying `region_host_map` and `jwt_token`.
+pub fn register_ok(region_host_map: HashMap<String, String>, jwt_token: &str) -> RegisterResponse {
+    RegisterResponse {
+        udp_address: "127.0.0.1:8002".to_string(),
+        jwt_token: jwt_token.to_string(),
+        msg: "mock relay".to_string(),
+        region_host_map,
+        ..Default::default()
+    }
+}
+
+#[derive(Default)]
+pub struct MockRelay {
+    script: Mutex<VecDeque<Reply>>,
+    default: Mutex<Option<Reply>>,
+    registrations: Mutex<Vec<RegisterRequest>>,
+    refreshes: Mutex<Vec<RefreshTokenRequest>>,
+    /// where the last successful registration asked shreds to be sent.
+    registered: Mutex<Option<SocketAddr>>,
+    token_seq: AtomicUsize,
+}
+
+impl MockRelay {
+    /// a relay answering every Register with `default`.
+    pub fn new(default: Reply) -> Arc<Self> {
+        let relay = Self::default();
+        *relay.default.lock().unwrap() = Some(default);
+        Arc::new(relay)
+    }
+
+    /// answer the next Register calls with `replies`, in order, before falling back to the default.
+    pub fn script(&self, replies: impl IntoIterator<Item = Reply>) {
+        self.script.lock().unwrap().extend(replies);
+    }
+
+    pub fn set_default(&self, reply: Reply) {
+        *self.default.lock().unwrap() = Some(reply);
+    }
+
+    /// every Register request received so far.
+    pub fn registrations(&self) -> Vec<RegisterRequest> {
+        self.registrations.lock().unwrap().clone()
+    }
+
+    /// every RefreshToken request received so far.
+    pub fn refreshes(&self) -> Vec<RefreshTokenRequest> {
+        self.refreshes.lock().unwrap().clone()
+    }
+
+    pub fn registered_addr(&self) -> Option<SocketAddr> {
+        *self.registered.lock().unwrap()
+    }
+
+    /// send `shreds` to the port of the last successful registration, returns how many were sent.
+    pub async fn push_shreds(&self, shreds: &[Vec<u8>]) -> std::io::Result<usize> {
+        let addr = self.registered_addr().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "no client registered")
+        })?;
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        for shred in shreds {
+            socket.send_to(shred, addr).await?;
+        }
+        Ok(shreds.len())
+    }
+
+    /// serve on an ephemeral localhost port, returns the `http://` url to register against.
+    pub async fn serve(self: &Arc<Self>) -> std::io::Result<(String, JoinHandle<()>)> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let service = RelayServer::new(MockRelayService(Arc::clone(self)));
+        let handle = tokio::spawn(async move {
+            let _ = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await;
+        });
+        Ok((url, handle))
+    }
+
+    fn next_reply(&self) -> Reply {
+        if let Some(reply) = self.script.lock().unwrap().pop_front() {
+            return reply;
+        }
+        self.default
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| Reply::Err(Status::unavailable("mock relay has no reply")))
+    }
+}
+
+struct MockRelayService(Arc<MockRelay>);
+
+#[tonic::async_trait]
+impl Relay for MockRelayService {
+    async fn register(&self, request: Request<RegisterRequest>) -> Result<Response<RegisterResponse>, Status> {
+        let ip = request
+            .remote_addr()
+            .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |addr| addr.ip());
+        let request = request.into_inner();
+        let port = request.server_port as u16;
+        self.0.registrations.lock().unwrap().push(request);
+
+        match self.0.next_reply() {
+            Reply::Ok(response) => {
+                *self.0.registered.lock().unwrap() = Some(SocketAddr::new(ip, port));
+                Ok(Response::new(response))
+            }
+            Reply::Err(status) => Err(status),
+        }
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<Response<RefreshTokenResponse>, Status> {
+        self.0.refreshes.lock().unwrap().push(request.into_inner());
+        let seq = self.0.token_seq.fetch_add(1, Ordering::Relaxed);
+        Ok(Response::new(RefreshTokenResponse {
+            jwt_token: format!("mock-refreshed-{}", seq),
+        }))
+    }
+}
@@ -0,0 +1,189 @@
+//! Authenticated send-back envelope, wraps one shred sent from a client to a region.
+//!
+//! Layout (big-endian):
+//! ```text
+//! | magic "ZD" (2) | version (1) | key_id (4) | seq (8) | mac (16) | shred ... |
+//! ```
+//! `mac` is HMAC-SHA256 over everything before it plus the shred, truncated to 16 bytes.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const MAGIC: [u8; 2] = *b"ZD";
+pub const VERSION: u8 = 1;
+pub const MAC_LEN: usize = 16;
+pub const HEADER_LEN: usize = 2 + 1 + 4 + 8 + MAC_LEN;
+const MAC_OFFSET: usize = HEADER_LEN - MAC_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub key_id: u32,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadMac,
+}
+
+impl std::fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvelopeError::TooShort => write!(f, "envelope too short"),
+            EnvelopeError::BadMagic => write!(f, "bad envelope magic"),
+            EnvelopeError::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            EnvelopeError::BadMac => write!(f, "envelope mac mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// derive the envelope key from the key material (auth key or jwt).
+pub fn derive_key(material: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(b"shred-zdn send-back").expect("hmac accepts any key length");
+    mac.update(material);
+    mac.finalize().into_bytes().into()
+}
+
+/// public id of a derived key, lets the relay look the key up without it being sent.
+pub fn key_id(key: &[u8; 32]) -> u32 {
+    let digest = Sha256::digest(key);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// append the envelope for `shred` to `out`.
+pub fn seal(key: &[u8; 32], header: Header, shred: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    out.reserve(HEADER_LEN + shred.len());
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&header.key_id.to_be_bytes());
+    out.extend_from_slice(&header.seq.to_be_bytes());
+
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&out[start..]);
+    mac.update(shred);
+    out.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+    out.extend_from_slice(shred);
+}
+
+/// read the header without verifying the mac, used to pick the key by `key_id`.
+pub fn peek(packet: &[u8]) -> Result<Header, EnvelopeError> {
+    if packet.len() < HEADER_LEN {
+        return Err(EnvelopeError::TooShort);
+    }
+    if packet[..2] != MAGIC {
+        return Err(EnvelopeError::BadMagic);
+    }
+    if packet[2] != VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(packet[2]));
+    }
+    Ok(Header {
+        key_id: u32::from_be_bytes(packet[3..7].try_into().unwrap()),
+        seq: u64::from_be_bytes(packet[7..15].try_into().unwrap()),
+    })
+}
+
+/// verify the envelope with `key` and return its header and the wrapped shred.
+pub fn open<'a>(key: &[u8; 32], packet: &'a [u8]) -> Result<(Header, &'a [u8]), EnvelopeError> {
+    let header = peek(packet)?;
+    let shred = &packet[HEADER_LEN..];
+
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&packet[..MAC_OFFSET]);
+    mac.update(shred);
+    mac.verify_truncated_left(&packet[MAC_OFFSET..HEADER_LEN])
+        .map_err(|_| EnvelopeError::BadMac)?;
+    Ok((header, shred))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHRED: &[u8] = b"a shred payload";
+
+    fn sealed(key: &[u8; 32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        seal(key, Header { key_id: key_id(key), seq: 42 }, SHRED, &mut out);
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = derive_key(b"auth key");
+        let packet = sealed(&key);
+        assert_eq!(packet.len(), HEADER_LEN + SHRED.len());
+
+        let (header, shred) = open(&key, &packet).unwrap();
+        assert_eq!(header, Header { key_id: key_id(&key), seq: 42 });
+        assert_eq!(shred, SHRED);
+        assert_eq!(peek(&packet).unwrap(), header);
+    }
+
+    #[test]
+    fn seal_appends() {
+        let key = derive_key(b"auth key");
+        let mut out = b"prefix".to_vec();
+        seal(&key, Header { key_id: key_id(&key), seq: 42 }, SHRED, &mut out);
+        assert_eq!(&out[..6], b"prefix");
+        assert_eq!(out[6..], sealed(&key)[..]);
+    }
+
+    #[test]
+    fn tampered_mac_or_shred() {
+        let key = derive_key(b"auth key");
+        let mut packet = sealed(&key);
+        packet[MAC_OFFSET] ^= 1;
+        assert_eq!(open(&key, &packet), Err(EnvelopeError::BadMac));
+
+        let mut packet = sealed(&key);
+        *packet.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&key, &packet), Err(EnvelopeError::BadMac));
+    }
+
+    #[test]
+    fn wrong_key_or_key_id() {
+        let key = derive_key(b"auth key");
+        let other = derive_key(b"another key");
+        assert_ne!(key_id(&key), key_id(&other));
+        assert_eq!(open(&other, &sealed(&key)), Err(EnvelopeError::BadMac));
+
+        // the key id is covered by the mac
+        let mut packet = sealed(&key);
+        packet[3..7].copy_from_slice(&key_id(&other).to_be_bytes());
+        assert_eq!(peek(&packet).unwrap().key_id, key_id(&other));
+        assert_eq!(open(&key, &packet), Err(EnvelopeError::BadMac));
+    }
+
+    #[test]
+    fn wrong_magic_or_version() {
+        let key = derive_key(b"auth key");
+        let mut packet = sealed(&key);
+        packet[0] = b'X';
+        assert_eq!(peek(&packet), Err(EnvelopeError::BadMagic));
+        assert_eq!(open(&key, &packet), Err(EnvelopeError::BadMagic));
+
+        let mut packet = sealed(&key);
+        packet[2] = VERSION + 1;
+        assert_eq!(peek(&packet), Err(EnvelopeError::UnsupportedVersion(VERSION + 1)));
+        assert_eq!(open(&key, &packet), Err(EnvelopeError::UnsupportedVersion(VERSION + 1)));
+    }
+
+    #[test]
+    fn truncated() {
+        let key = derive_key(b"auth key");
+        let packet = sealed(&key);
+        assert_eq!(peek(&packet[..HEADER_LEN - 1]), Err(EnvelopeError::TooShort));
+        assert_eq!(open(&key, &packet[..HEADER_LEN - 1]), Err(EnvelopeError::TooShort));
+        assert_eq!(open(&key, &[]), Err(EnvelopeError::TooShort));
+        // a cut shred fails the mac
+        assert_eq!(open(&key, &packet[..packet.len() - 1]), Err(EnvelopeError::BadMac));
+    }
+}
@@ -25,7 +25,7 @@ pub async fn generate_channel(
             connector.enforce_http(false);
             connector.set_nodelay(true);
             connector.set_keepalive(Some(Duration::from_secs(1)));        
-            connector.set_local_address(ip.map(|ip| IpAddr::V4(ip)));            
+            connector.set_local_address(ip.map(IpAddr::V4));            
             connector
         }).await        
     } else { 
@@ -1,7 +1,45 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    sync::{OnceLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// the installed logger, swapped on `set_log_filters`.
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+struct ReloadableLogger(RwLock<env_logger::Logger>);
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
 
 // init env-logger
 pub fn init_env_logger() {
+    let logger = build_logger(None);
+    log::set_max_level(logger.filter());
+    let logger = LOGGER.get_or_init(|| ReloadableLogger(RwLock::new(logger)));
+    let _ = log::set_logger(logger);
+}
+
+/// replace the filters of the running logger, e.g. `info,shred_zdn=debug`, on top of `RUST_LOG`.
+pub fn set_log_filters(filters: &str) {
+    if let Some(reloadable) = LOGGER.get() {
+        let logger = build_logger(Some(filters));
+        log::set_max_level(logger.filter());
+        *reloadable.0.write().unwrap() = logger;
+    }
+}
+
+fn build_logger(filters: Option<&str>) -> env_logger::Logger {
     let mut builder = env_logger::Builder::new();
 
     // set filter level
@@ -18,6 +56,9 @@ pub fn init_env_logger() {
     if let Ok(rust_log) = std::env::var(env_logger::DEFAULT_FILTER_ENV) {
         builder.parse_filters(rust_log.as_str());
     }
+    if let Some(filters) = filters {
+        builder.parse_filters(filters);
+    }
 
     // log output to Stdout
     builder.target(env_logger::Target::Stdout);
@@ -34,7 +75,7 @@ pub fn init_env_logger() {
         )
     });
         
-    builder.init();
+    builder.build()
 }
 
 #[inline]
@@ -57,8 +98,8 @@ pub fn diff_time(start: SystemTime, stop: SystemTime) -> i128 {
         stop.duration_since(start)
             .unwrap().as_micros() as i128
     } else {
-        start.duration_since(stop)
-            .unwrap().as_micros() as i128 * -1
+        -(start.duration_since(stop)
+            .unwrap().as_micros() as i128)
     }
 }
 
@@ -10,10 +10,32 @@ pub mod common {
 
 pub mod shred_zdn {           
     pub mod zdn_ping;
+    pub mod probe;
     pub mod args;
+    pub mod config;
+    pub mod reload;
+    pub mod inspect;
+    pub mod replay;
+    pub mod bench;
     pub mod stats;
     pub mod receiver;
+    pub mod rx_timestamp;
     pub mod processor;
     pub mod sniffer;
+    pub mod send_back;
+    pub mod session;
+    pub mod tx_proxy;
+    pub mod failover;
+    pub mod ranker;
+    pub mod calibration;
+    pub mod race;
+    pub mod deshred;
+    pub mod entry_stream;
+    pub mod shm_ring;
+    pub mod slots;
+    pub mod slot_window;
+    pub mod lifecycle;
+    pub mod admin;
+    pub mod metrics;
     pub mod consts;
 }
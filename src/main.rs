@@ -1,196 +1,367 @@
-use std::{    
-    sync::{Arc, atomic::AtomicUsize, RwLock},
-    time::Duration,
-    collections::HashMap,
-};
+use std::{collections::HashMap, sync::{Arc, atomic::AtomicUsize}, time::Duration};
 
 use log::{info, warn, error};
-use clap::{Parser};
+use clap::{error::ErrorKind, CommandFactory};
+use tokio::signal::unix::{signal, SignalKind};
 
-use shred_zdn::common::{
-    utils::init_env_logger,
-    net_utils::tonic::generate_channel,
-};
+use shred_zdn::common::utils::{init_env_logger, set_log_filters};
 use shred_zdn::shred_zdn:: {
-        zdn_ping::{
-            sort_regions,
-            resolve_nearest_n_region_addrs
-        },
-        args::{Opts, Protocol},
+        args::{Command, ConfigCommand, Opts, RegistrationErrorPolicy},
+        config::FileConfig,
         stats::Stats,
         receiver::start_receivers,
         processor::start_processor,
-        sniffer::start_sniffer,
-        consts::{VERSION, HOST},
+        sniffer::{open_capture, open_recording, start_sniffer},
+        send_back::{start_send_back_resolver, SendBack},
+        session::{RegistrationError, Session},
+        tx_proxy::{start_tx_proxy, TxProxy},
+        lifecycle::{start_discovery, start_registration, start_standalone, Lifecycle, LifecycleState},
+        race::start_race_analyzer,
+        entry_stream::start_entry_stream,
+        shm_ring::ShmRingWriter,
+        reload::LiveConfig,
+        inspect::{inspect_port, inspect_recording},
+        replay::replay,
+        bench::{run_bench, BenchConfig},
+        zdn_ping::rank_regions,
+        consts::REQUEST_REGION,
+        slots::start_slot_tracker,
+        admin::{start_admin, AdminState},
 };
-use zdn_proto::relay::{relay_client::RelayClient, RegisterRequest};
 
 #[tokio::main]
 async fn main() {
+    let opts = Opts::load().unwrap_or_else(|e| Opts::command().error(ErrorKind::ValueValidation, e).exit());
+    if let Some(Command::Config { action: ConfigCommand::Check }) = &opts.command {
+        print!("{}", FileConfig::effective(&opts).to_toml());
+        return;
+    }
     init_env_logger();
-    let opts = Opts::parse();
+    if let Some(filters) = &opts.log_level {
+        set_log_filters(filters);
+    }
+    match opts.command.clone() {
+        None | Some(Command::Run) | Some(Command::Config { .. }) => run(opts).await,
+        Some(Command::ProbeRegions) => probe_regions(&opts).await,
+        Some(Command::Inspect { port: Some(port), count, .. }) => {
+            if let Err(e) = inspect_port(port, count).await {
+                exit_with(format!("inspect on port {} failed: {}", port, e));
+            }
+        }
+        Some(Command::Inspect { pcap, filter, count, .. }) => {
+            let path = pcap.expect("validated");
+            let recording = open_recording(&path, filter.as_deref())
+                .unwrap_or_else(|e| exit_with(format!("Failed to open {}: {}", path.display(), e)));
+            let result = tokio::task::spawn_blocking(move || inspect_recording(recording, count)).await.unwrap();
+            match result {
+                Ok(packets) => info!("{} packets", packets),
+                Err(e) => exit_with(format!("Failed to read {}: {}", path.display(), e)),
+            }
+        }
+        Some(Command::Replay { pcap, filter, to, speed }) => {
+            let to = if to.is_empty() { opts.forwards.clone() } else { to };
+            let recording = open_recording(&pcap, filter.as_deref())
+                .unwrap_or_else(|e| exit_with(format!("Failed to open {}: {}", pcap.display(), e)));
+            let socket = std::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).expect("bind failed");
+            let result = tokio::task::spawn_blocking(move || replay(recording, &socket, &to, speed)).await.unwrap();
+            match result {
+                Ok(summary) => info!("{}", summary),
+                Err(e) => exit_with(format!("Failed to read {}: {}", pcap.display(), e)),
+            }
+        }
+        Some(Command::Bench { target, listen, rate, secs, duplicates }) => {
+            let config = BenchConfig {
+                target,
+                listen,
+                rate,
+                duration: Duration::from_secs(secs),
+                duplicates,
+            };
+            match run_bench(config).await {
+                Ok(report) => info!("bench: {}", report),
+                Err(e) => exit_with(format!("bench failed: {}", e)),
+            }
+        }
+    }
+}
+
+/// receive, dedup and forward shreds until SIGINT or SIGTERM, then drain and print the final stats.
+async fn run(mut opts: Opts) {
     let stats = Arc::new(Stats::new());
     let counter = Arc::new(AtomicUsize::new(0));
     let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
-    let (sender_sl, receiver_sl) = tokio::sync::mpsc::unbounded_channel();    
-
-    // socket for forwarding shreds to validator    
-    let forward_socket = tokio::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();    
-
-    // network interface (lo0 for macOS's loopback, "lo" for Linux)
-    let interface = opts.interface.as_str();
-    let mut cap = pcap::Capture::from_device(interface)
-        .expect("Failed to open device")
-        .promisc(true)
-        .immediate_mode(true)
-        .snaplen(65535)
-        .open()
-        .expect("Capture init failed")
-        .setnonblock()
-        .expect("Failed to set non-blocking");
-    
-    // header length of different links：Ethernet=14，Loopback=NULL=4
-    let header_len = match cap.get_datalink().0 {
-        1 => 14,
-        0 => 4,
-        other => {
-            warn!("Unknown link type {}, default to 4-byte", other);
-            4
+    let (sender_sl, receiver_sl) = tokio::sync::mpsc::unbounded_channel();
+
+    // socket for forwarding shreds to validator
+    let forward_socket = tokio::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+
+    // network interfaces to sniff (lo0 for macOS's loopback, "lo" for Linux)
+    let captures = opts
+        .sniff_specs()
+        .iter()
+        .map(|spec| open_capture(spec, &opts.protocol)
+            .unwrap_or_else(|e| panic!("Capture init failed on {}: {}", spec.interface, e)))
+        .collect::<Vec<_>>();
+    if opts.no_sniff {
+        info!("running without captures, nothing is sent back");
+    }
+
+    // Discovering → Probing → Registered / Degraded
+    let lifecycle = Lifecycle::new(opts.region_filter());
+
+    // settings applied live on SIGHUP
+    let live = LiveConfig::new(&opts, &lifecycle);
+
+    // send-back addresses and format, set by the resolver and registration
+    let send_back = Arc::new(SendBack::new());
+    let send_back_modes = SendBack::offered_modes(opts.auth_send_back);
+
+    // local sendTransaction proxy, forwarders come from registration
+    let tx_proxy = match opts.tx_proxy {
+        Some(_) => Some(Arc::new(TxProxy::new().await)),
+        None => None,
+    };
+
+    let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel::<RegistrationError>();
+    let register_handle = if opts.standalone {
+        // no control plane, send back to the static regions.
+        info!("starting standalone");
+        drop(error_tx);
+        start_standalone(opts.static_region.clone(), &lifecycle)
+    } else {
+        // register to keep online, fatal errors are reported back here.
+        info!("starting registration");
+        let session = Session::new(&opts.relay, opts.auth.as_deref().unwrap_or_default(), opts.port, send_back_modes, &stats);
+        let register_handle = start_registration(
+            session,
+            opts.failover_policy(),
+            opts.probe_config(),
+            opts.register_interval(),
+            &lifecycle,
+            &counter,
+            &send_back,
+            tx_proxy.clone(),
+            error_tx,
+        );
+
+        // find the nearest region once the relay returns the region map.
+        drop(start_discovery(
+            opts.startup_timeouts(),
+            opts.probe_config(),
+            opts.calibration_policy(),
+            opts.rerank_policy(),
+            &lifecycle,
+            &stats,
+        ));
+        register_handle
+    };
+
+    // per-slot completeness and loss of every source.
+    let slots = start_slot_tracker(&stats);
+    // per-slot comparison of the sources.
+    let race = opts.race_report.clone().map(start_race_analyzer);
+    // decoded entries for WebSocket clients.
+    let entries = match opts.entry_stream {
+        Some(addr) => match start_entry_stream(addr, opts.entry_stream_buffer).await {
+            Ok(entries) => Some(entries),
+            Err(e) => exit_with(format!("Failed to start the entry stream on {}: {}", addr, e)),
+        },
+        None => None,
+    };
+
+    // send back to the top regions, following ranking and DNS changes.
+    drop(start_send_back_resolver(live.send_back.subscribe(), &lifecycle, &send_back));
+
+    // sniff shreds of validator and send back to 0slot.trade to speed up.
+    info!("starting sniffer");
+    let sniffer = start_sniffer(captures, &stats, Arc::new(forward_socket), &send_back, receiver_sl, Arc::clone(&slots), race.clone(), entries.clone(), opts.dedup_rotation()).await;
+    // receive shreds from 0slot.trade.
+    info!("starting receivers");
+    let receivers = start_receivers(&opts, &stats, &sender, &sender_sl, &slots, race.as_ref(), entries.as_ref()).await;
+    // accepted shreds for co-located readers.
+    let ring = opts.shm_ring.as_ref().map(|path| {
+        ShmRingWriter::create(path, opts.shm_ring_capacity)
+            .unwrap_or_else(|e| exit_with(format!("Failed to create the shared-memory ring {}: {}", path.display(), e)))
+    });
+    // forward shreds to validator.
+    info!("starting processor");
+    let processor = start_processor(&opts, &stats, receiver, &counter, live.forwards.subscribe(), ring).await;
+    // forward transactions to the relay's forwarders.
+    let tx_server = match (opts.tx_proxy, &tx_proxy) {
+        (Some(addr), Some(tx_proxy)) => {
+            info!("starting tx proxy");
+            Some(start_tx_proxy(addr, tx_proxy).await)
         }
+        _ => None,
     };
-    let payload_offset = header_len + 20 + 8;
-    let proto = match opts.protocol {
-        Protocol::Udp => "udp",
-        Protocol::Tcp => "tcp",
+    // expose state to operators.
+    if let Some(addr) = opts.admin {
+        let state = AdminState {
+            lifecycle: Arc::clone(&lifecycle),
+            send_back: Arc::clone(&send_back),
+            stats: Arc::clone(&stats),
+            tx_proxy: tx_proxy.clone(),
+        };
+        drop(start_admin(addr, state).await);
+    }
+
+    // print stats
+    let report = || {
+        info!("stats: {}, lifecycle {}", stats.report(), lifecycle.state());
+        if let Some(tx_proxy) = &tx_proxy {
+            info!("tx-proxy: {}", tx_proxy.report());
+        }
+        info!("latency: {}", stats.latency.report());
+        if let Some(race) = &race {
+            info!("race: {}", race.report());
+        }
+        if let Some(entries) = &entries {
+            info!("entry-stream: {}", entries.report());
+        }
     };
-    cap.filter(&format!("{} dst port {}", proto, opts.sniffer_port), true).unwrap();
-
-    // region map return from server
-    let region_map = Arc::new(RwLock::new(HashMap::<String, String>::new()));
-    // set to request-region to request region map from server
-    let region = Arc::new(RwLock::new("request-region".to_string()));
-
-    // register runtime
-    let runtime = Arc::new(
-        tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(1)
-            .thread_name("async")
-            .enable_all()
-            .build()
-            .unwrap()
-    );    
-    let auth_key = opts.auth.clone();
-    let port = opts.port;    
-    let counter_clone = counter.clone();
-    let region_clone = Arc::clone(&region);
-    let region_map_clone = Arc::clone(&region_map);
-    runtime.spawn(async move {
-        let mut is_registered: bool = false;
-        loop {
-            let region_str = {
-                        let guard = region_clone.read().unwrap();
-                        guard.clone()
-                    }; // release read lock
-            // register to keep online.
-            match generate_channel(HOST, None).await {
-                Ok(channel) => {
-                    let mut client = RelayClient::new(channel);
-                    match client.register(RegisterRequest {
-                        auth_header: auth_key.clone(),
-                        version: VERSION.to_string(),
-                        server_port: port as _,
-                        region: region_str.clone(),
-                    }).await {
-                        Ok(response) => {
-                            let inner = response.into_inner();
-                            if region_str != "request-region" && !is_registered {
-                                is_registered = true;
-                                info!("Registered: {}, addr={}", inner.msg, inner.udp_address);
-                            }
-                            // get region map from server
-                            if !inner.region_host_map.is_empty() {
-                                let mut map = region_map_clone.write().unwrap();
-                                *map = inner
-                                    .region_host_map
-                                    .iter()
-                                    .map(|(k, v)| (k.clone(), v.clone()))
-                                    .collect();
-                                if region_str == "request-region" {
-                                    info!("get region map from server");
-                                }                                
-                            }                            
-                        }
-                        Err(err) => {                            
-                            if err.message().contains("ipv6") {
-                                error!("IPv6 not supported, please switch to IPv4");
-                                std::process::exit(1);
-                            }
-                            if err.message().contains("auth_key") {
-                                error!("Invalid auth key");
-                                std::process::exit(2);
-                            }
-                            if err.message().contains("limit") {
-                                error!("Exceeded IP registration limit");
-                                std::process::exit(3);
-                            }
-                            warn!("Register failed: {}, retrying...", err);
+    let policy = opts.on_registration_error;
+    let mut stats_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + opts.stats_interval(),
+        opts.stats_interval(),
+    );
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    loop {
+        tokio::select! {
+            _ = stats_interval.tick() => report(),
+            _ = interrupt.recv() => {
+                info!("SIGINT, shutting down");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("SIGTERM, shutting down");
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("SIGHUP, reloading the config");
+                match live.reload(&opts) {
+                    Ok(applied) => {
+                        if applied.stats_interval() != opts.stats_interval() {
+                            stats_interval = tokio::time::interval_at(
+                                tokio::time::Instant::now() + applied.stats_interval(),
+                                applied.stats_interval(),
+                            );
                         }
+                        opts = applied;
                     }
+                    Err(e) => error!("reload failed, keeping the running config: {}", e),
                 }
-                Err(_) => warn!("Unable to connect to ZDN, retrying...")
             }
-            
-            let recent = counter_clone.load(std::sync::atomic::Ordering::Relaxed);
-            // wait to see if any shreds received.
-            tokio::time::sleep(Duration::from_secs(3)).await;
-            let current = counter_clone.load(std::sync::atomic::Ordering::Relaxed);
-            if current == recent {
-                warn!("No recent shreds received...");
+            Some(err) = error_rx.recv() => {
+                if !handle_registration_error(err, policy) {
+                    register_handle.abort();
+                    lifecycle.transition(LifecycleState::Degraded { reason: "sniff-only".to_string() });
+                }
             }
         }
-    });
+    }
 
-    // wait until region_map is populated
-    loop {
-        {
-            let map = region_map.read().unwrap();
-            if !map.is_empty() {
-                break;
+    // the relay has no unregister call, it drops the registration once the keep-alives stop
+    register_handle.abort();
+    lifecycle.transition(LifecycleState::Stopping);
+
+    // no new shreds: the capture threads end at their next poll, the receivers right away.
+    // the processor and the sniffer end once every sender of their queue is gone.
+    sniffer.stop();
+    for receiver in receivers {
+        receiver.abort();
+    }
+    drop(sender);
+    drop(sender_sl);
+    // no new transactions, the requests being read are still answered.
+    if let Some(tx_proxy) = &tx_proxy {
+        tx_proxy.stop();
+    }
+
+    let drain = async {
+        let _ = processor.await;
+        sniffer.join().await;
+        // every source of the entry stream stopped
+        if let Some(entries) = &entries {
+            entries.close().await;
+        }
+        if let Some(race) = &race {
+            race.flush().await;
+        }
+        slots.flush().await;
+        if let Some(server) = tx_server {
+            let _ = server.await;
+        }
+        if let Some(tx_proxy) = &tx_proxy {
+            tx_proxy.drain().await;
+        }
+    };
+    let timeout = opts.drain_timeout();
+    info!("draining, up to {:?}", timeout);
+    tokio::select! {
+        drained = tokio::time::timeout(timeout, drain) => {
+            if drained.is_err() {
+                warn!("not drained after {:?}, stopping anyway", timeout);
             }
         }
-        std::thread::sleep(std::time::Duration::from_millis(300));
+        _ = interrupt.recv() => warn!("SIGINT again, stopping without draining"),
+        _ = terminate.recv() => warn!("SIGTERM again, stopping without draining"),
     }
+    report();
+}
 
-    let region_map_clone = {
-        let map_guard = region_map.read().unwrap();        
-        map_guard.clone() 
-    }; // release read lock
-    // find the nearest region
-    let sorted_regions = sort_regions(&region_map_clone);
-    let nearest_region = &sorted_regions[0];
-    {
-        let mut region_guard = region.write().unwrap();
-        *region_guard = nearest_region.clone(); // write the nearest region back so that we can register using it.
+/// print the regions ordered by probe latency, from `--static-region` or the relay's region map.
+async fn probe_regions(opts: &Opts) {
+    let host_map = if opts.static_region.is_empty() {
+        let stats = Arc::new(Stats::new());
+        let send_back_modes = SendBack::offered_modes(opts.auth_send_back);
+        let mut session = Session::new(&opts.relay, opts.auth.as_deref().unwrap_or_default(), opts.port, send_back_modes, &stats);
+        match session.keep_alive(REQUEST_REGION).await {
+            Ok(response) => response.region_host_map,
+            Err(err) => {
+                error!("Failed to get the region map: {}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+    } else {
+        opts.static_region.iter().cloned().collect::<HashMap<_, _>>()
+    };
+    let host_map = opts.region_filter().apply(&host_map);
+    if host_map.is_empty() {
+        exit_with("no regions to probe".to_string());
     }
-    info!("✅ Nearest region: {}", nearest_region);    
 
-    //let region = find_nearest_region().expect("Unable to determine nearest region");
-    let n = 3;
-    let send_back_addrs = resolve_nearest_n_region_addrs(&region_map_clone, &sorted_regions, n);
+    let ranking = rank_regions(&host_map, &opts.probe_config()).await;
+    for (rank, (region, ms)) in ranking.iter().enumerate() {
+        println!("{:>2}. {:<16} {:>5}ms  {}", rank + 1, region, ms, host_map[region]);
+    }
+    let mut unreachable = host_map.keys().filter(|region| !ranking.iter().any(|(ranked, _)| ranked == *region)).collect::<Vec<_>>();
+    unreachable.sort();
+    for region in unreachable {
+        println!("    {:<16}     -    {} (no answer)", region, host_map[region]);
+    }
+}
 
-    // sniff shreds of validator and send back to 0slot.trade to speed up.    
-    info!("starting sniffer");
-    let _ = start_sniffer(cap, payload_offset, Arc::new(forward_socket), Arc::new(send_back_addrs), receiver_sl).await;
-    // receive shreds from 0slot.trade.    
-    info!("starting receivers");
-    let _ = start_receivers(&opts, &stats, &sender, &sender_sl).await;    
-    // forward shreds to validator.
-    info!("starting processor");    
-    let _ = start_processor(&opts, &stats, receiver, &counter).await;
+fn exit_with(message: String) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
+}
 
-    // print stats
-    loop {
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        info!("stats: {}", stats.report());
+/// apply the policy to a fatal registration error, returns false when registration should stop.
+fn handle_registration_error(err: RegistrationError, policy: RegistrationErrorPolicy) -> bool {
+    match policy {
+        RegistrationErrorPolicy::Exit => {
+            error!("{}", err);
+            std::process::exit(err.exit_code());
+        }
+        RegistrationErrorPolicy::Retry => {
+            warn!("{}, retrying...", err);
+            true
+        }
+        RegistrationErrorPolicy::SniffOnly => {
+            error!("{}, stop registering and continue sniff-only", err);
+            false
+        }
     }
 }
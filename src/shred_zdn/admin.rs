@@ -0,0 +1,76 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info};
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::shred_zdn::{
+    lifecycle::{Lifecycle, LifecycleState},
+    metrics,
+    send_back::SendBack,
+    stats::Stats,
+    tx_proxy::TxProxy,
+};
+
+/// shared state the admin api reads from.
+pub struct AdminState {
+    pub lifecycle: Arc<Lifecycle>,
+    pub send_back: Arc<SendBack>,
+    pub stats: Arc<Stats>,
+    pub tx_proxy: Option<Arc<TxProxy>>,
+}
+
+/// serve the admin api on `addr`:
+/// - `GET /state`: lifecycle state, region ranking and send-back addresses as JSON.
+/// - `GET /metrics`: counters and gauges in the Prometheus text format.
+pub async fn start_admin(addr: SocketAddr, state: AdminState) -> JoinHandle<()> {
+    let state = Arc::new(state);
+    let make_svc = make_service_fn(move |_| {
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&state), req)))
+        }
+    });
+    let server = Server::bind(&addr).serve(make_svc);
+    info!("admin api listening on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("admin api server failed: {}", e);
+        }
+    })
+}
+
+async fn handle(state: Arc<AdminState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/state") => {
+            let lifecycle = state.lifecycle.state();
+            let (region, reason) = match &lifecycle {
+                LifecycleState::Registered { region } => (Some(region.clone()), None),
+                LifecycleState::Degraded { reason } => (None, Some(reason.clone())),
+                _ => (None, None),
+            };
+            let body = json!({
+                "state": lifecycle.name(),
+                "region": region,
+                "reason": reason,
+                "regions": state.lifecycle.regions.borrow().clone(),
+                "send_back_addrs": state.send_back.addrs().iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+                "send_back_mode": format!("{:?}", state.send_back.mode()),
+            });
+            Response::builder()
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        }
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render(&state.stats, &state.lifecycle, &state.send_back, state.tx_proxy.as_deref())))
+            .unwrap(),
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+    };
+    Ok(response)
+}
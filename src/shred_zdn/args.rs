@@ -1,48 +1,587 @@
-use std::net::SocketAddr;
-use clap::{Parser, ValueEnum};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, ValueEnum)]
+use crate::shred_zdn::{
+    calibration::CalibrationPolicy,
+    config::{validate, ConfigError, FileConfig},
+    consts::HOST,
+    failover::FailoverPolicy,
+    lifecycle::StartupTimeouts,
+    probe::ProbeConfig,
+    ranker::RerankPolicy,
+    send_back::SendBackConfig,
+    zdn_ping::RegionFilter,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Protocol {
     Udp,
     Tcp,
 }
 
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+        }
+    }
+}
+
+/// what to do when registration fails in a way that retrying will not fix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationErrorPolicy {
+    /// exit with the error's exit code
+    Exit,
+    /// log and keep retrying
+    Retry,
+    /// stop registering and keep running the sniffer and the other sources
+    SniffOnly,
+}
+
+/// how region latency is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProbeMethod {
+    /// ICMP echo, or TCP connect when unprivileged ICMP sockets are not allowed
+    Auto,
+    /// ICMP echo over an unprivileged datagram socket
+    Icmp,
+    /// TCP connect to the probe port
+    Tcp,
+    /// UDP echo against the probe port
+    Udp,
+}
+
+/// which side of the sniffed port the captured traffic is on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SniffDirection {
+    /// traffic sent to the port (`dst port`), the default for a validator's tvu.
+    #[default]
+    In,
+    /// traffic sent from the port (`src port`).
+    Out,
+    /// both directions (`port`).
+    InOut,
+}
+
+/// one capture: an interface, the ports to watch on it and the direction.
+/// format: `<interface>:<port>[,<port>...][:in|out|inout]`, e.g. `eth0:8001,8003:in` or `eth0:1:8001`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniffSpec {
+    pub interface: String,
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub direction: SniffDirection,
+}
+
+impl SniffSpec {
+    /// generate the BPF filter for this capture, e.g. `udp and (dst port 8001 or dst port 8003)`.
+    pub fn bpf_filter(&self, protocol: &Protocol) -> String {
+        let qualifier = match self.direction {
+            SniffDirection::In => "dst port",
+            SniffDirection::Out => "src port",
+            SniffDirection::InOut => "port",
+        };
+        let ports = self.ports
+            .iter()
+            .map(|port| format!("{} {}", qualifier, port))
+            .collect::<Vec<_>>()
+            .join(" or ");
+        format!("{} and ({})", protocol.as_str(), ports)
+    }
+}
+
+impl FromStr for SniffSpec {
+    type Err = String;
+
+    /// parsed from the right, interface aliases such as `eth0:1` contain a colon.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        let mut direction = SniffDirection::In;
+        // ports are digits, a trailing word is the direction
+        if let Some((head, last)) = s.rsplit_once(':') {
+            let last = last.trim();
+            if !last.is_empty() && last.chars().all(|c| c.is_ascii_alphabetic()) {
+                direction = match last.to_ascii_lowercase().as_str() {
+                    "in" => SniffDirection::In,
+                    "out" => SniffDirection::Out,
+                    "inout" => SniffDirection::InOut,
+                    other => return Err(format!("invalid direction '{}', expected in, out or inout", other)),
+                };
+                rest = head;
+            }
+        }
+        let (interface, ports) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing ports in sniff spec '{}'", s))?;
+        let interface = interface.trim();
+        if interface.is_empty() {
+            return Err(format!("missing interface in sniff spec '{}'", s));
+        }
+        let ports = ports
+            .split(',')
+            .map(|p| p.trim().parse::<u16>().map_err(|e| format!("invalid port '{}': {}", p, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { interface: interface.to_string(), ports, direction })
+    }
+}
+
+fn parse_static_region(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, host)) if !name.is_empty() && !host.is_empty() => Ok((name.to_string(), host.to_string())),
+        _ => Err(format!("invalid static region '{}', expected <name>=<host>", s)),
+    }
+}
+
+/// configuration file tools.
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// validate the configuration and print the effective values
+    Check,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// receive, dedup and forward shreds (the default)
+    Run,
+    /// probe the regions and print them ordered by latency
+    ProbeRegions,
+    /// decode and print shred headers received on a port or read from a pcap file
+    Inspect {
+        /// UDP port to receive shreds on
+        #[clap(long, conflicts_with = "pcap")]
+        port: Option<u16>,
+        /// pcap recording to read instead
+        #[clap(long)]
+        pcap: Option<PathBuf>,
+        /// BPF filter applied to the recording, e.g. `udp dst port 8001`
+        #[clap(long, requires = "pcap")]
+        filter: Option<String>,
+        /// stop after this many packets
+        #[clap(long)]
+        count: Option<usize>,
+    },
+    /// resend the UDP payloads of a pcap recording
+    Replay {
+        /// pcap recording to replay
+        #[clap(long)]
+        pcap: PathBuf,
+        /// BPF filter applied to the recording, e.g. `udp dst port 8001`
+        #[clap(long)]
+        filter: Option<String>,
+        /// send to these addresses, comma-separated, the forward addresses by default
+        #[clap(long, value_delimiter = ',')]
+        to: Vec<SocketAddr>,
+        /// playback speed relative to the recording, 0 to send as fast as possible
+        #[clap(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// send synthetic shreds to a running instance and measure what it forwards
+    Bench {
+        /// address to send to, the instance's receive port
+        #[clap(long)]
+        target: SocketAddr,
+        /// receive the forwarded shreds here, a forward address of the instance
+        #[clap(long)]
+        listen: Option<SocketAddr>,
+        /// shreds per second
+        #[clap(long, default_value_t = 10_000)]
+        rate: u64,
+        /// seconds to send for
+        #[clap(long, default_value_t = 10)]
+        secs: u64,
+        /// fraction of the shreds sent twice, to exercise dedup
+        #[clap(long, default_value_t = 0.0)]
+        duplicates: f64,
+    },
+    /// configuration file tools
+    Config {
+        #[clap(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+// required values (`--port`, `--forwards`, `--auth` and a capture) may come from the config
+// file as well, so they are checked by `config::validate` instead of clap.
 #[derive(Parser, Debug, Clone)]
 #[command(about = "Receive Shreds from 0slot.trade")]
 pub struct Opts {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML config file, flags and environment variables override its values
+    #[clap(long, global = true, env = "SHRED_ZDN_CONFIG")]
+    pub config: Option<PathBuf>,
+
     /// zdn auth key
-    #[clap(long)]
-    pub auth: String,
+    #[clap(long, env = "SHRED_ZDN_AUTH")]
+    pub auth: Option<String>,
+
+    /// run without the 0slot.trade control plane: no registration, send back to the static regions and addresses
+    #[clap(long, env = "SHRED_ZDN_STANDALONE")]
+    pub standalone: bool,
+
+    /// static region `<name>=<host>` for standalone mode, repeatable, in order of preference
+    #[clap(long, value_parser = parse_static_region, env = "SHRED_ZDN_STATIC_REGION")]
+    pub static_region: Vec<(String, String)>,
+
+    /// static send-back addresses, comma-separated, always sent to in addition to the top regions
+    #[clap(long, value_delimiter = ',', env = "SHRED_ZDN_SEND_BACK")]
+    pub send_back: Vec<SocketAddr>,
+
+    /// send sniffed shreds back to this many of the top regions
+    #[clap(long, default_value_t = 3, env = "SHRED_ZDN_SEND_BACK_FAN_OUT")]
+    pub send_back_fan_out: usize,
+
+    /// the regions' send-back port
+    #[clap(long, default_value_t = 8002, env = "SHRED_ZDN_SEND_BACK_PORT")]
+    pub send_back_port: u16,
+
+    /// only use these regions, comma-separated
+    #[clap(long, value_delimiter = ',', env = "SHRED_ZDN_INCLUDE_REGIONS")]
+    pub include_regions: Vec<String>,
+
+    /// never use these regions, comma-separated
+    #[clap(long, value_delimiter = ',', env = "SHRED_ZDN_EXCLUDE_REGIONS")]
+    pub exclude_regions: Vec<String>,
+
+    /// resolve region hostnames again every this many seconds, 0 to only resolve on ranking changes
+    #[clap(long, alias = "dns-ttl-secs", default_value_t = 300, env = "SHRED_ZDN_DNS_REFRESH_SECS")]
+    pub dns_refresh_secs: u64,
 
     /// port to receive shreds from 0slot.trade
-    #[clap(long)]
+    #[clap(long, default_value_t = 0, hide_default_value = true, env = "SHRED_ZDN_PORT")]
     pub port: u16,    
 
     /// The network interface to sniff for the local validator's traffic (e.g., en0). If the validator is on the same host, use a loopback interface, such as `lo`
-    #[clap(long)]
-    pub interface: String,
+    #[clap(long, env = "SHRED_ZDN_INTERFACE")]
+    pub interface: Option<String>,
 
     /// The local validator's shred port to sniff.
-    #[clap(long)]
-    pub sniffer_port: u16,
+    #[clap(long, env = "SHRED_ZDN_SNIFFER_PORT")]
+    pub sniffer_port: Option<u16>,
+
+    /// Capture spec `<interface>:<port>[,<port>...][:in|out|inout]`, repeatable, each runs its own capture thread
+    #[clap(long, env = "SHRED_ZDN_SNIFF")]
+    pub sniff: Vec<SniffSpec>,
+
+    /// run without captures: the sources are received and forwarded, nothing is sniffed or sent back
+    #[clap(long, env = "SHRED_ZDN_NO_SNIFF", conflicts_with_all = ["interface", "sniffer_port", "sniff"])]
+    pub no_sniff: bool,
 
     /// Protocol (udp or tcp), case-insensitive
-    #[clap(long, default_value = "udp")]
+    #[clap(long, default_value = "udp", env = "SHRED_ZDN_PROTOCOL")]
     pub protocol: Protocol,
 
     /// forward addresses, comma-separated, at lease one
-    #[clap(long, value_delimiter = ',', required = true, num_args = 1..)]
+    #[clap(long, value_delimiter = ',', num_args = 1.., env = "SHRED_ZDN_FORWARDS")]
     pub forwards: Vec<SocketAddr>,
 
     /// reference shred-stream port
-    #[clap(long)]
+    #[clap(long, env = "SHRED_ZDN_REFERENCE")]
     pub reference: Option<u16>,
+
+    /// serve a local JSON-RPC `sendTransaction` endpoint on this address, forwarding to the relay's tx forwarders
+    #[clap(long, env = "SHRED_ZDN_TX_PROXY")]
+    pub tx_proxy: Option<SocketAddr>,
+
+    /// what to do on fatal registration errors (invalid auth key, IPv6, IP limit, unsupported version)
+    #[clap(long, value_enum, default_value = "exit", env = "SHRED_ZDN_ON_REGISTRATION_ERROR")]
+    pub on_registration_error: RegistrationErrorPolicy,
+
+    /// degrade when the relay has not returned the region map after this many seconds
+    #[clap(long, default_value_t = 60, env = "SHRED_ZDN_DISCOVERY_TIMEOUT_SECS")]
+    pub discovery_timeout_secs: u64,
+
+    /// degrade and use the regions unranked when probing takes longer than this many seconds
+    #[clap(long, default_value_t = 30, env = "SHRED_ZDN_PROBE_TIMEOUT_SECS")]
+    pub probe_timeout_secs: u64,
+
+    /// fail over to the next region when no shreds arrived for this many seconds
+    #[clap(long, default_value_t = 30, env = "SHRED_ZDN_FAILOVER_NO_SHRED_SECS")]
+    pub failover_no_shred_secs: u64,
+
+    /// fail over to the next region after this many consecutive registration failures
+    #[clap(long, default_value_t = 3, env = "SHRED_ZDN_FAILOVER_REGISTER_FAILURES")]
+    pub failover_register_failures: usize,
+
+    /// move back to the preferred region after this many seconds on another one
+    #[clap(long, default_value_t = 300, env = "SHRED_ZDN_FAILBACK_SECS")]
+    pub failback_secs: u64,
+
+    /// re-measure region latency every this many seconds, 0 to disable
+    #[clap(long, default_value_t = 1800, env = "SHRED_ZDN_RERANK_SECS")]
+    pub rerank_secs: u64,
+
+    /// switch region when another one is faster by at least this many milliseconds
+    #[clap(long, default_value_t = 5, env = "SHRED_ZDN_RERANK_MARGIN_MS")]
+    pub rerank_margin_ms: u64,
+
+    /// ... in this many consecutive measurements
+    #[clap(long, default_value_t = 3, env = "SHRED_ZDN_RERANK_ROUNDS")]
+    pub rerank_rounds: usize,
+
+    /// serve the admin api (`GET /state`) on this address
+    #[clap(long, env = "SHRED_ZDN_ADMIN")]
+    pub admin: Option<SocketAddr>,
+
+    /// offer authenticated send-back packets (keyed by the auth key or jwt) during registration
+    #[clap(long, env = "SHRED_ZDN_AUTH_SEND_BACK")]
+    pub auth_send_back: bool,
+
+    /// how region latency is measured
+    #[clap(long, value_enum, default_value_t = ProbeMethod::Auto, env = "SHRED_ZDN_PROBE_METHOD")]
+    pub probe_method: ProbeMethod,
+
+    /// probes sent to each region per measurement
+    #[clap(long, default_value_t = 4, env = "SHRED_ZDN_PROBE_COUNT")]
+    pub probe_count: usize,
+
+    /// port of the TCP and UDP probes, the regions' send-back port
+    #[clap(long, default_value_t = 8002, env = "SHRED_ZDN_PROBE_PORT")]
+    pub probe_port: u16,
+
+    /// calibrate this many of the best probed regions by measured shred arrival, 0 to disable
+    #[clap(long, default_value_t = 0, env = "SHRED_ZDN_CALIBRATE_TOP_K")]
+    pub calibrate_top_k: usize,
+
+    /// how long shreds are sampled on each calibrated region
+    #[clap(long, default_value_t = 30, env = "SHRED_ZDN_CALIBRATE_SECS")]
+    pub calibrate_secs: u64,
+
+    /// keep calibration results in this file and reuse them for a day
+    #[clap(long, env = "SHRED_ZDN_CALIBRATION_STATE")]
+    pub calibration_state: Option<PathBuf>,
+
+    /// relay control plane to register with
+    #[clap(long, default_value = HOST, env = "SHRED_ZDN_RELAY")]
+    pub relay: String,
+
+    /// compare the sources slot by slot and append one JSON line per slot to this file
+    #[clap(long, env = "SHRED_ZDN_RACE_REPORT")]
+    pub race_report: Option<PathBuf>,
+
+    /// seconds between two registration keep-alives
+    #[clap(long, default_value_t = 3, env = "SHRED_ZDN_REGISTER_INTERVAL_SECS")]
+    pub register_interval_secs: u64,
+
+    /// seconds a shred is remembered for dedup, the window rotates this often
+    #[clap(long, default_value_t = 15, env = "SHRED_ZDN_DEDUP_ROTATION_SECS")]
+    pub dedup_rotation_secs: u64,
+
+    /// seconds between two stats lines
+    #[clap(long, default_value_t = 10, env = "SHRED_ZDN_STATS_INTERVAL_SECS")]
+    pub stats_interval_secs: u64,
+
+    /// seconds to drain the pipeline on SIGINT/SIGTERM before giving up
+    #[clap(long, default_value_t = 10, env = "SHRED_ZDN_DRAIN_TIMEOUT_SECS")]
+    pub drain_timeout_secs: u64,
+
+    /// stream decoded entries as JSON to WebSocket clients on this address
+    #[clap(long, env = "SHRED_ZDN_ENTRY_STREAM")]
+    pub entry_stream: Option<SocketAddr>,
+
+    /// disconnect an entry stream client that is this many events behind
+    #[clap(long, default_value_t = 1024, env = "SHRED_ZDN_ENTRY_STREAM_BUFFER")]
+    pub entry_stream_buffer: usize,
+
+    /// write every accepted shred to a shared-memory ring at this path, e.g. `/dev/shm/shred-zdn.ring`
+    #[clap(long, env = "SHRED_ZDN_SHM_RING")]
+    pub shm_ring: Option<PathBuf>,
+
+    /// shreds the shared-memory ring holds, a power of two
+    #[clap(long, default_value_t = 65536, env = "SHRED_ZDN_SHM_RING_CAPACITY")]
+    pub shm_ring_capacity: usize,
+
+    /// log filters on top of `RUST_LOG`, e.g. `info,shred_zdn=debug`
+    #[clap(long, env = "SHRED_ZDN_LOG_LEVEL")]
+    pub log_level: Option<String>,
+}
+
+impl Opts {
+    /// parse the command line and the environment, fill in the rest from `--config` and validate.
+    /// `--help` and malformed flags exit through clap.
+    pub fn load() -> Result<Self, ConfigError> {
+        let matches = Self::command().get_matches();
+        let opts = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        Self::merge(opts, &matches)
+    }
+
+    /// the same as `load` with the config file read again, errors are returned instead of exiting.
+    pub fn reload() -> Result<Self, ConfigError> {
+        let error = |e: clap::Error| ConfigError(vec![e.to_string()]);
+        let matches = Self::command().try_get_matches_from(std::env::args_os()).map_err(error)?;
+        let opts = Self::from_arg_matches(&matches).map_err(error)?;
+        Self::merge(opts, &matches)
+    }
+
+    fn merge(mut opts: Self, matches: &clap::ArgMatches) -> Result<Self, ConfigError> {
+        if let Some(path) = opts.config.clone() {
+            FileConfig::load(&path)?.apply(&mut opts, matches);
+        }
+        validate(&opts)?;
+        Ok(opts)
+    }
+
+    /// all captures to run: `--interface`/`--sniffer-port` followed by every `--sniff`.
+    pub fn sniff_specs(&self) -> Vec<SniffSpec> {
+        let legacy = match (&self.interface, self.sniffer_port) {
+            (Some(interface), Some(port)) => Some(SniffSpec {
+                interface: interface.clone(),
+                ports: vec![port],
+                direction: SniffDirection::In,
+            }),
+            _ => None,
+        };
+        legacy.into_iter().chain(self.sniff.iter().cloned()).collect()
+    }
+
+    pub fn probe_config(&self) -> ProbeConfig {
+        ProbeConfig {
+            method: self.probe_method,
+            count: self.probe_count.max(1),
+            port: self.probe_port,
+        }
+    }
+
+    pub fn calibration_policy(&self) -> Option<CalibrationPolicy> {
+        if self.calibrate_top_k == 0 {
+            return None;
+        }
+        Some(CalibrationPolicy {
+            top_k: self.calibrate_top_k,
+            window: Duration::from_secs(self.calibrate_secs.max(1)),
+            state_file: self.calibration_state.clone(),
+        })
+    }
+
+    pub fn send_back_config(&self) -> SendBackConfig {
+        SendBackConfig {
+            fan_out: self.send_back_fan_out,
+            port: self.send_back_port,
+            extra: self.send_back.clone(),
+            dns_refresh: (self.dns_refresh_secs > 0).then(|| Duration::from_secs(self.dns_refresh_secs)),
+        }
+    }
+
+    pub fn region_filter(&self) -> RegionFilter {
+        RegionFilter {
+            include: self.include_regions.clone(),
+            exclude: self.exclude_regions.clone(),
+        }
+    }
+
+    pub fn failover_policy(&self) -> FailoverPolicy {
+        FailoverPolicy {
+            no_shred_timeout: Duration::from_secs(self.failover_no_shred_secs),
+            max_register_failures: self.failover_register_failures.max(1),
+            failback_after: Duration::from_secs(self.failback_secs),
+        }
+    }
+
+    pub fn register_interval(&self) -> Duration {
+        Duration::from_secs(self.register_interval_secs.max(1))
+    }
+
+    pub fn dedup_rotation(&self) -> Duration {
+        Duration::from_secs(self.dedup_rotation_secs.max(1))
+    }
+
+    pub fn stats_interval(&self) -> Duration {
+        Duration::from_secs(self.stats_interval_secs.max(1))
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+
+    pub fn startup_timeouts(&self) -> StartupTimeouts {
+        StartupTimeouts {
+            discovery: Duration::from_secs(self.discovery_timeout_secs),
+            probing: Duration::from_secs(self.probe_timeout_secs),
+        }
+    }
+
+    pub fn rerank_policy(&self) -> Option<RerankPolicy> {
+        (self.rerank_secs > 0).then(|| RerankPolicy {
+            interval: Duration::from_secs(self.rerank_secs),
+            margin_ms: self.rerank_margin_ms as u128,
+            rounds: self.rerank_rounds.max(1),
+        })
+    }
 }
 
 #[derive(enum_map::Enum, Eq, PartialEq, Copy, Clone, Debug)]
 pub enum Source {
     Zdn,
     Reference,
+    LocalTurbine,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Zdn => "zdn",
+            Source::Reference => "reference",
+            Source::LocalTurbine => "turbine",
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn spec(s: &str) -> SniffSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn sniff_spec_ports_and_direction() {
+        assert_eq!(
+            spec("eth0:8001,8003:out"),
+            SniffSpec { interface: "eth0".to_string(), ports: vec![8001, 8003], direction: SniffDirection::Out }
+        );
+        assert_eq!(spec("lo:8001").direction, SniffDirection::In);
+        assert_eq!(spec("lo:8001:InOut").direction, SniffDirection::InOut);
+    }
+
+    #[test]
+    fn sniff_spec_interface_alias() {
+        let alias = spec("eth0:1:8001");
+        assert_eq!((alias.interface.as_str(), alias.ports), ("eth0:1", vec![8001]));
+        let alias = spec("eth0:1:8001,8002:in");
+        assert_eq!((alias.interface.as_str(), alias.ports), ("eth0:1", vec![8001, 8002]));
+    }
+
+    #[test]
+    fn sniff_spec_errors() {
+        for (s, error) in [
+            ("eth0", "missing ports"),
+            ("eth0:in", "missing ports"),
+            (":8001", "missing interface"),
+            ("eth0:8001:sideways", "invalid direction"),
+            ("eth0:80x1", "invalid port"),
+            ("eth0:70000", "invalid port"),
+        ] {
+            let err = s.parse::<SniffSpec>().unwrap_err();
+            assert!(err.contains(error), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn bpf_filter_per_direction() {
+        let mut spec = spec("lo:8001,8003");
+        assert_eq!(spec.bpf_filter(&Protocol::Udp), "udp and (dst port 8001 or dst port 8003)");
+        spec.direction = SniffDirection::Out;
+        assert_eq!(spec.bpf_filter(&Protocol::Udp), "udp and (src port 8001 or src port 8003)");
+        spec.direction = SniffDirection::InOut;
+        assert_eq!(spec.bpf_filter(&Protocol::Tcp), "tcp and (port 8001 or port 8003)");
+    }
+}
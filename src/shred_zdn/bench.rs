@@ -0,0 +1,162 @@
+use std::{
+    collections::HashSet,
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, sleep, MissedTickBehavior},
+};
+
+use crate::shred_zdn::stats::percentile;
+
+/// size of a merkle data shred.
+const SHRED_SIZE: usize = 1203;
+/// merkle data shred with a 6-entry proof, what `dedup_slice` and `shred_header` accept.
+const VARIANT: u8 = 0x86;
+/// data shreds per synthetic slot.
+const SHREDS_PER_SLOT: u64 = 1000;
+/// forwarded shreds still arriving after the last send are counted for this long.
+const DRAIN: Duration = Duration::from_secs(1);
+
+pub struct BenchConfig {
+    pub target: SocketAddr,
+    pub listen: Option<SocketAddr>,
+    pub rate: u64,
+    pub duration: Duration,
+    /// fraction of the shreds sent twice.
+    pub duplicates: f64,
+}
+
+/// forwarded shreds seen on the listen address.
+#[derive(Debug, Default)]
+pub struct Forwarded {
+    pub unique: usize,
+    /// a shred forwarded more than once, dedup failed.
+    pub duplicates: usize,
+    /// sorted, from the first send to the receive, in microseconds.
+    pub latencies_us: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub sent: usize,
+    pub duplicates_sent: usize,
+    pub send_errors: usize,
+    pub elapsed: Duration,
+    pub forwarded: Option<Forwarded>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "sent {} shreds (+{} duplicates) in {:.2}s, {:.0}/s, send errors {}",
+            self.sent, self.duplicates_sent, secs, self.sent as f64 / secs, self.send_errors,
+        )?;
+        if let Some(forwarded) = &self.forwarded {
+            let ms = |p: f64| percentile(&forwarded.latencies_us, p) as f64 / 1000.0;
+            let loss = 1.0 - forwarded.unique as f64 / self.sent.max(1) as f64;
+            write!(
+                f,
+                "; forwarded {} ({:.2}% lost), {} duplicates, latency p50 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+                forwarded.unique, loss * 100.0, forwarded.duplicates, ms(0.50), ms(0.99), ms(1.0),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// a synthetic merkle data shred, `seq` makes it unique and the send time rides in the signature.
+fn synthetic_shred(base_slot: u64, seq: u64, sent_at: SystemTime) -> Vec<u8> {
+    let mut shred = vec![0u8; SHRED_SIZE];
+    shred[..8].copy_from_slice(&seq.to_le_bytes());
+    let sent_ns = sent_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    shred[8..16].copy_from_slice(&sent_ns.to_le_bytes());
+    shred[64] = VARIANT;
+    let slot = base_slot + seq / SHREDS_PER_SLOT;
+    let index = (seq % SHREDS_PER_SLOT) as u32;
+    shred[65..73].copy_from_slice(&slot.to_le_bytes());
+    shred[73..77].copy_from_slice(&index.to_le_bytes());
+    shred
+}
+
+/// send synthetic shreds to `config.target` at `config.rate` and, with a listen address, measure
+/// what the instance forwards.
+pub async fn run_bench(config: BenchConfig) -> io::Result<BenchReport> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let listener = match config.listen {
+        Some(addr) => Some(tokio::spawn(listen(UdpSocket::bind(addr).await?, config.duration + DRAIN))),
+        None => None,
+    };
+    // far above any real slot so the bench never mixes with live shreds
+    let base_slot = u64::MAX / 2 + SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() * SHREDS_PER_SLOT;
+    info!("sending {} shreds/s to {} for {}s", config.rate, config.target, config.duration.as_secs());
+
+    let mut report = BenchReport::default();
+    let mut tick = interval(Duration::from_millis(1));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // every n-th shred is sent twice
+    let duplicate_every = (config.duplicates > 0.0).then(|| (1.0 / config.duplicates).round().max(1.0) as u64);
+    let start = Instant::now();
+    let mut seq = 0u64;
+    while start.elapsed() < config.duration {
+        tick.tick().await;
+        let due = (start.elapsed().as_secs_f64() * config.rate as f64) as u64;
+        while seq < due {
+            let shred = synthetic_shred(base_slot, seq, SystemTime::now());
+            let copies = if duplicate_every.is_some_and(|every| seq.is_multiple_of(every)) { 2 } else { 1 };
+            for _ in 0..copies {
+                if let Err(e) = socket.send_to(&shred, config.target).await {
+                    report.send_errors += 1;
+                    warn!("Send to {} failed: {}", config.target, e);
+                }
+            }
+            report.sent += 1;
+            report.duplicates_sent += copies - 1;
+            seq += 1;
+        }
+    }
+    report.elapsed = start.elapsed();
+
+    if let Some(listener) = listener {
+        report.forwarded = Some(listener.await.map_err(io::Error::other)?);
+    }
+    Ok(report)
+}
+
+/// count the forwarded synthetic shreds until `duration` passed.
+async fn listen(socket: UdpSocket, duration: Duration) -> Forwarded {
+    let mut forwarded = Forwarded::default();
+    let mut seen = HashSet::new();
+    let mut buf = vec![0u8; 2048];
+    let deadline = sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            recv = socket.recv(&mut buf) => {
+                let Ok(len) = recv else { continue };
+                if len != SHRED_SIZE || buf[64] != VARIANT {
+                    continue;
+                }
+                let seq = u64::from_le_bytes(buf[..8].try_into().unwrap());
+                if !seen.insert(seq) {
+                    forwarded.duplicates += 1;
+                    continue;
+                }
+                forwarded.unique += 1;
+                let sent_at = UNIX_EPOCH + Duration::from_nanos(u64::from_le_bytes(buf[8..16].try_into().unwrap()));
+                if let Ok(latency) = SystemTime::now().duration_since(sent_at) {
+                    forwarded.latencies_us.push(latency.as_micros() as i64);
+                }
+            }
+        }
+    }
+    forwarded.latencies_us.sort_unstable();
+    forwarded
+}
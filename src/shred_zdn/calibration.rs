@@ -0,0 +1,178 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio::time::timeout;
+
+use crate::shred_zdn::{
+    lifecycle::{Lifecycle, LifecycleState},
+    stats::{percentile, Stats},
+};
+
+/// a region needs this many lead samples for its result to count.
+const MIN_SAMPLES: usize = 50;
+/// wait after the switch so the previous region's stream drains before sampling.
+const SETTLE: Duration = Duration::from_secs(3);
+/// a state file younger than this is reused instead of calibrating again.
+const STATE_MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone)]
+pub struct CalibrationPolicy {
+    /// how many of the best probed regions are calibrated.
+    pub top_k: usize,
+    /// how long shreds are sampled on each region.
+    pub window: Duration,
+    /// where the results are kept between runs.
+    pub state_file: Option<PathBuf>,
+}
+
+/// how early one region delivered shreds, compared with turbine and the reference feed.
+#[derive(Debug, Clone)]
+pub struct RegionCalibration {
+    pub region: String,
+    pub samples: usize,
+    pub zdn_first_pct: f64,
+    pub lead_us_p50: i64,
+    pub lead_us_p90: i64,
+}
+
+impl RegionCalibration {
+    fn from_leads(region: &str, mut leads: Vec<i64>) -> Self {
+        leads.sort_unstable();
+        let zdn_first = leads.iter().filter(|lead| **lead >= 0).count();
+        Self {
+            region: region.to_string(),
+            samples: leads.len(),
+            zdn_first_pct: if leads.is_empty() { 0.0 } else { zdn_first as f64 * 100.0 / leads.len() as f64 },
+            lead_us_p50: percentile(&leads, 0.50),
+            lead_us_p90: percentile(&leads, 0.90),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "region": self.region,
+            "samples": self.samples,
+            "zdn_first_pct": self.zdn_first_pct,
+            "lead_us_p50": self.lead_us_p50,
+            "lead_us_p90": self.lead_us_p90,
+        })
+    }
+}
+
+impl std::fmt::Display for RegionCalibration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} samples {}, zdn-first {:.1}%, zdn-lead-us p50 {} p90 {}",
+            self.region, self.samples, self.zdn_first_pct, self.lead_us_p50, self.lead_us_p90
+        )
+    }
+}
+
+/// register with each of the top-k regions in turn and move the one whose shreds arrive
+/// earliest to the front of `ranking`. a fresh state file is reused instead.
+pub async fn calibrate(
+    policy: &CalibrationPolicy,
+    ranking: Vec<(String, u128)>,
+    lifecycle: &Lifecycle,
+    stats: &Stats,
+) -> Vec<(String, u128)> {
+    let candidates = ranking.iter().take(policy.top_k).map(|(region, _)| region.clone()).collect::<Vec<_>>();
+    if let Some(chosen) = policy.state_file.as_deref().and_then(|path| load_state(path, &candidates)) {
+        info!("✅ Calibrated region from state file: {}", chosen);
+        return promote(ranking, &chosen);
+    }
+    recalibrate(policy, ranking, lifecycle, stats).await
+}
+
+/// `calibrate` without the state file shortcut, the results still replace the state file.
+pub async fn recalibrate(
+    policy: &CalibrationPolicy,
+    ranking: Vec<(String, u128)>,
+    lifecycle: &Lifecycle,
+    stats: &Stats,
+) -> Vec<(String, u128)> {
+    let candidates = ranking.iter().take(policy.top_k).map(|(region, _)| region.clone()).collect::<Vec<_>>();
+    if candidates.len() < 2 {
+        return ranking;
+    }
+
+    info!("calibrating regions {:?}, {}s each", candidates, policy.window.as_secs());
+    let mut results = Vec::new();
+    for region in &candidates {
+        // registration follows the preferred region
+        let order = std::iter::once(region.clone())
+            .chain(ranking.iter().map(|(r, _)| r.clone()).filter(|r| r != region))
+            .collect::<Vec<_>>();
+        lifecycle.regions.send_replace(order);
+
+        let mut state = lifecycle.state.subscribe();
+        let registered = state.wait_for(|state| matches!(state, LifecycleState::Registered { region: r } if r == region));
+        if !matches!(timeout(policy.window, registered).await, Ok(Ok(_))) {
+            warn!("calibration: region {} not registered within {}s, skipped", region, policy.window.as_secs());
+            continue;
+        }
+        tokio::time::sleep(SETTLE).await;
+
+        stats.calibration.start();
+        tokio::time::sleep(policy.window).await;
+        let result = RegionCalibration::from_leads(region, stats.calibration.finish());
+        info!("calibration: {}", result);
+        results.push(result);
+    }
+
+    let chosen = results
+        .iter()
+        .filter(|result| result.samples >= MIN_SAMPLES)
+        .max_by_key(|result| result.lead_us_p50)
+        .map(|result| result.region.clone());
+    let saved = policy.state_file.as_ref().map(|path| (path, save_state(path, chosen.as_deref(), &results)));
+    if let Some((path, Err(e))) = saved {
+        warn!("Failed to write calibration state {}: {}", path.display(), e);
+    }
+    match chosen {
+        Some(chosen) => {
+            info!("✅ Calibrated region: {}", chosen);
+            promote(ranking, &chosen)
+        }
+        None => {
+            warn!("calibration: not enough samples (turbine sniffer or reference feed needed), keeping the probe ranking");
+            ranking
+        }
+    }
+}
+
+/// move `region` to the front, keeping the order of the others.
+fn promote(mut ranking: Vec<(String, u128)>, region: &str) -> Vec<(String, u128)> {
+    if let Some(index) = ranking.iter().position(|(r, _)| r == region) {
+        let entry = ranking.remove(index);
+        ranking.insert(0, entry);
+    }
+    ranking
+}
+
+/// the chosen region of a fresh state file, if it is still one of the candidates.
+fn load_state(path: &Path, candidates: &[String]) -> Option<String> {
+    let state: Value = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    let calibrated_at = UNIX_EPOCH + Duration::from_secs(state.get("calibrated_at")?.as_u64()?);
+    if SystemTime::now().duration_since(calibrated_at).ok()? > STATE_MAX_AGE {
+        info!("calibration state {} is stale", path.display());
+        return None;
+    }
+    let chosen = state.get("chosen")?.as_str()?;
+    candidates.iter().find(|region| *region == chosen).cloned()
+}
+
+fn save_state(path: &Path, chosen: Option<&str>, results: &[RegionCalibration]) -> std::io::Result<()> {
+    let calibrated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let state = json!({
+        "calibrated_at": calibrated_at,
+        "chosen": chosen,
+        "regions": results.iter().map(RegionCalibration::to_json).collect::<Vec<_>>(),
+    });
+    std::fs::write(path, serde_json::to_vec_pretty(&state).unwrap())
+}
@@ -0,0 +1,568 @@
+//! TOML configuration file, see the `Configuration` section of the README.
+//!
+//! Every key is optional. A value from the file replaces clap's default but never a flag or
+//! an environment variable, so the precedence is: command line, environment, file, default.
+
+use std::{fmt, net::SocketAddr, path::PathBuf};
+
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+
+use crate::shred_zdn::args::{Command, Opts, ProbeMethod, Protocol, RegistrationErrorPolicy, SniffSpec};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standalone: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_registration_error: Option<RegistrationErrorPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    pub sources: SourcesConfig,
+    pub forward: ForwardConfig,
+    /// one capture per entry, `[[sniff]]` in the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sniff: Option<Vec<SniffSpec>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_sniff: Option<bool>,
+    pub send_back: SendBackSection,
+    pub regions: RegionsConfig,
+    pub probe: ProbeSection,
+    pub calibration: CalibrationSection,
+    pub failover: FailoverSection,
+    pub rerank: RerankSection,
+    pub timing: TimingConfig,
+    pub sinks: SinksConfig,
+}
+
+/// where shreds come from.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<SocketAddr>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendBackSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fan_out: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra: Option<Vec<SocketAddr>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authenticated: Option<bool>,
+    #[serde(alias = "dns_ttl_secs", skip_serializing_if = "Option::is_none")]
+    pub dns_refresh_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticRegion {
+    pub name: String,
+    pub host: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegionsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    /// standalone regions in order of preference, `[[regions.static]]` in the file.
+    #[serde(rename = "static", skip_serializing_if = "Option::is_none")]
+    pub static_regions: Option<Vec<StaticRegion>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discovery_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<ProbeMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailoverSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_shred_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub register_failures: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failback_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RerankSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub margin_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rounds: Option<usize>,
+}
+
+/// loop intervals that used to be compiled in.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub register_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup_rotation_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drain_timeout_secs: Option<u64>,
+}
+
+/// where results go besides the forward targets.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_proxy: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub race_report: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry_stream: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry_stream_buffer: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shm_ring: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shm_ring_capacity: Option<usize>,
+}
+
+/// everything wrong with the effective configuration, reported at once.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FileConfig {
+    pub fn load(path: &std::path::Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError(vec![format!("cannot read config file {}: {}", path.display(), e)]))?;
+        toml::from_str(&text).map_err(|e| ConfigError(vec![format!("config file {}: {}", path.display(), e)]))
+    }
+
+    /// fill in every value of `opts` that was neither given on the command line nor in the environment.
+    pub fn apply(self, opts: &mut Opts, matches: &ArgMatches) {
+        let m = Merge(matches);
+        m.set("relay", &mut opts.relay, self.relay);
+        m.set("auth", &mut opts.auth, self.auth.map(Some));
+        m.set("standalone", &mut opts.standalone, self.standalone);
+        m.set("on_registration_error", &mut opts.on_registration_error, self.on_registration_error);
+        m.set("log_level", &mut opts.log_level, self.log_level.map(Some));
+
+        m.set("port", &mut opts.port, self.sources.port);
+        m.set("reference", &mut opts.reference, self.sources.reference.map(Some));
+        m.set("protocol", &mut opts.protocol, self.sources.protocol);
+        m.set("forwards", &mut opts.forwards, self.forward.targets);
+        // the file's captures replace the legacy pair as well, --no-sniff drops them
+        if !m.explicit("interface") && !m.explicit("sniffer_port") && !m.explicit("no_sniff") {
+            m.set("sniff", &mut opts.sniff, self.sniff);
+        }
+        m.set("no_sniff", &mut opts.no_sniff, self.no_sniff);
+
+        m.set("send_back_fan_out", &mut opts.send_back_fan_out, self.send_back.fan_out);
+        m.set("send_back_port", &mut opts.send_back_port, self.send_back.port);
+        m.set("send_back", &mut opts.send_back, self.send_back.extra);
+        m.set("auth_send_back", &mut opts.auth_send_back, self.send_back.authenticated);
+        m.set("dns_refresh_secs", &mut opts.dns_refresh_secs, self.send_back.dns_refresh_secs);
+
+        m.set("include_regions", &mut opts.include_regions, self.regions.include);
+        m.set("exclude_regions", &mut opts.exclude_regions, self.regions.exclude);
+        let static_regions = self
+            .regions
+            .static_regions
+            .map(|regions| regions.into_iter().map(|region| (region.name, region.host)).collect());
+        m.set("static_region", &mut opts.static_region, static_regions);
+        m.set("discovery_timeout_secs", &mut opts.discovery_timeout_secs, self.regions.discovery_timeout_secs);
+
+        m.set("probe_method", &mut opts.probe_method, self.probe.method);
+        m.set("probe_count", &mut opts.probe_count, self.probe.count);
+        m.set("probe_port", &mut opts.probe_port, self.probe.port);
+        m.set("probe_timeout_secs", &mut opts.probe_timeout_secs, self.probe.timeout_secs);
+
+        m.set("calibrate_top_k", &mut opts.calibrate_top_k, self.calibration.top_k);
+        m.set("calibrate_secs", &mut opts.calibrate_secs, self.calibration.secs);
+        m.set("calibration_state", &mut opts.calibration_state, self.calibration.state.map(Some));
+
+        m.set("failover_no_shred_secs", &mut opts.failover_no_shred_secs, self.failover.no_shred_secs);
+        m.set("failover_register_failures", &mut opts.failover_register_failures, self.failover.register_failures);
+        m.set("failback_secs", &mut opts.failback_secs, self.failover.failback_secs);
+
+        m.set("rerank_secs", &mut opts.rerank_secs, self.rerank.secs);
+        m.set("rerank_margin_ms", &mut opts.rerank_margin_ms, self.rerank.margin_ms);
+        m.set("rerank_rounds", &mut opts.rerank_rounds, self.rerank.rounds);
+
+        m.set("register_interval_secs", &mut opts.register_interval_secs, self.timing.register_interval_secs);
+        m.set("dedup_rotation_secs", &mut opts.dedup_rotation_secs, self.timing.dedup_rotation_secs);
+        m.set("stats_interval_secs", &mut opts.stats_interval_secs, self.timing.stats_interval_secs);
+        m.set("drain_timeout_secs", &mut opts.drain_timeout_secs, self.timing.drain_timeout_secs);
+
+        m.set("admin", &mut opts.admin, self.sinks.admin.map(Some));
+        m.set("tx_proxy", &mut opts.tx_proxy, self.sinks.tx_proxy.map(Some));
+        m.set("race_report", &mut opts.race_report, self.sinks.race_report.map(Some));
+        m.set("entry_stream", &mut opts.entry_stream, self.sinks.entry_stream.map(Some));
+        m.set("entry_stream_buffer", &mut opts.entry_stream_buffer, self.sinks.entry_stream_buffer);
+        m.set("shm_ring", &mut opts.shm_ring, self.sinks.shm_ring.map(Some));
+        m.set("shm_ring_capacity", &mut opts.shm_ring_capacity, self.sinks.shm_ring_capacity);
+    }
+
+    /// the effective configuration in file form, the auth key is masked.
+    pub fn effective(opts: &Opts) -> Self {
+        Self {
+            relay: Some(opts.relay.clone()),
+            auth: opts.auth.as_ref().map(|_| "********".to_string()),
+            standalone: Some(opts.standalone),
+            on_registration_error: Some(opts.on_registration_error),
+            log_level: opts.log_level.clone(),
+            sources: SourcesConfig {
+                port: Some(opts.port),
+                reference: opts.reference,
+                protocol: Some(opts.protocol.clone()),
+            },
+            forward: ForwardConfig { targets: Some(opts.forwards.clone()) },
+            sniff: Some(opts.sniff_specs()),
+            no_sniff: Some(opts.no_sniff),
+            send_back: SendBackSection {
+                fan_out: Some(opts.send_back_fan_out),
+                port: Some(opts.send_back_port),
+                extra: Some(opts.send_back.clone()),
+                authenticated: Some(opts.auth_send_back),
+                dns_refresh_secs: Some(opts.dns_refresh_secs),
+            },
+            regions: RegionsConfig {
+                include: Some(opts.include_regions.clone()),
+                exclude: Some(opts.exclude_regions.clone()),
+                static_regions: Some(
+                    opts.static_region
+                        .iter()
+                        .map(|(name, host)| StaticRegion { name: name.clone(), host: host.clone() })
+                        .collect(),
+                ),
+                discovery_timeout_secs: Some(opts.discovery_timeout_secs),
+            },
+            probe: ProbeSection {
+                method: Some(opts.probe_method),
+                count: Some(opts.probe_count),
+                port: Some(opts.probe_port),
+                timeout_secs: Some(opts.probe_timeout_secs),
+            },
+            calibration: CalibrationSection {
+                top_k: Some(opts.calibrate_top_k),
+                secs: Some(opts.calibrate_secs),
+                state: opts.calibration_state.clone(),
+            },
+            failover: FailoverSection {
+                no_shred_secs: Some(opts.failover_no_shred_secs),
+                register_failures: Some(opts.failover_register_failures),
+                failback_secs: Some(opts.failback_secs),
+            },
+            rerank: RerankSection {
+                secs: Some(opts.rerank_secs),
+                margin_ms: Some(opts.rerank_margin_ms),
+                rounds: Some(opts.rerank_rounds),
+            },
+            timing: TimingConfig {
+                register_interval_secs: Some(opts.register_interval_secs),
+                dedup_rotation_secs: Some(opts.dedup_rotation_secs),
+                stats_interval_secs: Some(opts.stats_interval_secs),
+                drain_timeout_secs: Some(opts.drain_timeout_secs),
+            },
+            sinks: SinksConfig {
+                admin: opts.admin,
+                tx_proxy: opts.tx_proxy,
+                race_report: opts.race_report.clone(),
+                entry_stream: opts.entry_stream,
+                entry_stream_buffer: Some(opts.entry_stream_buffer),
+                shm_ring: opts.shm_ring.clone(),
+                shm_ring_capacity: Some(opts.shm_ring_capacity),
+            },
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config serializes")
+    }
+}
+
+/// check the merged options, the messages name the flag and the file key to fix.
+pub fn validate(opts: &Opts) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    match &opts.command {
+        None | Some(Command::Run | Command::Config { .. }) => validate_run(opts, &mut errors),
+        Some(Command::ProbeRegions) => {
+            if opts.auth.as_deref().unwrap_or_default().is_empty() && opts.static_region.is_empty() {
+                errors.push("probe-regions needs the auth key to fetch the region map, or --static-region".to_string());
+            }
+        }
+        Some(Command::Inspect { port, pcap, .. }) => {
+            if port.is_none() && pcap.is_none() {
+                errors.push("inspect needs --port or --pcap".to_string());
+            }
+        }
+        Some(Command::Replay { to, speed, .. }) => {
+            if to.is_empty() && opts.forwards.is_empty() {
+                errors.push("replay needs --to or the forward addresses".to_string());
+            }
+            if speed.is_nan() || *speed < 0.0 {
+                errors.push(format!("invalid replay speed {}", speed));
+            }
+        }
+        Some(Command::Bench { rate, duplicates, .. }) => {
+            if *rate == 0 {
+                errors.push("bench --rate must be at least 1".to_string());
+            }
+            if !(0.0..=1.0).contains(duplicates) {
+                errors.push(format!("bench --duplicates {} is not between 0 and 1", duplicates));
+            }
+        }
+    }
+    if let Some(region) = opts.include_regions.iter().find(|region| opts.exclude_regions.contains(region)) {
+        errors.push(format!("region {} is both included and excluded", region));
+    }
+    if errors.is_empty() { Ok(()) } else { Err(ConfigError(errors)) }
+}
+
+/// the receive, forward and capture settings `run` needs.
+fn validate_run(opts: &Opts, errors: &mut Vec<String>) {
+    if opts.port == 0 {
+        errors.push("the receive port is required: --port, SHRED_ZDN_PORT or `sources.port`".to_string());
+    }
+    if opts.forwards.is_empty() {
+        errors.push("at least one forward address is required: --forwards, SHRED_ZDN_FORWARDS or `forward.targets`".to_string());
+    }
+    if opts.auth.as_deref().unwrap_or_default().is_empty() && !opts.standalone {
+        errors.push("the auth key is required unless running standalone: --auth, SHRED_ZDN_AUTH or `auth`".to_string());
+    }
+    match (&opts.interface, opts.sniffer_port) {
+        (Some(_), None) => errors.push("--interface needs --sniffer-port".to_string()),
+        (None, Some(_)) => errors.push("--sniffer-port needs --interface".to_string()),
+        _ => {}
+    }
+    let sniffs = opts.sniff_specs();
+    if opts.no_sniff && !sniffs.is_empty() {
+        errors.push("--no-sniff (`no_sniff`) runs without captures: remove the captures or --no-sniff".to_string());
+    }
+    if sniffs.is_empty() && !opts.no_sniff {
+        errors.push("at least one capture is required: --interface with --sniffer-port, --sniff or `[[sniff]]`, or --no-sniff to run without".to_string());
+    }
+    for spec in &sniffs {
+        if spec.ports.is_empty() {
+            errors.push(format!("capture on {} has no ports", spec.interface));
+        }
+    }
+    if opts.reference == Some(opts.port) {
+        errors.push(format!("the reference port {} is the receive port", opts.port));
+    }
+    // probe-regions takes static regions without --standalone, only the relay mode ignores them
+    if !opts.standalone && !opts.static_region.is_empty() {
+        errors.push("--static-region (`regions.static`) only applies in standalone mode: add --standalone (`standalone = true`) or remove it".to_string());
+    }
+    if opts.standalone && opts.static_region.is_empty() && opts.send_back.is_empty() {
+        errors.push("standalone mode needs --static-region or --send-back (`regions.static` or `send_back.extra`)".to_string());
+    }
+    if opts.shm_ring.is_some() && !opts.shm_ring_capacity.is_power_of_two() {
+        errors.push(format!("the shared-memory ring capacity {} is not a power of two: --shm-ring-capacity or `sinks.shm_ring_capacity`", opts.shm_ring_capacity));
+    }
+    for (name, value) in [
+        ("register_interval_secs", opts.register_interval_secs),
+        ("dedup_rotation_secs", opts.dedup_rotation_secs),
+        ("stats_interval_secs", opts.stats_interval_secs),
+    ] {
+        if value == 0 {
+            errors.push(format!("`timing.{}` must be at least 1", name));
+        }
+    }
+}
+
+struct Merge<'a>(&'a ArgMatches);
+
+impl Merge<'_> {
+    fn set<T>(&self, id: &str, target: &mut T, value: Option<T>) {
+        if let Some(value) = value.filter(|_| !self.explicit(id)) {
+            *target = value;
+        }
+    }
+
+    /// given on the command line or in the environment.
+    fn explicit(&self, id: &str) -> bool {
+        matches!(self.0.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+    }
+}
+
+/// option parsing reads the process environment, tests that parse options hold this lock so the
+/// test that sets a variable does not leak it into them.
+#[cfg(test)]
+pub(crate) fn env_lock() -> std::sync::MutexGuard<'static, ()> {
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    /// the options from `args` with `file` filled in, the way `Opts::load` merges them.
+    fn load(args: &[&str], file: &str) -> Opts {
+        let _env = env_lock();
+        merge(args, file)
+    }
+
+    fn merge(args: &[&str], file: &str) -> Opts {
+        let matches = Opts::command().try_get_matches_from(["shred-zdn"].iter().chain(args)).unwrap();
+        let mut opts = Opts::from_arg_matches(&matches).unwrap();
+        toml::from_str::<FileConfig>(file).unwrap().apply(&mut opts, &matches);
+        opts
+    }
+
+    const FILE: &str = r#"
+        relay = "relay.example:443"
+
+        [probe]
+        count = 7
+        timeout_secs = 45
+
+        [rerank]
+        secs = 60
+        margin_ms = 9
+
+        [[sniff]]
+        interface = "eth0"
+        ports = [8001]
+    "#;
+
+    #[test]
+    fn the_file_replaces_defaults_but_not_flags() {
+        let opts = load(&["--rerank-secs", "30"], FILE);
+        assert_eq!(opts.rerank_secs, 30);
+        assert_eq!(opts.rerank_margin_ms, 9);
+        assert_eq!(opts.probe_count, 7);
+        assert_eq!(opts.relay, "relay.example:443");
+        // neither given nor in the file
+        assert_eq!(opts.stats_interval_secs, 10);
+        assert_eq!(opts.sniff_specs().len(), 1);
+    }
+
+    #[test]
+    fn the_environment_beats_the_file_and_flags_beat_the_environment() {
+        let (from_env, from_flag) = {
+            let _env = env_lock();
+            // no other test parses options while the variable is set
+            unsafe { std::env::set_var("SHRED_ZDN_PROBE_TIMEOUT_SECS", "50") };
+            scopeguard::defer! {
+                unsafe { std::env::remove_var("SHRED_ZDN_PROBE_TIMEOUT_SECS") };
+            }
+            (merge(&[], FILE), merge(&["--probe-timeout-secs", "55"], FILE))
+        };
+        assert_eq!(from_env.probe_timeout_secs, 50);
+        assert_eq!(from_flag.probe_timeout_secs, 55);
+        assert_eq!(load(&[], FILE).probe_timeout_secs, 45);
+    }
+
+    #[test]
+    fn legacy_capture_flags_and_no_sniff_drop_the_file_captures() {
+        let legacy = load(&["--interface", "lo", "--sniffer-port", "9000"], FILE);
+        assert_eq!(legacy.sniff_specs().iter().map(|spec| spec.interface.as_str()).collect::<Vec<_>>(), ["lo"]);
+        let none = load(&["--no-sniff"], FILE);
+        assert!(none.sniff_specs().is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for file in [
+            "relai = \"relay.example:443\"",
+            "[probe]\ncounts = 3",
+            "[timing]\nstats_interval = 5",
+            "[[sniff]]\ninterface = \"eth0\"\nports = [8001]\nport = 8001",
+            "[[regions.static]]\nname = \"fra\"\nhost = \"fra.example\"\nip = \"10.0.0.1\"",
+        ] {
+            let error = toml::from_str::<FileConfig>(file).unwrap_err().to_string();
+            assert!(error.contains("unknown field"), "{}: {}", file, error);
+        }
+        // renamed keys keep their old name
+        let file = toml::from_str::<FileConfig>("[send_back]\ndns_ttl_secs = 30").unwrap();
+        assert_eq!(file.send_back.dns_refresh_secs, Some(30));
+    }
+
+    #[test]
+    fn the_effective_config_reads_back() {
+        let opts = load(&["--auth", "secret", "--port", "8002", "--forwards", "127.0.0.1:9000"], FILE);
+        let text = FileConfig::effective(&opts).to_toml();
+        assert!(!text.contains("secret"));
+        let again = toml::from_str::<FileConfig>(&text).unwrap();
+        assert_eq!(again.probe.count, Some(7));
+        assert_eq!(again.sources.port, Some(8002));
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let opts = load(&["--include-regions", "fra,ny", "--exclude-regions", "ny"], "");
+        let ConfigError(errors) = validate(&opts).unwrap_err();
+        for expected in ["receive port", "forward address", "auth key", "at least one capture", "region ny"] {
+            assert!(errors.iter().any(|error| error.contains(expected)), "no {:?} in {:?}", expected, errors);
+        }
+
+        let opts = load(&["--auth", "key", "--port", "8002", "--forwards", "127.0.0.1:9000"], FILE);
+        assert!(validate(&opts).is_ok());
+        let opts = load(&["--auth", "key", "--port", "8002", "--forwards", "127.0.0.1:9000", "--no-sniff"], "");
+        assert!(validate(&opts).is_ok());
+    }
+}
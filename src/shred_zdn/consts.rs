@@ -1,2 +1,4 @@
 pub const VERSION: &str = "0.2.0h";
 pub const HOST: &str = "https://shred-zdn.0slot.trade";
+// region sent in RegisterRequest to ask the relay for the region map
+pub const REQUEST_REGION: &str = "request-region";
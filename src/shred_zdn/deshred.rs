@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, warn};
+use solana_entry::entry::Entry;
+use solana_ledger::shred::{Shred, Shredder};
+
+/// a slot without new shreds for this long is dropped, complete or not.
+const SLOT_IDLE: Duration = Duration::from_secs(10);
+/// a missing data shred with later shreds already in is given up after this long.
+const GAP_WAIT: Duration = Duration::from_millis(500);
+/// finished slots remembered so late shreds do not open them again.
+const FINISHED_SLOTS: usize = 1024;
+
+/// what deshredding a slot produces, in order.
+#[derive(Debug)]
+pub enum EntryEvent {
+    /// the first data shred of the slot arrived.
+    SlotStart { slot: u64, parent: Option<u64>, at: SystemTime },
+    /// one entry batch, the data shreds `start_index..=end_index`.
+    Entries { slot: u64, start_index: u32, end_index: u32, entries: Vec<Entry>, at: SystemTime },
+    /// every batch up to the slot's last shred was decoded or skipped, `skipped` counts the data
+    /// shreds of the batches skipped for a lost shred.
+    SlotComplete { slot: u64, entries: usize, transactions: usize, skipped: u32, at: SystemTime },
+}
+
+/// data shreds of one slot, decoded batch by batch as soon as a batch is contiguous.
+struct SlotShreds {
+    shreds: BTreeMap<u32, Shred>,
+    /// first index of the next undecoded batch.
+    next: u32,
+    entries: usize,
+    transactions: usize,
+    skipped: u32,
+    last_seen: Instant,
+    /// arrival of the latest shred, the time of events not caused by an arrival.
+    last_at: SystemTime,
+    /// since when a shred is missing before shreds that are already in.
+    gap_since: Option<Instant>,
+}
+
+/// turns data shreds from any source into entry events, duplicates are ignored.
+/// coding shreds are not used: solana-ledger only exposes recovery of legacy shreds, merkle
+/// shreds cannot be rebuilt here. a batch with a data shred still missing after `GAP_WAIT` is
+/// skipped up to the batch's data-complete shred, later batches are decoded.
+#[derive(Default)]
+pub struct Deshredder {
+    slots: HashMap<u64, SlotShreds>,
+    finished: BTreeSet<u64>,
+}
+
+impl Deshredder {
+    /// add a shred, returns the events it completed.
+    pub fn insert(&mut self, payload: &[u8], at: SystemTime) -> Vec<EntryEvent> {
+        self.insert_at(payload, at, Instant::now())
+    }
+
+    fn insert_at(&mut self, payload: &[u8], at: SystemTime, now: Instant) -> Vec<EntryEvent> {
+        let mut events = Vec::new();
+        let Ok(shred) = Shred::new_from_serialized_shred(payload.to_vec()) else {
+            return events;
+        };
+        let slot = shred.slot();
+        if !shred.is_data() || self.finished.contains(&slot) {
+            return events;
+        }
+
+        let state = self.slots.entry(slot).or_insert_with(|| {
+            events.push(EntryEvent::SlotStart { slot, parent: shred.parent().ok(), at });
+            SlotShreds {
+                shreds: BTreeMap::new(),
+                next: 0,
+                entries: 0,
+                transactions: 0,
+                skipped: 0,
+                last_seen: now,
+                last_at: at,
+                gap_since: None,
+            }
+        });
+        state.last_seen = now;
+        state.last_at = at;
+        if shred.index() < state.next {
+            return events;
+        }
+        state.shreds.entry(shred.index()).or_insert(shred);
+        self.advance(slot, now, &mut events);
+        events
+    }
+
+    /// skip the gaps open for `GAP_WAIT` and drop the slots that stopped receiving shreds,
+    /// returns the events the skips completed.
+    pub fn tick(&mut self) -> Vec<EntryEvent> {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> Vec<EntryEvent> {
+        let mut events = Vec::new();
+        let mut slots = self.slots.keys().copied().collect::<Vec<_>>();
+        slots.sort_unstable();
+        for slot in slots {
+            self.advance(slot, now, &mut events);
+        }
+
+        let idle = self
+            .slots
+            .iter()
+            .filter(|(_, state)| now.saturating_duration_since(state.last_seen) >= SLOT_IDLE)
+            .map(|(slot, _)| *slot)
+            .collect::<Vec<_>>();
+        for slot in idle {
+            let state = self.slots.remove(&slot).unwrap();
+            debug!("slot {} incomplete, decoded up to shred {}", slot, state.next);
+            self.finish(slot);
+        }
+        events
+    }
+
+    /// decode every batch that is contiguous from `next` to a data-complete shred, skip the
+    /// batch of a gap open for `GAP_WAIT`.
+    fn advance(&mut self, slot: u64, now: Instant, events: &mut Vec<EntryEvent>) {
+        let Some(state) = self.slots.get_mut(&slot) else { return };
+        let at = state.last_at;
+        loop {
+            let start = state.next;
+            let (end, last_in_slot) = match batch_end(&state.shreds, start) {
+                Some(end) => {
+                    let batch = (start..=end).filter_map(|index| state.shreds.remove(&index)).collect::<Vec<_>>();
+                    let last_in_slot = batch.last().is_some_and(|shred| shred.last_in_slot());
+                    match decode(&batch) {
+                        Ok(entries) => {
+                            state.entries += entries.len();
+                            state.transactions += entries.iter().map(|entry| entry.transactions.len()).sum::<usize>();
+                            events.push(EntryEvent::Entries { slot, start_index: start, end_index: end, entries, at });
+                        }
+                        Err(e) => warn!("slot {} shreds {}..={}: {}", slot, start, end, e),
+                    }
+                    (end, last_in_slot)
+                }
+                None => {
+                    let Some(gap) = first_gap(&state.shreds, start) else {
+                        state.gap_since = None;
+                        return;
+                    };
+                    let since = *state.gap_since.get_or_insert(now);
+                    if now.saturating_duration_since(since) < GAP_WAIT {
+                        return;
+                    }
+                    // the batch ends at the first data-complete shred after the gap, if it is in yet
+                    let Some((end, last_in_slot)) = state
+                        .shreds
+                        .range(gap..)
+                        .find(|(_, shred)| shred.data_complete() || shred.last_in_slot())
+                        .map(|(index, shred)| (*index, shred.last_in_slot()))
+                    else {
+                        return;
+                    };
+                    debug!("slot {} shred {} missing, skipping shreds {}..={}", slot, gap, start, end);
+                    state.shreds = state.shreds.split_off(&(end + 1));
+                    state.skipped += end + 1 - start;
+                    (end, last_in_slot)
+                }
+            };
+            state.next = end + 1;
+            state.gap_since = None;
+            if last_in_slot {
+                events.push(EntryEvent::SlotComplete {
+                    slot,
+                    entries: state.entries,
+                    transactions: state.transactions,
+                    skipped: state.skipped,
+                    at,
+                });
+                self.slots.remove(&slot);
+                self.finish(slot);
+                return;
+            }
+        }
+    }
+
+    fn finish(&mut self, slot: u64) {
+        self.finished.insert(slot);
+        if self.finished.len() > FINISHED_SLOTS {
+            self.finished.pop_first();
+        }
+    }
+}
+
+/// the first missing index from `start` on, if a later shred is already in.
+fn first_gap(shreds: &BTreeMap<u32, Shred>, start: u32) -> Option<u32> {
+    for (index, expected) in shreds.range(start..).map(|(index, _)| *index).zip(start..) {
+        if index != expected {
+            return Some(expected);
+        }
+    }
+    None
+}
+
+/// index of the data-complete shred closing the batch that starts at `start`, if all shreds are in.
+fn batch_end(shreds: &BTreeMap<u32, Shred>, start: u32) -> Option<u32> {
+    for ((index, shred), expected) in shreds.range(start..).zip(start..) {
+        if *index != expected {
+            return None;
+        }
+        if shred.data_complete() || shred.last_in_slot() {
+            return Some(*index);
+        }
+    }
+    None
+}
+
+fn decode(batch: &[Shred]) -> Result<Vec<Entry>, String> {
+    let data = Shredder::deshred(batch).map_err(|e| format!("deshred failed: {}", e))?;
+    bincode::deserialize::<Vec<Entry>>(&data).map_err(|e| format!("invalid entries: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_entry::entry::create_ticks;
+    use solana_ledger::shred::{ProcessShredsStats, ReedSolomonCache};
+    use solana_sdk::{hash::Hash, signature::Keypair};
+
+    use super::*;
+
+    const SLOT: u64 = 1_000;
+
+    /// data shred payloads of two batches of 200 ticks each, the second one ends the slot.
+    fn batches() -> [Vec<Vec<u8>>; 2] {
+        let shredder = Shredder::new(SLOT, SLOT - 1, 0, 0).unwrap();
+        let (keypair, cache) = (Keypair::new(), ReedSolomonCache::default());
+        let ticks = create_ticks(200, 1, Hash::default());
+        let mut next_index = 0;
+        [false, true].map(|last_in_slot| {
+            let (data, _) = shredder.entries_to_shreds(
+                &keypair,
+                &ticks,
+                last_in_slot,
+                None,
+                next_index,
+                next_index,
+                true,
+                &cache,
+                &mut ProcessShredsStats::default(),
+            );
+            next_index += data.len() as u32;
+            data.into_iter().map(Shred::into_payload).collect()
+        })
+    }
+
+    fn summary(events: &[EntryEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event {
+                EntryEvent::SlotStart { slot, parent, .. } => format!("start {} {:?}", slot, parent),
+                EntryEvent::Entries { start_index, end_index, entries, .. } => {
+                    format!("entries {}..={} {}", start_index, end_index, entries.len())
+                }
+                EntryEvent::SlotComplete { entries, skipped, .. } => format!("complete {} skipped {}", entries, skipped),
+            })
+            .collect()
+    }
+
+    /// the events of a slot that decoded completely, `n1` and `n2` data shreds per batch.
+    fn complete(n1: usize, n2: usize) -> Vec<String> {
+        vec![
+            format!("start {} Some({})", SLOT, SLOT - 1),
+            format!("entries 0..={} 200", n1 - 1),
+            format!("entries {}..={} 200", n1, n1 + n2 - 1),
+            "complete 400 skipped 0".to_string(),
+        ]
+    }
+
+    fn insert_all<'a>(
+        deshredder: &mut Deshredder,
+        shreds: impl IntoIterator<Item = &'a Vec<u8>>,
+        now: Instant,
+    ) -> Vec<EntryEvent> {
+        shreds.into_iter().flat_map(|shred| deshredder.insert_at(shred, SystemTime::now(), now)).collect()
+    }
+
+    #[test]
+    fn batches_decode_in_order() {
+        let [first, second] = batches();
+        assert!(first.len() > 2);
+        let mut deshredder = Deshredder::default();
+        let events = insert_all(&mut deshredder, first.iter().chain(&second), Instant::now());
+        assert_eq!(summary(&events), complete(first.len(), second.len()));
+        assert!(deshredder.slots.is_empty());
+    }
+
+    #[test]
+    fn batches_decode_out_of_order() {
+        let [first, second] = batches();
+        let mut deshredder = Deshredder::default();
+        let events = insert_all(&mut deshredder, first.iter().chain(&second).rev(), Instant::now());
+        assert_eq!(summary(&events), complete(first.len(), second.len()));
+    }
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let [first, second] = batches();
+        let mut deshredder = Deshredder::default();
+        let now = Instant::now();
+        let twice = first.iter().chain(&second).flat_map(|shred| [shred, shred]);
+        let events = insert_all(&mut deshredder, twice, now);
+        assert_eq!(summary(&events), complete(first.len(), second.len()));
+
+        // a late copy does not open the finished slot again
+        assert!(insert_all(&mut deshredder, [&first[0]], now).is_empty());
+        assert!(deshredder.slots.is_empty());
+    }
+
+    #[test]
+    fn a_lost_shred_skips_its_batch_after_the_wait() {
+        let [first, second] = batches();
+        let mut deshredder = Deshredder::default();
+        let now = Instant::now();
+        let without_one = first.iter().chain(&second).filter(|shred| *shred != &first[1]);
+        let events = insert_all(&mut deshredder, without_one, now);
+        assert_eq!(summary(&events), complete(first.len(), second.len())[..1].to_vec());
+
+        assert!(deshredder.tick_at(now + GAP_WAIT / 2).is_empty());
+        let (n1, n2) = (first.len(), second.len());
+        assert_eq!(
+            summary(&deshredder.tick_at(now + GAP_WAIT)),
+            [format!("entries {}..={} 200", n1, n1 + n2 - 1), format!("complete 200 skipped {}", n1)]
+        );
+        assert!(deshredder.slots.is_empty());
+    }
+
+    #[test]
+    fn a_late_shred_closes_the_gap() {
+        let [first, second] = batches();
+        let mut deshredder = Deshredder::default();
+        let now = Instant::now();
+        let without_one = first.iter().chain(&second).filter(|shred| *shred != &first[1]);
+        let mut events = insert_all(&mut deshredder, without_one, now);
+        events.extend(deshredder.tick_at(now + GAP_WAIT / 2));
+        events.extend(insert_all(&mut deshredder, [&first[1]], now + GAP_WAIT / 2));
+        assert_eq!(summary(&events), complete(first.len(), second.len()));
+    }
+
+    #[test]
+    fn idle_slots_are_dropped() {
+        let [first, _] = batches();
+        let mut deshredder = Deshredder::default();
+        let now = Instant::now();
+        insert_all(&mut deshredder, [&first[0]], now);
+        assert!(deshredder.tick_at(now + SLOT_IDLE).is_empty());
+        assert!(deshredder.slots.is_empty());
+        assert!(insert_all(&mut deshredder, [&first[1]], now + SLOT_IDLE).is_empty());
+    }
+}
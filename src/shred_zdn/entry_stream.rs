@@ -0,0 +1,454 @@
+//! WebSocket stream of JSON events built from deshredded slots.
+//!
+//! A client sends one subscription message, e.g.
+//! `{"type": "subscribe", "events": ["entries"], "accounts": ["<base58 key>"], "votes": false}`,
+//! and then receives `slot_start`, `entries` and `slot_complete` events that match it. A client
+//! that falls more than the buffer limit behind is disconnected.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_entry::entry::Entry;
+use solana_sdk::{transaction::VersionedTransaction, vote};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    time::{interval, MissedTickBehavior},
+};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::common::utils::time_to_string;
+use crate::shred_zdn::deshred::{Deshredder, EntryEvent};
+
+/// the event types a client can subscribe to.
+const EVENTS: [&str; 3] = ["slot_start", "entries", "slot_complete"];
+
+/// what a client subscribed to, empty lists match everything.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Subscription {
+    /// entries of `EVENTS`.
+    events: Vec<String>,
+    /// only transactions with one of these static account keys, base58.
+    accounts: HashSet<String>,
+    /// only transactions with one of these signatures, base58.
+    signatures: HashSet<String>,
+    /// include vote transactions.
+    votes: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Subscription),
+}
+
+impl Subscription {
+    /// an error message for event types that do not exist, they would never match.
+    fn validate(&self) -> Result<(), String> {
+        match self.events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+            Some(unknown) => Err(format!("unknown event `{}`, expected one of {}", unknown, EVENTS.join(", "))),
+            None => Ok(()),
+        }
+    }
+
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|wanted| wanted == event)
+    }
+
+    fn matches(&self, tx: &TransactionJson) -> bool {
+        (self.votes || !tx.vote)
+            && (self.accounts.is_empty() || tx.account_keys.iter().any(|key| self.accounts.contains(key)))
+            && (self.signatures.is_empty() || tx.signatures.iter().any(|sig| self.signatures.contains(sig)))
+    }
+
+    /// the JSON text of `event` for this client, None when nothing in it matches.
+    fn render(&self, event: &EventJson) -> Option<String> {
+        match event {
+            EventJson::Plain { kind, value } => self.wants(kind).then(|| value.to_string()),
+            EventJson::Entries { head, entries } => {
+                if !self.wants("entries") {
+                    return None;
+                }
+                let filtered = !self.accounts.is_empty() || !self.signatures.is_empty();
+                let entries = entries
+                    .iter()
+                    .filter_map(|entry| {
+                        let txs = entry.transactions.iter().filter(|tx| self.matches(tx)).map(|tx| &tx.value).collect::<Vec<_>>();
+                        // an entry without matching transactions only matters unfiltered
+                        if filtered && txs.is_empty() {
+                            return None;
+                        }
+                        Some(json!({ "num_hashes": entry.num_hashes, "hash": entry.hash, "transactions": txs }))
+                    })
+                    .collect::<Vec<_>>();
+                if filtered && entries.is_empty() {
+                    return None;
+                }
+                let mut value = head.clone();
+                value["entries"] = Value::Array(entries);
+                Some(value.to_string())
+            }
+        }
+    }
+}
+
+struct TransactionJson {
+    signatures: Vec<String>,
+    account_keys: Vec<String>,
+    vote: bool,
+    value: Value,
+}
+
+struct EntryJson {
+    num_hashes: u64,
+    hash: String,
+    transactions: Vec<TransactionJson>,
+}
+
+/// an event converted once and filtered per client.
+enum EventJson {
+    Plain { kind: &'static str, value: Value },
+    Entries { head: Value, entries: Vec<EntryJson> },
+}
+
+impl EventJson {
+    fn new(event: &EntryEvent) -> Self {
+        match event {
+            EntryEvent::SlotStart { slot, parent, at } => EventJson::Plain {
+                kind: "slot_start",
+                value: json!({ "type": "slot_start", "slot": slot, "parent_slot": parent, "received_at": time_to_string(*at) }),
+            },
+            EntryEvent::Entries { slot, start_index, end_index, entries, at } => EventJson::Entries {
+                head: json!({
+                    "type": "entries",
+                    "slot": slot,
+                    "start_index": start_index,
+                    "end_index": end_index,
+                    "received_at": time_to_string(*at),
+                }),
+                entries: entries.iter().map(entry_json).collect(),
+            },
+            EntryEvent::SlotComplete { slot, entries, transactions, skipped, at } => EventJson::Plain {
+                kind: "slot_complete",
+                value: json!({
+                    "type": "slot_complete",
+                    "slot": slot,
+                    "entries": entries,
+                    "transactions": transactions,
+                    "skipped_shreds": skipped,
+                    "received_at": time_to_string(*at),
+                }),
+            },
+        }
+    }
+}
+
+fn entry_json(entry: &Entry) -> EntryJson {
+    EntryJson {
+        num_hashes: entry.num_hashes,
+        hash: entry.hash.to_string(),
+        transactions: entry.transactions.iter().map(transaction_json).collect(),
+    }
+}
+
+fn transaction_json(tx: &VersionedTransaction) -> TransactionJson {
+    let keys = tx.message.static_account_keys();
+    let signatures = tx.signatures.iter().map(|sig| sig.to_string()).collect::<Vec<_>>();
+    let account_keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+    let value = json!({ "signatures": signatures, "account_keys": account_keys });
+    TransactionJson {
+        signatures,
+        account_keys,
+        vote: keys.contains(&vote::program::id()),
+        value,
+    }
+}
+
+struct Client {
+    id: usize,
+    subscription: Subscription,
+    tx: mpsc::Sender<String>,
+}
+
+enum Feed {
+    Shred(Vec<u8>, SystemTime),
+    /// publish what came before, close the clients and stop, then answer.
+    Close(oneshot::Sender<()>),
+}
+
+/// deshreds the shreds of every source and fans the events out to the WebSocket clients.
+pub struct EntryStream {
+    shreds: UnboundedSender<Feed>,
+    /// set once closing, every connection holds a receiver until it ended.
+    closing: watch::Sender<bool>,
+    clients: Mutex<Vec<Client>>,
+    next_id: AtomicUsize,
+    /// events sent, clients dropped for falling behind.
+    sent: AtomicUsize,
+    slow: AtomicUsize,
+    reported_sent: AtomicUsize,
+}
+
+impl EntryStream {
+    /// note the arrival of a shred, coding shreds and other packets are ignored.
+    pub fn record(&self, shred: &[u8], at: SystemTime) {
+        let _ = self.shreds.send(Feed::Shred(shred.to_vec(), at));
+    }
+
+    /// publish the events of the shreds recorded so far, then close every client once its queue
+    /// is sent, e.g. before exiting. slots still open are not completed.
+    pub async fn close(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.shreds.send(Feed::Close(done)).is_ok() {
+            let _ = wait.await;
+        }
+        self.closing.closed().await;
+    }
+
+    /// connected clients and events sent since the last call.
+    pub fn report(&self) -> String {
+        let sent = self.sent.load(Ordering::Relaxed);
+        let delta = sent - self.reported_sent.swap(sent, Ordering::Relaxed);
+        format!(
+            "clients {}, events {}, slow disconnects {}",
+            self.clients.lock().unwrap().len(),
+            delta,
+            self.slow.load(Ordering::Relaxed),
+        )
+    }
+
+    fn publish(&self, event: &EntryEvent) {
+        let json = EventJson::new(event);
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| {
+            let Some(text) = client.subscription.render(&json) else { return true };
+            match client.tx.try_send(text) {
+                Ok(()) => {
+                    self.sent.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    // dropping the sender closes the connection
+                    warn!("entry stream client {} fell {} events behind, disconnecting", client.id, client.tx.max_capacity());
+                    self.slow.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+/// serve the entry stream on `addr`, each client may be `buffer` events behind before it is dropped.
+pub async fn start_entry_stream(addr: SocketAddr, buffer: usize) -> std::io::Result<Arc<EntryStream>> {
+    let listener = TcpListener::bind(addr).await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (closing, _) = watch::channel(false);
+    let stream = Arc::new(EntryStream {
+        shreds: tx,
+        closing,
+        clients: Mutex::new(Vec::new()),
+        next_id: AtomicUsize::new(0),
+        sent: AtomicUsize::new(0),
+        slow: AtomicUsize::new(0),
+        reported_sent: AtomicUsize::new(0),
+    });
+    info!("entry stream listening on ws://{}", addr);
+
+    tokio::spawn(deshred(rx, Arc::clone(&stream)));
+    let accepting = Arc::clone(&stream);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer)) => {
+                    tokio::spawn(serve(socket, peer, Arc::clone(&accepting), buffer.max(1)));
+                }
+                Err(e) => error!("entry stream accept failed: {}", e),
+            }
+        }
+    });
+    Ok(stream)
+}
+
+async fn deshred(mut rx: UnboundedReceiver<Feed>, stream: Arc<EntryStream>) {
+    let mut deshredder = Deshredder::default();
+    let mut tick = interval(Duration::from_secs(1));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Feed::Shred(shred, at)) => {
+                    for event in deshredder.insert(&shred, at) {
+                        stream.publish(&event);
+                    }
+                }
+                Some(Feed::Close(done)) => {
+                    // dropping the senders ends each connection once its queue is sent
+                    let mut clients = stream.clients.lock().unwrap();
+                    stream.closing.send_replace(true);
+                    clients.clear();
+                    let _ = done.send(());
+                    break;
+                }
+                None => break,
+            },
+            _ = tick.tick() => {
+                for event in deshredder.tick() {
+                    stream.publish(&event);
+                }
+            }
+        }
+    }
+}
+
+/// one client: wait for its subscription, then forward its events until either side closes.
+async fn serve(socket: TcpStream, peer: SocketAddr, stream: Arc<EntryStream>, buffer: usize) {
+    let mut closing = stream.closing.subscribe();
+    let mut ws = match tokio_tungstenite::accept_async(socket).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("entry stream handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    let shutting_down = || CloseFrame { code: CloseCode::Away, reason: "shutting down".into() };
+    if *closing.borrow_and_update() {
+        let _ = ws.send(Message::Close(Some(shutting_down()))).await;
+        return;
+    }
+    let id = stream.next_id.fetch_add(1, Ordering::Relaxed);
+    info!("entry stream client {} connected from {}", id, peer);
+
+    let (tx, mut events) = mpsc::channel(buffer);
+    let mut subscribed = Some(tx);
+    loop {
+        tokio::select! {
+            message = ws.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("entry stream client {}: {}", id, e);
+                        break;
+                    }
+                };
+                let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Subscribe(subscription)) => match subscription.validate() {
+                        Err(message) => json!({ "type": "error", "message": message }),
+                        Ok(()) => {
+                            let mut clients = stream.clients.lock().unwrap();
+                            match subscribed.take() {
+                                // the stream closed since the last check, the queue ends right away
+                                Some(_) if *closing.borrow() => json!({ "type": "error", "message": "shutting down" }),
+                                Some(tx) => {
+                                    info!("entry stream client {} subscribed: {:?}", id, subscription);
+                                    clients.push(Client { id, subscription, tx });
+                                    json!({ "type": "subscribed" })
+                                }
+                                None => json!({ "type": "error", "message": "already subscribed" }),
+                            }
+                        }
+                    },
+                    Err(e) => json!({ "type": "error", "message": e.to_string() }),
+                };
+                if ws.send(Message::Text(reply.to_string())).await.is_err() {
+                    break;
+                }
+            }
+            // not subscribed yet, nothing to send before closing
+            Ok(()) = closing.changed(), if subscribed.is_some() => {
+                let _ = ws.send(Message::Close(Some(shutting_down()))).await;
+                break;
+            }
+            event = events.recv(), if subscribed.is_none() => {
+                let Some(text) = event else {
+                    // dropped when closing, or by `publish` for falling behind
+                    let close = if *closing.borrow() {
+                        shutting_down()
+                    } else {
+                        CloseFrame { code: CloseCode::Again, reason: "too slow, buffer limit reached".into() }
+                    };
+                    let _ = ws.send(Message::Close(Some(close))).await;
+                    break;
+                };
+                if ws.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    stream.clients.lock().unwrap().retain(|client| client.id != id);
+    info!("entry stream client {} disconnected", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(text: &str) -> Result<Subscription, String> {
+        match serde_json::from_str::<ClientMessage>(text) {
+            Ok(ClientMessage::Subscribe(subscription)) => subscription.validate().map(|()| subscription),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    #[test]
+    fn subscriptions_name_known_events_only() {
+        let all = subscription(r#"{"type": "subscribe"}"#).unwrap();
+        assert!(EVENTS.iter().all(|event| all.wants(event)));
+        let entries = subscription(r#"{"type": "subscribe", "events": ["entries"]}"#).unwrap();
+        assert!(entries.wants("entries") && !entries.wants("slot_start"));
+
+        let error = subscription(r#"{"type": "subscribe", "events": ["entries", "slot_end"]}"#).unwrap_err();
+        assert_eq!(error, "unknown event `slot_end`, expected one of slot_start, entries, slot_complete");
+        assert!(subscription(r#"{"type": "subscribe", "event": ["entries"]}"#).unwrap_err().contains("unknown field"));
+    }
+
+    #[test]
+    fn filtered_entries_keep_matching_transactions_only() {
+        let tx = |key: &str, vote: bool| TransactionJson {
+            signatures: vec![format!("sig-{}", key)],
+            account_keys: vec![key.to_string()],
+            vote,
+            value: json!({ "account_keys": [key] }),
+        };
+        let event = EventJson::Entries {
+            head: json!({ "type": "entries", "slot": 1 }),
+            entries: vec![
+                EntryJson { num_hashes: 1, hash: "a".to_string(), transactions: vec![tx("alice", false), tx("bob", false)] },
+                EntryJson { num_hashes: 1, hash: "b".to_string(), transactions: vec![tx("vote", true)] },
+            ],
+        };
+
+        let alice = subscription(r#"{"type": "subscribe", "accounts": ["alice"]}"#).unwrap();
+        let rendered = serde_json::from_str::<Value>(&alice.render(&event).unwrap()).unwrap();
+        assert_eq!(rendered["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(rendered["entries"][0]["transactions"], json!([{ "account_keys": ["alice"] }]));
+
+        // unfiltered keeps every entry, votes only on request
+        let all = subscription(r#"{"type": "subscribe"}"#).unwrap();
+        let rendered = serde_json::from_str::<Value>(&all.render(&event).unwrap()).unwrap();
+        assert_eq!(rendered["entries"][1]["transactions"], json!([]));
+        let nobody = subscription(r#"{"type": "subscribe", "signatures": ["sig-carol"]}"#).unwrap();
+        assert!(nobody.render(&event).is_none());
+    }
+}
@@ -0,0 +1,304 @@
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+/// hold-down never grows beyond this many times the configured failback delay.
+const MAX_HOLD_DOWN_FACTOR: u32 = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FailoverPolicy {
+    /// switch region when no shreds arrived for this long.
+    pub no_shred_timeout: Duration,
+    /// switch region after this many consecutive failed registrations.
+    pub max_register_failures: usize,
+    /// go back to the preferred region after running this long on another one.
+    pub failback_after: Duration,
+}
+
+/// picks the region to register with from the regions sorted by preference.
+/// fails over to the next region on registration failures or missing shreds, and moves
+/// back to the preferred region with a hold-down that doubles every time it fails again.
+/// the failback waits for the preferred region to answer a probe, see `failback_due`.
+pub struct Failover {
+    regions: Vec<String>,
+    current: usize,
+    policy: FailoverPolicy,
+    failures: usize,
+    shred_count: usize,
+    last_shred_at: Instant,
+    switched_at: Instant,
+    hold_down: Duration,
+    failed_back: bool,
+    /// when to check the preferred region again while on another one.
+    failback_at: Instant,
+}
+
+impl Failover {
+    pub fn new(regions: Vec<String>, policy: FailoverPolicy) -> Self {
+        let now = Instant::now();
+        Self {
+            regions,
+            current: 0,
+            policy,
+            failures: 0,
+            shred_count: 0,
+            last_shred_at: now,
+            switched_at: now,
+            hold_down: policy.failback_after,
+            failed_back: false,
+            failback_at: now + policy.failback_after,
+        }
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.regions.get(self.current).map(String::as_str)
+    }
+
+    pub fn regions(&self) -> &[String] {
+        &self.regions
+    }
+
+    /// replace the preference order. on the preferred region this follows the new preferred one,
+    /// after a failover it stays on the current region if it is still listed.
+    pub fn set_regions(&mut self, regions: Vec<String>) {
+        let previous = self.region().map(str::to_string);
+        let failed_over = self.current != 0;
+        self.regions = regions;
+        self.current = match &previous {
+            Some(region) if failed_over => self.regions.iter().position(|r| r == region).unwrap_or(0),
+            _ => 0,
+        };
+        // a different region now, give it the same grace as a failover
+        if self.region() != previous.as_deref() {
+            self.switch(self.current, Instant::now());
+        }
+    }
+
+    pub fn on_register_ok(&mut self) {
+        self.failures = 0;
+    }
+
+    /// returns the new region when the failure triggered a failover.
+    pub fn on_register_err(&mut self) -> Option<String> {
+        self.failures += 1;
+        if self.failures < self.policy.max_register_failures {
+            return None;
+        }
+        let reason = format!("{} consecutive registration failures", self.failures);
+        self.fail_over(&reason, Instant::now())
+    }
+
+    /// feed the forwarded shred counter, returns the new region when a failover happened.
+    pub fn on_tick(&mut self, shred_count: usize) -> Option<String> {
+        self.tick_at(shred_count, Instant::now())
+    }
+
+    /// the preferred region once the hold-down on another region is over. probe it and report
+    /// the result with `on_failback_probe`.
+    pub fn failback_due(&self) -> Option<&str> {
+        self.failback_due_at(Instant::now())
+    }
+
+    /// move back to the preferred region if it answered, returns it when the switch happened.
+    /// otherwise stay and check again after the failback delay.
+    pub fn on_failback_probe(&mut self, answered: bool) -> Option<String> {
+        self.failback_probed_at(answered, Instant::now())
+    }
+
+    fn tick_at(&mut self, shred_count: usize, now: Instant) -> Option<String> {
+        if shred_count != self.shred_count {
+            self.shred_count = shred_count;
+            self.last_shred_at = now;
+        }
+
+        let silent = now.saturating_duration_since(self.last_shred_at);
+        if silent >= self.policy.no_shred_timeout {
+            let reason = format!("no shreds for {}s", silent.as_secs());
+            return self.fail_over(&reason, now);
+        }
+
+        // the preferred region held up for a full hold-down after a failback, forget earlier flapping
+        let on_region = now.saturating_duration_since(self.switched_at);
+        if self.current == 0 && self.failed_back && on_region >= self.hold_down {
+            self.failed_back = false;
+            self.hold_down = self.policy.failback_after;
+            info!("region {} stable, failback hold-down reset to {}s", self.regions[0], self.hold_down.as_secs());
+        }
+        None
+    }
+
+    fn failback_due_at(&self, now: Instant) -> Option<&str> {
+        (self.current != 0 && now >= self.failback_at).then(|| self.regions[0].as_str())
+    }
+
+    fn failback_probed_at(&mut self, answered: bool, now: Instant) -> Option<String> {
+        self.failback_due_at(now)?;
+        if !answered {
+            self.failback_at = now + self.policy.failback_after;
+            warn!(
+                "preferred region {} does not answer, staying on {}, next check in {}s",
+                self.regions[0], self.regions[self.current], self.policy.failback_after.as_secs()
+            );
+            return None;
+        }
+        let from = self.regions[self.current].clone();
+        let on_region = now.saturating_duration_since(self.switched_at);
+        self.switch(0, now);
+        self.failed_back = true;
+        info!("🔀 failback region {} -> {} after {}s", from, self.regions[0], on_region.as_secs());
+        Some(self.regions[0].clone())
+    }
+
+    fn fail_over(&mut self, reason: &str, now: Instant) -> Option<String> {
+        if self.regions.len() < 2 {
+            return None;
+        }
+        // the preferred region failed again before proving itself, wait longer next time
+        if self.current == 0 && self.failed_back {
+            self.failed_back = false;
+            self.hold_down = (self.hold_down * 2).min(self.policy.failback_after * MAX_HOLD_DOWN_FACTOR);
+        }
+        let from = self.regions[self.current].clone();
+        let next = (self.current + 1) % self.regions.len();
+        self.switch(next, now);
+        warn!(
+            "🔀 failover region {} -> {} ({}), failback in {}s",
+            from, self.regions[next], reason, self.hold_down.as_secs()
+        );
+        Some(self.regions[next].clone())
+    }
+
+    fn switch(&mut self, index: usize, now: Instant) {
+        self.current = index;
+        self.failures = 0;
+        self.last_shred_at = now;
+        self.switched_at = now;
+        self.failback_at = now + self.hold_down;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn failover(regions: &[&str]) -> (Failover, Instant) {
+        let policy = FailoverPolicy {
+            no_shred_timeout: secs(10),
+            max_register_failures: 3,
+            failback_after: secs(60),
+        };
+        let failover = Failover::new(regions.iter().map(|r| r.to_string()).collect(), policy);
+        let start = failover.switched_at;
+        (failover, start)
+    }
+
+    /// fail over from the preferred region by silence at `at`.
+    fn go_silent(failover: &mut Failover, at: Instant) -> Option<String> {
+        failover.tick_at(failover.shred_count, at)
+    }
+
+    #[test]
+    fn fails_over_after_register_failures() {
+        let (mut failover, _) = failover(&["fra", "ams", "lon"]);
+        assert_eq!(failover.on_register_err(), None);
+        failover.on_register_ok();
+        assert_eq!(failover.on_register_err(), None);
+        assert_eq!(failover.on_register_err(), None);
+        assert_eq!(failover.on_register_err().as_deref(), Some("ams"));
+        assert_eq!(failover.region(), Some("ams"));
+    }
+
+    #[test]
+    fn fails_over_without_shreds() {
+        let (mut failover, start) = failover(&["fra", "ams"]);
+        assert_eq!(failover.tick_at(1, start + secs(5)), None);
+        // shreds keep the region
+        assert_eq!(failover.tick_at(2, start + secs(14)), None);
+        assert_eq!(go_silent(&mut failover, start + secs(25)).as_deref(), Some("ams"));
+        // and wraps around
+        assert_eq!(go_silent(&mut failover, start + secs(40)).as_deref(), Some("fra"));
+    }
+
+    #[test]
+    fn single_region_stays() {
+        let (mut failover, start) = failover(&["fra"]);
+        assert_eq!(go_silent(&mut failover, start + secs(30)), None);
+        for _ in 0..5 {
+            assert_eq!(failover.on_register_err(), None);
+        }
+        assert_eq!(failover.region(), Some("fra"));
+    }
+
+    #[test]
+    fn fails_back_only_when_preferred_answers() {
+        let (mut failover, start) = failover(&["fra", "ams"]);
+        let switched = start + secs(10);
+        go_silent(&mut failover, switched);
+        assert_eq!(failover.failback_due_at(switched + secs(59)), None);
+        assert_eq!(failover.failback_probed_at(true, switched + secs(59)), None);
+
+        let due = switched + secs(60);
+        assert_eq!(failover.failback_due_at(due), Some("fra"));
+        // still down: stay and check again a failback delay later
+        assert_eq!(failover.failback_probed_at(false, due), None);
+        assert_eq!(failover.region(), Some("ams"));
+        assert_eq!(failover.failback_due_at(due + secs(30)), None);
+        assert_eq!(failover.failback_due_at(due + secs(60)), Some("fra"));
+
+        assert_eq!(failover.failback_probed_at(true, due + secs(60)).as_deref(), Some("fra"));
+        assert_eq!(failover.region(), Some("fra"));
+        assert_eq!(failover.failback_due_at(due + secs(600)), None);
+    }
+
+    #[test]
+    fn hold_down_doubles_on_flapping_up_to_the_cap() {
+        let (mut failover, start) = failover(&["fra", "ams"]);
+        let mut now = start;
+        let mut expected = secs(60);
+        for _ in 0..6 {
+            now += secs(10);
+            assert_eq!(go_silent(&mut failover, now).as_deref(), Some("ams"));
+            assert_eq!(failover.hold_down, expected);
+            assert_eq!(failover.failback_due_at(now + expected - secs(1)), None);
+            now += expected;
+            assert_eq!(failover.failback_probed_at(true, now).as_deref(), Some("fra"));
+            expected = (expected * 2).min(secs(60) * MAX_HOLD_DOWN_FACTOR);
+        }
+        assert_eq!(failover.hold_down, secs(480));
+    }
+
+    #[test]
+    fn hold_down_resets_once_preferred_is_stable() {
+        let (mut failover, start) = failover(&["fra", "ams"]);
+        go_silent(&mut failover, start + secs(10));
+        failover.failback_probed_at(true, start + secs(70));
+        go_silent(&mut failover, start + secs(80));
+        assert_eq!(failover.hold_down, secs(120));
+        failover.failback_probed_at(true, start + secs(200));
+
+        // shreds keep flowing for a full hold-down
+        for (count, at) in (1..=14).map(|i| (i, start + secs(200 + i as u64 * 9))) {
+            assert_eq!(failover.tick_at(count, at), None);
+        }
+        assert_eq!(failover.hold_down, secs(60));
+    }
+
+    #[test]
+    fn set_regions_follows_or_keeps() {
+        let (mut failover, start) = failover(&["fra", "ams", "lon"]);
+        failover.set_regions(vec!["lon".to_string(), "fra".to_string()]);
+        assert_eq!(failover.region(), Some("lon"));
+
+        // after a failover the current region is kept while it is listed
+        go_silent(&mut failover, start + secs(20));
+        assert_eq!(failover.region(), Some("fra"));
+        failover.set_regions(vec!["ams".to_string(), "lon".to_string(), "fra".to_string()]);
+        assert_eq!(failover.region(), Some("fra"));
+        failover.set_regions(vec!["ams".to_string(), "lon".to_string()]);
+        assert_eq!(failover.region(), Some("ams"));
+    }
+}
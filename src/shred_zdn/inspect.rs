@@ -0,0 +1,70 @@
+use std::{
+    io,
+    net::Ipv4Addr,
+    time::SystemTime,
+};
+
+use log::info;
+use tokio::net::UdpSocket;
+
+use crate::common::utils::time_to_string;
+use crate::shred_zdn::{
+    receiver::{shred_header, ShredKind},
+    rx_timestamp,
+    sniffer::{packet_time, Recording},
+};
+
+/// one line per packet, e.g. `slot 250000000 data 12 fec 0 last`.
+pub fn describe(data: &[u8]) -> String {
+    let Some(header) = shred_header(data) else {
+        return format!("not a merkle shred, {} bytes", data.len());
+    };
+    let kind = match header.kind {
+        ShredKind::Data { last_in_slot: true } => format!("data {} fec {} last", header.index, header.fec_set_index),
+        ShredKind::Data { last_in_slot: false } => format!("data {} fec {}", header.index, header.fec_set_index),
+        ShredKind::Code { num_data, num_coding, position } => format!(
+            "code {} fec {} ({} data, {} coding, position {})",
+            header.index, header.fec_set_index, num_data, num_coding, position
+        ),
+    };
+    format!("slot {} {}", header.slot, kind)
+}
+
+fn print(at: SystemTime, data: &[u8]) {
+    println!("{} {}B {}", time_to_string(at), data.len(), describe(data));
+}
+
+/// print the shreds received on `port` until `count` packets were seen, forever without one.
+pub async fn inspect_port(port: u16, count: Option<usize>) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    let _ = rx_timestamp::enable(&socket);
+    info!("inspecting shreds received on port {}", port);
+
+    let mut buf = vec![0u8; 2048];
+    let mut seen = 0;
+    while count.is_none_or(|count| seen < count) {
+        let (len, kernel_at) = rx_timestamp::recv(&socket, &mut buf).await?;
+        print(kernel_at.unwrap_or_else(SystemTime::now), &buf[..len]);
+        seen += 1;
+    }
+    Ok(())
+}
+
+/// print the UDP payloads of a recording, returns the number of packets printed.
+pub fn inspect_recording(recording: Recording, count: Option<usize>) -> Result<usize, pcap::Error> {
+    let Recording { mut cap, payload_offset, nano_ts } = recording;
+    let mut seen = 0;
+    while count.is_none_or(|count| seen < count) {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => return Err(e),
+        };
+        if packet.data.len() <= payload_offset {
+            continue;
+        }
+        print(packet_time(packet.header, nano_ts), &packet.data[payload_offset..]);
+        seen += 1;
+    }
+    Ok(seen)
+}
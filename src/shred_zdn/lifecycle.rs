@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch, Notify},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::shred_zdn::{
+    calibration::{calibrate, CalibrationPolicy},
+    consts::REQUEST_REGION,
+    failover::{Failover, FailoverPolicy},
+    probe::{probe_host, ProbeConfig},
+    ranker::{start_region_ranker, RerankPolicy},
+    send_back::SendBack,
+    session::{RegistrationError, Session},
+    stats::Stats,
+    tx_proxy::TxProxy,
+    zdn_ping::{format_ranking, rank_regions, RegionFilter},
+};
+
+/// Discovering → Probing → Registered, with Degraded whenever the registration is not healthy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleState {
+    /// asking the relay for the region map.
+    Discovering,
+    /// measuring region latency.
+    Probing,
+    /// registered with `region` and receiving shreds.
+    Registered { region: String },
+    /// running, but without a healthy registration.
+    Degraded { reason: String },
+    /// running without the control plane on static regions.
+    Standalone,
+    /// shutting down, registration stopped and the pipeline draining.
+    Stopping,
+}
+
+impl LifecycleState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LifecycleState::Discovering => "discovering",
+            LifecycleState::Probing => "probing",
+            LifecycleState::Registered { .. } => "registered",
+            LifecycleState::Degraded { .. } => "degraded",
+            LifecycleState::Standalone => "standalone",
+            LifecycleState::Stopping => "stopping",
+        }
+    }
+}
+
+impl std::fmt::Display for LifecycleState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LifecycleState::Registered { region } => write!(f, "registered ({})", region),
+            LifecycleState::Degraded { reason } => write!(f, "degraded ({})", reason),
+            state => write!(f, "{}", state.name()),
+        }
+    }
+}
+
+/// state and region channels shared by discovery, registration, the ranker and the admin api.
+pub struct Lifecycle {
+    pub state: watch::Sender<LifecycleState>,
+    /// region -> hostname, as returned by the relay and narrowed by `region_filter`.
+    pub region_map: watch::Sender<HashMap<String, String>>,
+    /// regions sorted by preference, empty until probing is done.
+    pub regions: watch::Sender<Vec<String>>,
+    /// `regions` once the send-back resolver moved the send-back addresses to it, registration
+    /// follows this one so both switch on the same ranking update.
+    pub applied_regions: watch::Sender<Vec<String>>,
+    region_filter: RwLock<RegionFilter>,
+    /// the last map before filtering, so a new filter can be applied to it.
+    unfiltered_map: Mutex<HashMap<String, String>>,
+    /// the filter left none of the regions, reported once until that changes.
+    all_filtered: AtomicBool,
+    /// a new region filter let regions in, the ranker ranks again right away.
+    pub regions_added: Notify,
+}
+
+impl Lifecycle {
+    pub fn new(region_filter: RegionFilter) -> Arc<Self> {
+        Arc::new(Self {
+            state: watch::channel(LifecycleState::Discovering).0,
+            region_map: watch::channel(HashMap::new()).0,
+            regions: watch::channel(Vec::new()).0,
+            applied_regions: watch::channel(Vec::new()).0,
+            region_filter: RwLock::new(region_filter),
+            unfiltered_map: Mutex::new(HashMap::new()),
+            all_filtered: AtomicBool::new(false),
+            regions_added: Notify::new(),
+        })
+    }
+
+    /// replace the region filter and apply it to the current map. excluded regions leave the
+    /// ranking right away, newly included ones make the ranker rank again.
+    pub fn set_region_filter(&self, region_filter: RegionFilter) {
+        *self.region_filter.write().unwrap() = region_filter;
+        let host_map = self.unfiltered_map.lock().unwrap().clone();
+        let before = self.region_map.borrow().clone();
+        self.set_region_map(&host_map);
+        let map = self.region_map.borrow().clone();
+        if map.keys().any(|region| !before.contains_key(region)) {
+            // kept until the ranker waits for it, e.g. while discovery is still probing
+            self.regions_added.notify_one();
+        }
+        self.regions.send_if_modified(|regions| {
+            let before = regions.len();
+            regions.retain(|region| map.contains_key(region));
+            regions.len() != before
+        });
+    }
+
+    /// publish the region map after applying the region filter, subscribers only wake up on changes.
+    /// a filter that leaves no region degrades the state once, discovery waits for a usable map.
+    pub fn set_region_map(&self, host_map: &HashMap<String, String>) {
+        *self.unfiltered_map.lock().unwrap() = host_map.clone();
+        let filter = self.region_filter.read().unwrap().clone();
+        let filtered = filter.apply(host_map);
+        let all_filtered = filtered.is_empty() && !host_map.is_empty();
+        if self.all_filtered.swap(all_filtered, Ordering::Relaxed) != all_filtered && all_filtered {
+            let mut regions = host_map.keys().collect::<Vec<_>>();
+            regions.sort();
+            warn!(
+                "all regions {:?} filtered out by include {:?} exclude {:?}",
+                regions, filter.include, filter.exclude
+            );
+            self.transition(LifecycleState::Degraded { reason: "all regions filtered out".to_string() });
+        }
+        self.region_map.send_if_modified(|map| {
+            if *map == filtered {
+                return false;
+            }
+            *map = filtered;
+            true
+        });
+    }
+
+    pub fn state(&self) -> LifecycleState {
+        self.state.borrow().clone()
+    }
+
+    pub fn transition(&self, state: LifecycleState) {
+        self.state.send_if_modified(|current| {
+            // nothing leaves stopping, tasks still winding down must not report otherwise
+            if *current == state || *current == LifecycleState::Stopping {
+                return false;
+            }
+            info!("lifecycle {} -> {}", current, state);
+            *current = state;
+            true
+        });
+    }
+}
+
+/// startup timeouts, reaching one degrades the state but startup keeps going.
+#[derive(Debug, Clone, Copy)]
+pub struct StartupTimeouts {
+    pub discovery: Duration,
+    pub probing: Duration,
+}
+
+/// keep the session registered, following the failover's choice of region.
+/// fatal errors are sent to `error_tx`, the embedding application decides what to do with them.
+#[allow(clippy::too_many_arguments)]
+pub fn start_registration(
+    mut session: Session,
+    failover_policy: FailoverPolicy,
+    probe: ProbeConfig,
+    register_interval: Duration,
+    lifecycle: &Arc<Lifecycle>,
+    counter: &Arc<AtomicUsize>,
+    send_back: &Arc<SendBack>,
+    tx_proxy: Option<Arc<TxProxy>>,
+    error_tx: UnboundedSender<RegistrationError>,
+) -> JoinHandle<()> {
+    let lifecycle = Arc::clone(lifecycle);
+    let counter = Arc::clone(counter);
+    let send_back = Arc::clone(send_back);
+    let mut regions = lifecycle.applied_regions.subscribe();
+
+    tokio::spawn(async move {
+        let mut is_registered: bool = false;
+        let mut failover: Option<Failover> = None;
+        loop {
+            if regions.has_changed().unwrap_or(false) {
+                let sorted_regions = regions.borrow_and_update().clone();
+                if let Some(failover) = failover.as_mut() {
+                    failover.set_regions(sorted_regions);
+                } else if !sorted_regions.is_empty() {
+                    failover = Some(Failover::new(sorted_regions, failover_policy));
+                }
+            }
+            let region = failover.as_ref().and_then(|failover| failover.region()).map(str::to_string);
+
+            // register to keep online.
+            let keep_alive_ok = match session.keep_alive(region.as_deref().unwrap_or(REQUEST_REGION)).await {
+                Ok(inner) => {
+                    if let Some(failover) = &mut failover {
+                        failover.on_register_ok();
+                    }
+                    if let Some(region) = &region {
+                        if !is_registered {
+                            is_registered = true;
+                            info!("Registered: {}, addr={}", inner.msg, inner.udp_address);
+                            lifecycle.transition(LifecycleState::Registered { region: region.clone() });
+                        }
+                        send_back.negotiate(inner.send_back_mode, session.auth_key(), session.jwt());
+                    }
+                    if let (Some(tx_proxy), Some(config)) = (&tx_proxy, &inner.tx_propagation_config) {
+                        tx_proxy.update(config).await;
+                    }
+                    // get region map from server
+                    if !inner.region_host_map.is_empty() {
+                        if lifecycle.region_map.borrow().is_empty() {
+                            info!("get region map from server");
+                        }
+                        lifecycle.set_region_map(&inner.region_host_map);
+                    }
+                    true
+                }
+                Err(err) if err.is_fatal() => {
+                    if region.is_some() {
+                        lifecycle.transition(LifecycleState::Degraded { reason: err.to_string() });
+                    }
+                    if error_tx.send(err).is_err() {
+                        break;
+                    }
+                    false
+                }
+                Err(err) => {
+                    warn!("Register failed: {}, retrying...", err);
+                    if region.is_some() {
+                        lifecycle.transition(LifecycleState::Degraded { reason: err.to_string() });
+                    }
+                    if failover.as_mut().and_then(|failover| failover.on_register_err()).is_some() {
+                        is_registered = false;
+                    }
+                    false
+                }
+            };
+
+            let recent = counter.load(Ordering::Relaxed);
+            // wait to see if any shreds received.
+            tokio::time::sleep(register_interval).await;
+            let current = counter.load(Ordering::Relaxed);
+            if let Some(region) = &region {
+                if current == recent {
+                    warn!("No recent shreds received...");
+                    lifecycle.transition(LifecycleState::Degraded { reason: "no recent shreds".to_string() });
+                } else if keep_alive_ok {
+                    lifecycle.transition(LifecycleState::Registered { region: region.clone() });
+                }
+            }
+            if failover.as_mut().and_then(|failover| failover.on_tick(current)).is_some() {
+                is_registered = false;
+            }
+
+            // back to the preferred region only once it answers again
+            let preferred = failover.as_ref().and_then(|failover| failover.failback_due()).map(str::to_string);
+            if let (Some(preferred), Some(failover)) = (preferred, failover.as_mut()) {
+                let host = lifecycle.region_map.borrow().get(&preferred).cloned();
+                let answered = match host {
+                    // a few probes, the keep-alive waits for them
+                    Some(host) => probe_host(&host, &ProbeConfig { count: probe.count.min(3), ..probe })
+                        .await
+                        .is_ok_and(|stats| stats.received > 0),
+                    None => false,
+                };
+                if failover.on_failback_probe(answered).is_some() {
+                    is_registered = false;
+                }
+            }
+        }
+    })
+}
+
+/// wait for the region map, probe region latency and publish the ranking, optionally calibrate
+/// the top regions by shred arrival, then keep the ranking fresh with the region ranker, which
+/// re-ranks on `rerank_policy`'s schedule and whenever the region filter lets regions in.
+/// reaching a startup timeout degrades the state and startup goes on.
+pub fn start_discovery(
+    timeouts: StartupTimeouts,
+    probe: ProbeConfig,
+    calibration: Option<CalibrationPolicy>,
+    rerank_policy: Option<RerankPolicy>,
+    lifecycle: &Arc<Lifecycle>,
+    stats: &Arc<Stats>,
+) -> JoinHandle<()> {
+    let lifecycle = Arc::clone(lifecycle);
+    let stats = Arc::clone(stats);
+
+    tokio::spawn(async move {
+        lifecycle.transition(LifecycleState::Discovering);
+        let mut region_map = lifecycle.region_map.subscribe();
+        if timeout(timeouts.discovery, region_map.wait_for(|map| !map.is_empty())).await.is_err() {
+            lifecycle.transition(LifecycleState::Degraded {
+                reason: format!("no region map after {}s", timeouts.discovery.as_secs()),
+            });
+            if region_map.wait_for(|map| !map.is_empty()).await.is_err() {
+                return;
+            }
+        }
+        let host_map = region_map.borrow().clone();
+
+        // find the nearest region
+        lifecycle.transition(LifecycleState::Probing);
+        let ranking = match timeout(timeouts.probing, rank_regions(&host_map, &probe)).await {
+            Ok(ranking) if !ranking.is_empty() => ranking,
+            result => {
+                let reason = match result {
+                    Err(_) => format!("region probing timed out after {}s", timeouts.probing.as_secs()),
+                    _ => "no region answered the probe".to_string(),
+                };
+                lifecycle.transition(LifecycleState::Degraded { reason });
+                // unranked, keep the order stable
+                let mut regions = host_map.keys().map(|region| (region.clone(), 0)).collect::<Vec<_>>();
+                regions.sort();
+                regions
+            }
+        };
+        info!("✅ Nearest region: {}, ranking: {}", ranking[0].0, format_ranking(&ranking));
+        // registration and the send-back resolver pick the ranking up from here
+        publish_ranking(&ranking, &lifecycle);
+
+        // measured shred arrival beats rtt, the ranker starts from the calibrated ranking
+        let ranking = match &calibration {
+            Some(policy) => {
+                let calibrated = calibrate(policy, ranking, &lifecycle, &stats).await;
+                publish_ranking(&calibrated, &lifecycle);
+                calibrated
+            }
+            None => ranking,
+        };
+
+        // re-rank regions while running.
+        info!("starting region ranker");
+        // the ranker runs detached until the process exits
+        drop(start_region_ranker(rerank_policy, probe, calibration, ranking, &lifecycle, &stats));
+    })
+}
+
+fn publish_ranking(ranking: &[(String, u128)], lifecycle: &Lifecycle) {
+    let sorted_regions = ranking.iter().map(|(region, _)| region.clone()).collect::<Vec<_>>();
+    lifecycle.regions.send_replace(sorted_regions);
+}
+
+/// standalone mode: publish the static regions in the given order without registering anywhere.
+pub fn start_standalone(static_regions: Vec<(String, String)>, lifecycle: &Arc<Lifecycle>) -> JoinHandle<()> {
+    let lifecycle = Arc::clone(lifecycle);
+
+    tokio::spawn(async move {
+        lifecycle.transition(LifecycleState::Standalone);
+        let host_map = static_regions.iter().cloned().collect::<HashMap<_, _>>();
+        lifecycle.set_region_map(&host_map);
+        let sorted_regions = static_regions
+            .into_iter()
+            .map(|(region, _)| region)
+            .filter(|region| lifecycle.region_map.borrow().contains_key(region))
+            .collect::<Vec<_>>();
+        lifecycle.regions.send_replace(sorted_regions);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_map(regions: &[&str]) -> HashMap<String, String> {
+        regions.iter().map(|region| (region.to_string(), format!("{}.example.com", region))).collect()
+    }
+
+    fn filter(include: &[&str]) -> RegionFilter {
+        RegionFilter { include: include.iter().map(|region| region.to_string()).collect(), exclude: Vec::new() }
+    }
+
+    #[test]
+    fn filtering_out_every_region_degrades_once() {
+        let lifecycle = Lifecycle::new(filter(&["tokyo"]));
+        let mut state = lifecycle.state.subscribe();
+        lifecycle.set_region_map(&host_map(&["ams", "fra"]));
+        assert!(state.has_changed().unwrap());
+        assert_eq!(
+            *state.borrow_and_update(),
+            LifecycleState::Degraded { reason: "all regions filtered out".to_string() }
+        );
+        assert!(lifecycle.region_map.borrow().is_empty());
+
+        // a later map update with the same result stays quiet
+        lifecycle.transition(LifecycleState::Probing);
+        state.borrow_and_update();
+        lifecycle.set_region_map(&host_map(&["ams", "fra", "ny"]));
+        assert!(!state.has_changed().unwrap());
+
+        // a usable map clears it, the next empty result is reported again
+        lifecycle.set_region_map(&host_map(&["tokyo"]));
+        assert_eq!(lifecycle.region_map.borrow().len(), 1);
+        lifecycle.set_region_map(&host_map(&["ams"]));
+        assert!(state.has_changed().unwrap());
+        assert!(matches!(*state.borrow(), LifecycleState::Degraded { .. }));
+    }
+}
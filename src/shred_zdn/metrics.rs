@@ -0,0 +1,400 @@
+use std::{
+    fmt::{Display, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use enum_map::EnumMap;
+
+use crate::shred_zdn::{
+    args::Source,
+    lifecycle::Lifecycle,
+    send_back::SendBack,
+    session::SessionState,
+    stats::{Stage, Stats, LATENCY_QUANTILES, LEAD_BUCKETS_US},
+    tx_proxy::TxProxy,
+};
+
+const LIFECYCLE_STATES: [&str; 6] = ["discovering", "probing", "registered", "degraded", "standalone", "stopping"];
+const SESSION_STATES: [SessionState; 4] = [
+    SessionState::Unregistered,
+    SessionState::Registered,
+    SessionState::Refreshing,
+    SessionState::Expired,
+];
+
+/// Prometheus text exposition format, one `# HELP`/`# TYPE` header per family.
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP shred_zdn_{name} {help}");
+        let _ = writeln!(self.0, "# TYPE shred_zdn_{name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.0, "shred_zdn_{name}");
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = write!(self.0, "{{{labels}}}");
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn per_source(&mut self, name: &str, help: &str, counters: &EnumMap<Source, AtomicUsize>) {
+        self.family(name, "counter", help);
+        for (source, counter) in counters {
+            self.sample(name, &[("source", source.as_str())], counter.load(Ordering::Relaxed));
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// render every counter and gauge for `GET /metrics`.
+pub fn render(stats: &Stats, lifecycle: &Lifecycle, send_back: &SendBack, tx_proxy: Option<&TxProxy>) -> String {
+    let mut out = Exposition(String::new());
+
+    // sources
+    out.per_source("packets_total", "Packets received per source.", &stats.packets);
+    out.per_source("invalid_packets_total", "Packets that are not merkle shreds, per source.", &stats.invalids);
+    out.per_source("first_arrivals_total", "Shreds seen first from each source.", &stats.firsts);
+
+    // forwarding
+    out.family("forwarded_shreds_total", "counter", "ZDN shreds forwarded to the validator.");
+    out.sample("forwarded_shreds_total", &[], stats.forwarded.load(Ordering::Relaxed));
+    out.family("forward_packets_total", "counter", "Packets sent per forward target.");
+    for (target, value) in stats.forward_sent.values() {
+        out.sample("forward_packets_total", &[("target", &target)], value);
+    }
+    out.family("forward_errors_total", "counter", "Failed sends per forward target.");
+    for (target, value) in stats.forward_errors.values() {
+        out.sample("forward_errors_total", &[("target", &target)], value);
+    }
+    out.family("processing_seconds_total", "counter", "Time spent deduplicating and forwarding.");
+    out.sample("processing_seconds_total", &[], stats.nanos.load(Ordering::Relaxed) as f64 / 1e9);
+    out.family("shm_ring_oversized_shreds_total", "counter", "Shreds too long for a shared-memory ring slot, not written to the ring.");
+    out.sample("shm_ring_oversized_shreds_total", &[], stats.ring_oversized.load(Ordering::Relaxed));
+
+    // pipeline latency
+    let stages = [Stage::KernelToReceiver, Stage::KernelToSniffer, Stage::ReceiveToDedup, Stage::DedupToSend, Stage::CaptureToSendBack];
+    let snapshots = stages.map(|stage| (stage.as_str(), stats.latency.snapshot(stage)));
+    out.family("stage_latency_seconds", "summary", "Latency per pipeline stage since the start.");
+    for (stage, snapshot) in &snapshots {
+        for ((_, quantile), ns) in LATENCY_QUANTILES.iter().zip(snapshot.quantiles_ns) {
+            out.sample("stage_latency_seconds", &[("stage", stage), ("quantile", quantile)], ns as f64 / 1e9);
+        }
+        out.sample("stage_latency_seconds_sum", &[("stage", stage)], snapshot.sum_ns as f64 / 1e9);
+        out.sample("stage_latency_seconds_count", &[("stage", stage)], snapshot.count);
+    }
+    out.family("stage_latency_max_seconds", "gauge", "Highest latency per pipeline stage since the start.");
+    for (stage, snapshot) in &snapshots {
+        out.sample("stage_latency_max_seconds", &[("stage", stage)], snapshot.max_ns as f64 / 1e9);
+    }
+
+    // send-back
+    out.family("sent_back_shreds_total", "counter", "Sniffed shreds sent back to the regions.");
+    out.sample("sent_back_shreds_total", &[], stats.sent_back.load(Ordering::Relaxed));
+    out.family("send_back_errors_total", "counter", "Failed send-back sends.");
+    out.sample("send_back_errors_total", &[], stats.send_back_errors.load(Ordering::Relaxed));
+    out.family("send_back_targets", "gauge", "Current send-back addresses.");
+    out.sample("send_back_targets", &[], send_back.addrs().len());
+
+    // turbine race
+    let race = &stats.turbine_race;
+    out.family("race_wins_total", "counter", "Shreds seen by both ZDN and the local turbine, by who was first.");
+    out.sample("race_wins_total", &[("winner", "zdn")], race.zdn_first.load(Ordering::Relaxed));
+    out.sample("race_wins_total", &[("winner", "turbine")], race.turbine_first.load(Ordering::Relaxed));
+    out.family("zdn_lead_seconds", "histogram", "How much earlier ZDN delivered a shred than the local turbine.");
+    let mut cumulative = 0;
+    for (bound, bucket) in LEAD_BUCKETS_US.iter().zip(&race.lead_buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let le = (*bound as f64 / 1e6).to_string();
+        out.sample("zdn_lead_seconds_bucket", &[("le", &le)], cumulative);
+    }
+    let count = race.lead_count.load(Ordering::Relaxed);
+    out.sample("zdn_lead_seconds_bucket", &[("le", "+Inf")], count);
+    out.sample("zdn_lead_seconds_sum", &[], race.lead_sum_us.load(Ordering::Relaxed) as f64 / 1e6);
+    out.sample("zdn_lead_seconds_count", &[], count);
+
+    // slot completeness
+    let slots = &stats.slots;
+    out.per_source("slots_total", "Finished slots each source delivered shreds of.", &slots.slots);
+    out.per_source("slots_completed_total", "Finished slots each source delivered every data shred of.", &slots.completed);
+    out.per_source("slot_data_shreds_expected_total", "Data shreds of the finished slots, per source.", &slots.expected);
+    out.per_source("slot_data_shreds_missing_total", "Data shreds a source never delivered.", &slots.missing);
+    out.per_source("slot_data_shreds_recoverable_total", "Missing data shreds the source's coding shreds could restore.", &slots.recoverable);
+    out.family("slot_loss_ratio", "gauge", "Missing over expected data shreds since the start, per source.");
+    for (source, _) in &slots.slots {
+        out.sample("slot_loss_ratio", &[("source", source.as_str())], slots.loss(source));
+    }
+    out.family("slot_completion_seconds", "summary", "Time from a slot's first shred until the source delivered all of it.");
+    for (source, completion_us) in &slots.completion_us {
+        let labels = [("source", source.as_str())];
+        out.sample("slot_completion_seconds_sum", &labels, completion_us.load(Ordering::Relaxed) as f64 / 1e6);
+        out.sample("slot_completion_seconds_count", &labels, slots.completed[source].load(Ordering::Relaxed));
+    }
+
+    // captures
+    let captures = stats.captures.read().unwrap();
+    out.family("capture_packets_total", "counter", "Packets captured per interface.");
+    for capture in captures.iter() {
+        out.sample("capture_packets_total", &[("interface", &capture.interface)], capture.captured.load(Ordering::Relaxed));
+    }
+    out.family("capture_kernel_received_total", "counter", "Packets the kernel passed to the capture, per interface.");
+    for capture in captures.iter() {
+        out.sample("capture_kernel_received_total", &[("interface", &capture.interface)], capture.kernel_received.load(Ordering::Relaxed));
+    }
+    out.family("capture_kernel_dropped_total", "counter", "Packets dropped by the kernel capture buffer, per interface.");
+    for capture in captures.iter() {
+        out.sample("capture_kernel_dropped_total", &[("interface", &capture.interface)], capture.kernel_dropped.load(Ordering::Relaxed));
+    }
+    out.family("capture_if_dropped_total", "counter", "Packets dropped by the interface, per interface.");
+    for capture in captures.iter() {
+        out.sample("capture_if_dropped_total", &[("interface", &capture.interface)], capture.if_dropped.load(Ordering::Relaxed));
+    }
+
+    // transaction proxy, the per-forwarder counters start over when the relay changes the forwarders
+    if let Some(tx_proxy) = tx_proxy {
+        out.family("tx_requests_total", "counter", "Requests to the transaction proxy.");
+        out.sample("tx_requests_total", &[], tx_proxy.requests.load(Ordering::Relaxed));
+        out.family("tx_rejected_total", "counter", "Transaction proxy requests rejected as invalid or too large.");
+        out.sample("tx_rejected_total", &[], tx_proxy.rejected.load(Ordering::Relaxed));
+        let targets = tx_proxy.targets();
+        out.family("tx_forward_sent_total", "counter", "Transactions sent per forwarder.");
+        for target in targets.iter() {
+            let labels = [("target", target.addr.as_str()), ("protocol", target.protocol.as_str())];
+            out.sample("tx_forward_sent_total", &labels, target.sent.load(Ordering::Relaxed));
+        }
+        out.family("tx_forward_errors_total", "counter", "Failed sends per forwarder.");
+        for target in targets.iter() {
+            let labels = [("target", target.addr.as_str()), ("protocol", target.protocol.as_str())];
+            out.sample("tx_forward_errors_total", &labels, target.errors.load(Ordering::Relaxed));
+        }
+        out.family("tx_forward_seconds_total", "counter", "Time spent sending per forwarder.");
+        for target in targets.iter() {
+            let labels = [("target", target.addr.as_str()), ("protocol", target.protocol.as_str())];
+            out.sample("tx_forward_seconds_total", &labels, target.nanos.load(Ordering::Relaxed) as f64 / 1e9);
+        }
+    }
+
+    // session and regions
+    let session = &stats.session;
+    out.family("registrations_total", "counter", "Successful registrations and keep-alives per region.");
+    for (region, value) in session.region_registrations.values() {
+        out.sample("registrations_total", &[("region", &region)], value);
+    }
+    out.family("jwt_refreshes_total", "counter", "Successful JWT refreshes.");
+    out.sample("jwt_refreshes_total", &[], session.refreshes.load(Ordering::Relaxed));
+    out.family("jwt_refresh_failures_total", "counter", "Failed JWT refreshes.");
+    out.sample("jwt_refresh_failures_total", &[], session.refresh_failures.load(Ordering::Relaxed));
+    let session_state = SessionState::from_u8(session.state.load(Ordering::Relaxed));
+    out.family("session_state", "gauge", "1 for the current session state.");
+    for state in SESSION_STATES {
+        let name = format!("{:?}", state).to_lowercase();
+        out.sample("session_state", &[("state", &name)], (state == session_state) as u8);
+    }
+
+    let lifecycle_state = lifecycle.state();
+    out.family("lifecycle_state", "gauge", "1 for the current lifecycle state.");
+    for state in LIFECYCLE_STATES {
+        out.sample("lifecycle_state", &[("state", state)], (state == lifecycle_state.name()) as u8);
+    }
+    out.family("region_rank", "gauge", "Position of each region in the current ranking, 0 is preferred.");
+    for (rank, region) in lifecycle.regions.borrow().iter().enumerate() {
+        out.sample("region_rank", &[("region", region)], rank);
+    }
+
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use zdn_proto::types::TxPropagationConfig;
+
+    use super::*;
+    use crate::shred_zdn::{lifecycle::LifecycleState, zdn_ping::RegionFilter};
+
+    /// one parsed sample line: the name, the raw label set and the value.
+    fn samples(text: &str) -> Vec<(String, String, f64)> {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let (series, value) = line.rsplit_once(' ').expect("sample without a value");
+                let (name, labels) = match series.split_once('{') {
+                    Some((name, labels)) => (name, labels.strip_suffix('}').expect("unclosed labels")),
+                    None => (series, ""),
+                };
+                let value = match value {
+                    "+Inf" => f64::INFINITY,
+                    value => value.parse().unwrap_or_else(|_| panic!("bad value in {}", line)),
+                };
+                (name.to_string(), labels.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn value(text: &str, name: &str, labels: &str) -> f64 {
+        samples(text)
+            .into_iter()
+            .find(|(n, l, _)| n == name && l == labels)
+            .unwrap_or_else(|| panic!("no sample {}{{{}}}", name, labels))
+            .2
+    }
+
+    fn render_default(lifecycle: &Lifecycle, tx_proxy: Option<&TxProxy>) -> (Stats, String) {
+        let stats = Stats::new();
+        let text = render(&stats, lifecycle, &SendBack::new(), tx_proxy);
+        (stats, text)
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let mut out = Exposition(String::new());
+        out.family("x_total", "counter", "Help.");
+        out.sample("x_total", &[("target", "a\"b\\c\nd"), ("source", "zdn")], 3);
+        out.sample("x_total", &[], 1.5);
+        assert_eq!(
+            out.0,
+            "# HELP shred_zdn_x_total Help.\n\
+             # TYPE shred_zdn_x_total counter\n\
+             shred_zdn_x_total{target=\"a\\\"b\\\\c\\nd\",source=\"zdn\"} 3\n\
+             shred_zdn_x_total 1.5\n"
+        );
+    }
+
+    #[test]
+    fn every_sample_follows_its_family_header() {
+        let lifecycle = Lifecycle::new(RegionFilter::default());
+        let (_, text) = render_default(&lifecycle, None);
+
+        let mut families = HashMap::<String, (usize, usize, String)>::new();
+        let mut current = None::<(String, String)>;
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                let (name, help) = rest.split_once(' ').unwrap();
+                assert!(!help.is_empty(), "{} has no help", name);
+                families.entry(name.to_string()).or_default().0 += 1;
+            } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "summary", "histogram"].contains(&kind), "{}", line);
+                let family = families.entry(name.to_string()).or_default();
+                family.1 += 1;
+                family.2 = kind.to_string();
+                current = Some((name.to_string(), kind.to_string()));
+            } else {
+                let (family, kind) = current.as_ref().expect("sample before any family");
+                let name = line.split(['{', ' ']).next().unwrap();
+                let suffixes: &[&str] = match kind.as_str() {
+                    "summary" => &["", "_sum", "_count"],
+                    "histogram" => &["_bucket", "_sum", "_count"],
+                    _ => &[""],
+                };
+                assert!(
+                    suffixes.iter().any(|suffix| name == format!("{}{}", family, suffix)),
+                    "{} is not part of {}",
+                    name,
+                    family
+                );
+                assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", name);
+            }
+        }
+        for (name, (help, kind, _)) in &families {
+            assert_eq!((*help, *kind), (1, 1), "{} headers", name);
+            assert!(name.starts_with("shred_zdn_"));
+        }
+        // every line parses
+        assert!(!samples(&text).is_empty());
+    }
+
+    #[test]
+    fn counters_are_totals_with_labels() {
+        let lifecycle = Lifecycle::new(RegionFilter::default());
+        let stats = Stats::new();
+        stats.packets[Source::Zdn].fetch_add(5, Ordering::Relaxed);
+        stats.forwarded.fetch_add(2, Ordering::Relaxed);
+        // logging the delta must not reset what /metrics reports
+        let _ = stats.report();
+        let text = render(&stats, &lifecycle, &SendBack::new(), None);
+        assert_eq!(value(&text, "shred_zdn_packets_total", "source=\"zdn\""), 5.0);
+        assert_eq!(value(&text, "shred_zdn_forwarded_shreds_total", ""), 2.0);
+    }
+
+    #[test]
+    fn lead_histogram_is_cumulative() {
+        let lifecycle = Lifecycle::new(RegionFilter::default());
+        let stats = Stats::new();
+        for lead_us in [-60_000, -2_000, 0, 1_500, 1_500, 30_000, 500_000] {
+            stats.turbine_race.record(lead_us);
+        }
+        let text = render(&stats, &lifecycle, &SendBack::new(), None);
+
+        let buckets = samples(&text)
+            .into_iter()
+            .filter(|(name, _, _)| name == "shred_zdn_zdn_lead_seconds_bucket")
+            .map(|(_, labels, value)| (labels, value))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets.len(), LEAD_BUCKETS_US.len() + 1);
+        assert!(buckets.windows(2).all(|pair| pair[0].1 <= pair[1].1), "{:?}", buckets);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_bucket", "le=\"-0.05\""), 1.0);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_bucket", "le=\"0\""), 3.0);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_bucket", "le=\"0.002\""), 5.0);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_bucket", "le=\"0.1\""), 6.0);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_bucket", "le=\"+Inf\""), 7.0);
+        assert_eq!(value(&text, "shred_zdn_zdn_lead_seconds_count", ""), 7.0);
+        assert!((value(&text, "shred_zdn_zdn_lead_seconds_sum", "") - 0.471).abs() < 1e-9);
+        assert_eq!(value(&text, "shred_zdn_race_wins_total", "winner=\"zdn\""), 5.0);
+        assert_eq!(value(&text, "shred_zdn_race_wins_total", "winner=\"turbine\""), 2.0);
+    }
+
+    #[test]
+    fn states_are_one_hot_and_regions_ranked() {
+        let lifecycle = Lifecycle::new(RegionFilter::default());
+        lifecycle.transition(LifecycleState::Registered { region: "fra".to_string() });
+        lifecycle.regions.send_replace(vec!["fra".to_string(), "ams".to_string()]);
+        let (_, text) = render_default(&lifecycle, None);
+
+        for state in LIFECYCLE_STATES {
+            let expected = if state == "registered" { 1.0 } else { 0.0 };
+            assert_eq!(value(&text, "shred_zdn_lifecycle_state", &format!("state=\"{}\"", state)), expected);
+        }
+        let session_states = samples(&text)
+            .into_iter()
+            .filter(|(name, _, _)| name == "shred_zdn_session_state")
+            .map(|(_, _, value)| value)
+            .collect::<Vec<_>>();
+        assert_eq!((session_states.len(), session_states.iter().sum::<f64>()), (SESSION_STATES.len(), 1.0));
+        assert_eq!(value(&text, "shred_zdn_region_rank", "region=\"fra\""), 0.0);
+        assert_eq!(value(&text, "shred_zdn_region_rank", "region=\"ams\""), 1.0);
+    }
+
+    #[tokio::test]
+    async fn tx_proxy_counters_only_with_the_proxy() {
+        let lifecycle = Lifecycle::new(RegionFilter::default());
+        let (_, text) = render_default(&lifecycle, None);
+        assert!(!text.contains("shred_zdn_tx_"));
+
+        let tx_proxy = TxProxy::new().await;
+        let config = TxPropagationConfig { trader_apis: vec!["127.0.0.1:1".to_string()], tx_forwarders: Vec::new() };
+        tx_proxy.update(&config).await;
+        tx_proxy.requests.fetch_add(3, Ordering::Relaxed);
+        tx_proxy.rejected.fetch_add(1, Ordering::Relaxed);
+        tx_proxy.targets()[0].sent.fetch_add(2, Ordering::Relaxed);
+        let _ = tx_proxy.report();
+
+        let (_, text) = render_default(&lifecycle, Some(&tx_proxy));
+        assert_eq!(value(&text, "shred_zdn_tx_requests_total", ""), 3.0);
+        assert_eq!(value(&text, "shred_zdn_tx_rejected_total", ""), 1.0);
+        let labels = "target=\"127.0.0.1:1\",protocol=\"http\"";
+        assert_eq!(value(&text, "shred_zdn_tx_forward_sent_total", labels), 2.0);
+        assert_eq!(value(&text, "shred_zdn_tx_forward_errors_total", labels), 0.0);
+        assert_eq!(value(&text, "shred_zdn_tx_forward_seconds_total", labels), 0.0);
+    }
+}
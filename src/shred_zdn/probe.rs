@@ -0,0 +1,306 @@
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::shred_zdn::{args::ProbeMethod, stats::percentile};
+
+/// pause between two probes of the same host.
+const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+/// a probe without an answer after this long counts as lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// prefix of the UDP echo probe, the region sends the datagram back unchanged.
+const UDP_PROBE_MAGIC: &[u8] = b"ZDNPROBE";
+const ICMP_PAYLOAD: &[u8] = b"shred-zdn";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeConfig {
+    pub method: ProbeMethod,
+    /// probes per host.
+    pub count: usize,
+    /// port for the TCP and UDP probes, the region's send-back port.
+    pub port: u16,
+}
+
+/// round-trip statistics of the probes sent to one host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProbeStats {
+    pub sent: usize,
+    pub received: usize,
+    pub min_us: i64,
+    pub avg_us: i64,
+    pub p99_us: i64,
+    /// mean difference between consecutive round trips.
+    pub jitter_us: i64,
+}
+
+impl ProbeStats {
+    fn from_rtts(sent: usize, rtts: &[Duration]) -> Self {
+        let rtts_us = rtts.iter().map(|rtt| rtt.as_micros() as i64).collect::<Vec<_>>();
+        let mut sorted = rtts_us.clone();
+        sorted.sort_unstable();
+        let avg_us = match rtts_us.len() {
+            0 => 0,
+            n => rtts_us.iter().sum::<i64>() / n as i64,
+        };
+        let jitter_us = match rtts_us.len() {
+            0 | 1 => 0,
+            n => rtts_us.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<i64>() / (n - 1) as i64,
+        };
+        Self {
+            sent,
+            received: rtts_us.len(),
+            min_us: sorted.first().copied().unwrap_or(0),
+            avg_us,
+            p99_us: percentile(&sorted, 0.99),
+            jitter_us,
+        }
+    }
+
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.received as f64 / self.sent as f64
+    }
+
+    /// average round trip in whole milliseconds, as used for ranking.
+    pub fn avg_ms(&self) -> u128 {
+        ((self.avg_us + 500) / 1000) as u128
+    }
+}
+
+impl std::fmt::Display for ProbeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |us: i64| us as f64 / 1000.0;
+        write!(
+            f,
+            "min {:.2}ms, avg {:.2}ms, p99 {:.2}ms, jitter {:.2}ms, loss {:.0}% ({}/{})",
+            ms(self.min_us), ms(self.avg_us), ms(self.p99_us), ms(self.jitter_us),
+            self.loss() * 100.0, self.received, self.sent,
+        )
+    }
+}
+
+/// probe `host` `config.count` times with the configured method.
+/// `auto` uses ICMP and falls back to TCP for this host when ICMP is unavailable or got no answer,
+/// e.g. when the region or a firewall on the way drops echo requests.
+pub async fn probe_host(host: &str, config: &ProbeConfig) -> io::Result<ProbeStats> {
+    let addr = lookup_host((host, config.port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host)))?;
+    if config.method != ProbeMethod::Auto {
+        return Ok(probe_with(&Prober::open(config.method, addr).await?, addr, config.count).await);
+    }
+
+    match Prober::icmp(addr) {
+        Ok(prober) => {
+            let stats = probe_with(&prober, addr, config.count).await;
+            if stats.received > 0 || config.count == 0 {
+                return Ok(stats);
+            }
+            debug!("No ICMP answer from {}, using TCP connect", host);
+        }
+        Err(e) => debug!("ICMP probing unavailable ({}), using TCP connect", e),
+    }
+    Ok(probe_with(&Prober::Tcp(addr), addr, config.count).await)
+}
+
+async fn probe_with(prober: &Prober, addr: SocketAddr, count: usize) -> ProbeStats {
+    let mut rtts = Vec::with_capacity(count);
+    for seq in 0..count {
+        if seq > 0 {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+        match timeout(PROBE_TIMEOUT, prober.probe(seq as u16)).await {
+            Ok(Ok(rtt)) => rtts.push(rtt),
+            Ok(Err(e)) => debug!("{} probe {} to {} failed: {}", prober.name(), seq, addr, e),
+            Err(_) => debug!("{} probe {} to {} timed out", prober.name(), seq, addr),
+        }
+    }
+    ProbeStats::from_rtts(count, &rtts)
+}
+
+enum Prober {
+    /// ICMP echo on an unprivileged datagram socket (`net.ipv4.ping_group_range` on Linux).
+    Icmp { socket: UdpSocket, v6: bool },
+    /// time to the SYN-ACK, or to the RST when nothing listens.
+    Tcp(SocketAddr),
+    /// datagram echoed back by the region.
+    Udp(UdpSocket),
+}
+
+impl Prober {
+    /// `auto` is resolved per host by `probe_host`, it opens ICMP here.
+    async fn open(method: ProbeMethod, addr: SocketAddr) -> io::Result<Self> {
+        match method {
+            ProbeMethod::Icmp | ProbeMethod::Auto => Self::icmp(addr),
+            ProbeMethod::Tcp => Ok(Prober::Tcp(addr)),
+            ProbeMethod::Udp => {
+                let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Ok(Prober::Udp(socket))
+            }
+        }
+    }
+
+    fn icmp(addr: SocketAddr) -> io::Result<Self> {
+        let (domain, protocol) = match addr {
+            SocketAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            SocketAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        socket.connect(&SocketAddr::new(addr.ip(), 0).into())?;
+        Ok(Prober::Icmp {
+            socket: UdpSocket::from_std(socket.into())?,
+            v6: addr.is_ipv6(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Prober::Icmp { .. } => "icmp",
+            Prober::Tcp(_) => "tcp",
+            Prober::Udp(_) => "udp",
+        }
+    }
+
+    async fn probe(&self, seq: u16) -> io::Result<Duration> {
+        let mut buf = [0u8; 256];
+        let start = Instant::now();
+        match self {
+            Prober::Tcp(addr) => match TcpStream::connect(addr).await {
+                Ok(_) => Ok(start.elapsed()),
+                // a refusal took a full round trip as well
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(start.elapsed()),
+                Err(e) => Err(e),
+            },
+            Prober::Icmp { socket, v6 } => {
+                socket.send(&echo_request(*v6, seq)).await?;
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    if is_echo_reply(*v6, &buf[..len], seq) {
+                        return Ok(start.elapsed());
+                    }
+                }
+            }
+            Prober::Udp(socket) => {
+                let request = [UDP_PROBE_MAGIC, &seq.to_be_bytes()].concat();
+                socket.send(&request).await?;
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    if buf[..len] == request[..] {
+                        return Ok(start.elapsed());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// ICMP(v6) echo request, the kernel fills in the identifier of a datagram socket.
+fn echo_request(v6: bool, seq: u16) -> Vec<u8> {
+    let mut packet = vec![if v6 { 128 } else { 8 }, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(ICMP_PAYLOAD);
+    // the kernel computes the ICMPv6 checksum itself
+    if !v6 {
+        let checksum = icmp_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+fn is_echo_reply(v6: bool, packet: &[u8], seq: u16) -> bool {
+    let reply_type = if v6 { 129 } else { 0 };
+    packet.len() >= 8 && packet[0] == reply_type && packet[6..8] == seq.to_be_bytes()
+}
+
+fn icmp_checksum(packet: &[u8]) -> u16 {
+    let mut sum = packet
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(rtts: &[u64]) -> Vec<Duration> {
+        rtts.iter().map(|ms| Duration::from_millis(*ms)).collect()
+    }
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        // the example of RFC 1071 section 3
+        assert_eq!(icmp_checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        // an odd length is padded with a zero byte
+        assert_eq!(icmp_checksum(&[0x00, 0x01, 0xf2]), !0xf201);
+    }
+
+    #[test]
+    fn echo_requests_carry_the_sequence_and_a_valid_checksum() {
+        let request = echo_request(false, 0x1234);
+        assert_eq!(request[..2], [8, 0]);
+        assert_eq!(request[6..8], [0x12, 0x34]);
+        assert_eq!(&request[8..], ICMP_PAYLOAD);
+        // summing a packet with its checksum in place gives zero
+        assert_eq!(icmp_checksum(&request), 0);
+
+        let request = echo_request(true, 7);
+        assert_eq!(request[..4], [128, 0, 0, 0]);
+        assert_eq!(request[6..8], [0, 7]);
+    }
+
+    #[test]
+    fn only_the_reply_to_the_same_sequence_matches() {
+        for v6 in [false, true] {
+            let mut reply = echo_request(v6, 42);
+            reply[0] = if v6 { 129 } else { 0 };
+            // the kernel sets the identifier of a datagram socket
+            reply[4..6].copy_from_slice(&0xbeefu16.to_be_bytes());
+            assert!(is_echo_reply(v6, &reply, 42));
+            assert!(!is_echo_reply(v6, &reply, 43));
+            assert!(!is_echo_reply(v6, &reply[..7], 42));
+            assert!(!is_echo_reply(v6, &echo_request(v6, 42), 42));
+            assert!(!is_echo_reply(!v6, &reply, 42));
+        }
+    }
+
+    #[test]
+    fn stats_of_answered_probes() {
+        let stats = ProbeStats::from_rtts(4, &ms(&[10, 12, 11, 15]));
+        assert_eq!((stats.sent, stats.received), (4, 4));
+        assert_eq!((stats.min_us, stats.avg_us, stats.p99_us), (10_000, 12_000, 15_000));
+        // (2 + 1 + 4) / 3 ms
+        assert_eq!(stats.jitter_us, 2_333);
+        assert_eq!((stats.loss(), stats.avg_ms()), (0.0, 12));
+    }
+
+    #[test]
+    fn stats_count_missing_answers_as_loss() {
+        let stats = ProbeStats::from_rtts(5, &ms(&[10, 12, 11, 15]));
+        assert_eq!((stats.sent, stats.received, stats.avg_us), (5, 4, 12_000));
+        assert!((stats.loss() - 0.2).abs() < 1e-9);
+
+        let lost = ProbeStats::from_rtts(3, &[]);
+        assert_eq!((lost.received, lost.min_us, lost.avg_us, lost.p99_us, lost.jitter_us), (0, 0, 0, 0, 0));
+        assert_eq!((lost.loss(), lost.avg_ms()), (1.0, 0));
+    }
+}
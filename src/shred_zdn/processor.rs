@@ -1,64 +1,104 @@
 use std::{
     collections::HashMap,
-    net::Ipv4Addr,
+    net::{Ipv4Addr, SocketAddr},
     sync::{atomic::{AtomicUsize, Ordering}, Arc},
-    time::{Duration, Instant},
+    time::{Instant, SystemTime},
 };
 
-use tokio::{net::UdpSocket, select, sync::mpsc::UnboundedReceiver, time};
+use tokio::{net::UdpSocket, select, sync::{mpsc::UnboundedReceiver, watch}, task::JoinHandle, time};
 use log::{error};
 use nohash_hasher::BuildNoHashHasher;
 
+use crate::common::utils::diff_time;
 use crate::shred_zdn::args::{Opts, Source};
-use crate::shred_zdn::stats::Stats;
+use crate::shred_zdn::shm_ring::ShmRingWriter;
+use crate::shred_zdn::stats::{Stage, Stats};
 
 pub async fn start_processor(
     opts: &Opts,
     stats: &Arc<Stats>,
-    mut receiver: UnboundedReceiver<(Source, Vec<u8>, u64)>,
+    mut receiver: UnboundedReceiver<(Source, Vec<u8>, u64, SystemTime, Instant)>,
     counter: &Arc<AtomicUsize>,
-) {
-    let mut current = HashMap::<u64, Source, BuildNoHashHasher<u64>>::default();
-    let mut preparing = HashMap::<u64, Source, BuildNoHashHasher<u64>>::default();
+    mut forwards: watch::Receiver<Vec<SocketAddr>>,
+    mut ring: Option<ShmRingWriter>,
+) -> JoinHandle<()> {
+    let mut current = HashMap::<u64, (Source, SystemTime), BuildNoHashHasher<u64>>::default();
+    let mut preparing = HashMap::<u64, (Source, SystemTime), BuildNoHashHasher<u64>>::default();
     let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.expect("bind failed");
-    let addrs = opts.forwards.clone();
-    let mut rotate_interval = time::interval(Duration::from_secs(15));
-    let stats = Arc::clone(&stats);
-    let counter = Arc::clone(&counter);
+    let stats = Arc::clone(stats);
+    // per-target counters, looked up once per change of the forward addresses
+    let target_stats = Arc::clone(&stats);
+    let forward_targets = move |addrs: &[SocketAddr]| {
+        let stats = &target_stats;
+        addrs
+            .iter()
+            .map(|addr| {
+                let label = addr.to_string();
+                (*addr, stats.forward_sent.counter(&label), stats.forward_errors.counter(&label))
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut targets = forward_targets(&forwards.borrow_and_update());
+    let mut rotate_interval = time::interval(opts.dedup_rotation());
+    let counter = Arc::clone(counter);
 
     tokio::spawn(async move {
         loop {
             select! {
-                // process shreds from 0slot.trade
-                Some((source, buf, hash)) = receiver.recv() => {
+                // process shreds from 0slot.trade, ends once the receivers stopped and the queue is drained
+                received = receiver.recv() => {
+                    let Some((source, buf, hash, received_at, read_at)) = received else { break };
                     let now = Instant::now();
+                    stats.latency.record(Stage::ReceiveToDedup, now.duration_since(read_at));
                     scopeguard::defer! {
                         stats.nanos.fetch_add(now.elapsed().as_nanos() as u64, Ordering::Relaxed);
                     }
 
-                    if current.contains_key(&hash) {
+                    if let Some(&(first, first_at)) = current.get(&hash) {
+                        // ZDN vs reference lead by receive time, sampled while a region is calibrated
+                        match (first, source) {
+                            (Source::Zdn, Source::Reference) => stats.calibration.record(diff_time(first_at, received_at) as i64),
+                            (Source::Reference, Source::Zdn) => stats.calibration.record(diff_time(received_at, first_at) as i64),
+                            _ => {}
+                        }
                         continue;
                     }
-                    current.insert(hash, source);
-                    preparing.insert(hash, source);
+                    current.insert(hash, (source, received_at));
+                    preparing.insert(hash, (source, received_at));
 
                     stats.firsts[source].fetch_add(1, Ordering::Relaxed);
+                    // co-located readers first, it costs no syscall
+                    if ring.as_mut().is_some_and(|ring| !ring.push(source, received_at, &buf)) {
+                        stats.ring_oversized.fetch_add(1, Ordering::Relaxed);
+                    }
                     if matches!(source, Source::Zdn) {
                         // forward shreds from 0slot.trade to validators
-                        for addr in &addrs {
+                        for (addr, sent, errors) in &targets {
                             match socket.send_to(&buf, addr).await {
-                                Ok(_) => (),
+                                Ok(_) => {
+                                    sent.fetch_add(1, Ordering::Relaxed);
+                                }
                                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                    errors.fetch_add(1, Ordering::Relaxed);
                                     error!("sys buff is full.");
                                 }
-                                Err(e) => error!("Send failed: {}", e),
+                                Err(e) => {
+                                    errors.fetch_add(1, Ordering::Relaxed);
+                                    error!("Send failed: {}", e);
+                                }
                             }
                         }
+                        stats.latency.record(Stage::DedupToSend, now.elapsed());
                         stats.forwarded.fetch_add(1, Ordering::Relaxed);
                         counter.fetch_add(1, Ordering::Relaxed);
                     }
                 },
 
+                // forward addresses reloaded, the socket stays
+                Ok(()) = forwards.changed() => {
+                    targets = forward_targets(&forwards.borrow_and_update());
+                }
+
                 _ = rotate_interval.tick() => {
                     std::mem::swap(&mut current, &mut preparing);
                     preparing.clear();
@@ -66,5 +106,5 @@ pub async fn start_processor(
                 }
             }
         }
-    });
+    })
 }
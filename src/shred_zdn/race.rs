@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use enum_map::EnumMap;
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::shred_zdn::{
+    args::Source,
+    receiver::shred_header,
+    slot_window::{start_slot_aggregator, SlotAggregator, SlotFeed},
+    stats::percentile,
+};
+
+/// compared in every slot, the lead is positive when the first source was earlier.
+const PAIRS: [(Source, Source); 3] = [
+    (Source::Zdn, Source::Reference),
+    (Source::Zdn, Source::LocalTurbine),
+    (Source::Reference, Source::LocalTurbine),
+];
+
+struct Arrival {
+    source: Source,
+    slot: u64,
+    /// shred index and whether it is a coding shred.
+    key: (u32, bool),
+    at: SystemTime,
+}
+
+/// first arrival per source of every shred of one slot.
+#[derive(Default)]
+struct SlotRace {
+    shreds: HashMap<(u32, bool), EnumMap<Source, Option<SystemTime>>>,
+}
+
+/// what one source delivered in a slot, times relative to the slot's first shred from any source.
+#[derive(Default)]
+struct SourceRace {
+    shreds: usize,
+    exclusive: usize,
+    first_us: Option<i64>,
+    last_us: Option<i64>,
+}
+
+struct SlotReport {
+    slot: u64,
+    shreds: usize,
+    sources: EnumMap<Source, SourceRace>,
+    /// sorted leads of the shreds both sources delivered, per entry of `PAIRS`.
+    leads: [Vec<i64>; 3],
+}
+
+impl SlotRace {
+    fn report(&self, slot: u64) -> SlotReport {
+        let start = self
+            .shreds
+            .values()
+            .flat_map(|arrivals| arrivals.values().flatten())
+            .min()
+            .copied()
+            .unwrap_or(UNIX_EPOCH);
+        let mut sources = EnumMap::<Source, SourceRace>::default();
+        let mut leads: [Vec<i64>; 3] = Default::default();
+        for arrivals in self.shreds.values() {
+            let seen_by = arrivals.values().flatten().count();
+            for (source, at) in arrivals.iter().filter_map(|(source, at)| Some((source, (*at)?))) {
+                let race = &mut sources[source];
+                let since_start = lead_us(start, at);
+                race.shreds += 1;
+                race.exclusive += (seen_by == 1) as usize;
+                race.first_us = Some(race.first_us.map_or(since_start, |first| first.min(since_start)));
+                race.last_us = Some(race.last_us.map_or(since_start, |last| last.max(since_start)));
+            }
+            for ((a, b), leads) in PAIRS.iter().zip(&mut leads) {
+                if let (Some(a), Some(b)) = (arrivals[*a], arrivals[*b]) {
+                    leads.push(lead_us(a, b));
+                }
+            }
+        }
+        for leads in &mut leads {
+            leads.sort_unstable();
+        }
+        SlotReport {
+            slot,
+            shreds: self.shreds.len(),
+            sources,
+            leads,
+        }
+    }
+}
+
+impl SlotReport {
+    fn to_json(&self) -> Value {
+        let ms = |us: Option<i64>| us.map(|us| us as f64 / 1000.0);
+        let sources = self
+            .sources
+            .iter()
+            .map(|(source, race)| {
+                let value = json!({
+                    "shreds": race.shreds,
+                    "exclusive": race.exclusive,
+                    "first_shred_ms": ms(race.first_us),
+                    "last_shred_ms": ms(race.last_us),
+                });
+                (source.as_str().to_string(), value)
+            })
+            .collect::<Map<_, _>>();
+        let leads = PAIRS
+            .iter()
+            .zip(&self.leads)
+            .map(|((a, b), leads)| {
+                let value = json!({
+                    "count": leads.len(),
+                    "p10": percentile(leads, 0.10),
+                    "p50": percentile(leads, 0.50),
+                    "p90": percentile(leads, 0.90),
+                    "p99": percentile(leads, 0.99),
+                });
+                (format!("{}_vs_{}", a.as_str(), b.as_str()), value)
+            })
+            .collect::<Map<_, _>>();
+        json!({
+            "slot": self.slot,
+            "shreds": self.shreds,
+            "sources": sources,
+            "lead_us": leads,
+        })
+    }
+}
+
+/// finished slots since the last periodic report.
+#[derive(Default)]
+struct Summary {
+    slots: usize,
+    /// per-slot median lead, per entry of `PAIRS`.
+    medians: [Vec<i64>; 3],
+    exclusive: EnumMap<Source, usize>,
+}
+
+impl Summary {
+    fn extend(&mut self, reports: &[SlotReport]) {
+        for report in reports {
+            self.add(report);
+        }
+    }
+
+    fn add(&mut self, report: &SlotReport) {
+        self.slots += 1;
+        for (medians, leads) in self.medians.iter_mut().zip(&report.leads) {
+            if !leads.is_empty() {
+                medians.push(percentile(leads, 0.50));
+            }
+        }
+        for (source, race) in &report.sources {
+            self.exclusive[source] += race.exclusive;
+        }
+    }
+}
+
+/// per-slot race between the sources, keyed by shred slot and index.
+pub struct RaceAnalyzer {
+    feed: SlotFeed<Arrival>,
+    summary: Arc<Mutex<Summary>>,
+}
+
+impl RaceAnalyzer {
+    /// note the arrival of `shred` from `source`, packets that are not merkle shreds are ignored.
+    pub fn record(&self, source: Source, shred: &[u8], at: SystemTime) {
+        if let Some(header) = shred_header(shred) {
+            self.feed.send(Arrival { source, slot: header.slot, key: header.key(), at });
+        }
+    }
+
+    /// report the slots still open and flush the report file, e.g. before exiting.
+    pub async fn flush(&self) {
+        self.feed.flush().await;
+    }
+
+    /// summary of the slots finished since the last call.
+    pub fn report(&self) -> String {
+        let mut summary = self.summary.lock().unwrap();
+        let Summary { slots, mut medians, exclusive } = std::mem::take(&mut *summary);
+        let mut out = format!("slots {}", slots);
+        for ((a, b), medians) in PAIRS.iter().zip(&mut medians) {
+            if medians.is_empty() {
+                continue;
+            }
+            medians.sort_unstable();
+            let p50 = percentile(medians, 0.50) as f64 / 1000.0;
+            out.push_str(&format!(", {}-vs-{} lead p50 {:+.2}ms", a.as_str(), b.as_str(), p50));
+        }
+        let exclusive = exclusive
+            .iter()
+            .map(|(source, count)| format!("{} {}", source.as_str(), count))
+            .collect::<Vec<_>>()
+            .join(" ");
+        out.push_str(&format!(", exclusive {}", exclusive));
+        out
+    }
+}
+
+/// start the analyzer, every finished slot is appended to `output` as one JSON line.
+pub fn start_race_analyzer(output: PathBuf) -> Arc<RaceAnalyzer> {
+    let summary = Arc::new(Mutex::new(Summary::default()));
+    let writer = match OpenOptions::new().create(true).append(true).open(&output) {
+        Ok(file) => {
+            info!("writing the per-slot race report to {}", output.display());
+            Some(BufWriter::new(file))
+        }
+        Err(e) => {
+            warn!("Failed to open race report {}: {}, only summarizing", output.display(), e);
+            None
+        }
+    };
+    let feed = start_slot_aggregator(RaceReport { summary: Arc::clone(&summary), writer });
+    Arc::new(RaceAnalyzer { feed, summary })
+}
+
+/// summarizes the finished slots and appends them to the report file.
+struct RaceReport {
+    summary: Arc<Mutex<Summary>>,
+    writer: Option<BufWriter<File>>,
+}
+
+impl SlotAggregator for RaceReport {
+    type Arrival = Arrival;
+    type Slot = SlotRace;
+
+    fn slot_of(arrival: &Arrival) -> u64 {
+        arrival.slot
+    }
+
+    fn open(&mut self, _: &Arrival, _: Instant) -> SlotRace {
+        SlotRace::default()
+    }
+
+    fn record(&mut self, race: &mut SlotRace, Arrival { source, key, at, .. }: Arrival, _: Instant) {
+        // keep the first arrival, e.g. captured on several interfaces
+        race.shreds.entry(key).or_default()[source].get_or_insert(at);
+    }
+
+    fn finish(&mut self, slots: Vec<(u64, SlotRace)>) {
+        let reports = slots.into_iter().map(|(slot, race)| race.report(slot)).collect::<Vec<_>>();
+        self.summary.lock().unwrap().extend(&reports);
+        let Some(out) = &mut self.writer else { return };
+        for report in &reports {
+            if let Err(e) = writeln!(out, "{}", report.to_json()) {
+                warn!("Failed to write race report: {}, disabled", e);
+                self.writer = None;
+                return;
+            }
+        }
+        if let Err(e) = out.flush() {
+            warn!("Failed to flush race report: {}", e);
+        }
+    }
+}
+
+/// microseconds from `a` to `b`, positive when `a` was earlier.
+fn lead_us(a: SystemTime, b: SystemTime) -> i64 {
+    match b.duration_since(a) {
+        Ok(lead) => lead.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn at(us: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_micros(us)
+    }
+
+    fn arrive(race: &mut SlotRace, source: Source, key: (u32, bool), us: u64) {
+        RaceReport { summary: Arc::default(), writer: None }.record(
+            race,
+            Arrival { source, slot: 1, key, at: at(us) },
+            Instant::now(),
+        );
+    }
+
+    #[test]
+    fn lead_is_positive_when_the_first_was_earlier() {
+        assert_eq!(lead_us(at(100), at(350)), 250);
+        assert_eq!(lead_us(at(350), at(100)), -250);
+        assert_eq!(lead_us(at(100), at(100)), 0);
+    }
+
+    #[test]
+    fn report_counts_leads_and_exclusive_shreds() {
+        let mut race = SlotRace::default();
+        // data 0 from all three, zdn first
+        arrive(&mut race, Source::Zdn, (0, false), 1_000);
+        arrive(&mut race, Source::Reference, (0, false), 1_400);
+        arrive(&mut race, Source::LocalTurbine, (0, false), 3_000);
+        // data 1 from zdn and turbine, turbine first
+        arrive(&mut race, Source::LocalTurbine, (1, false), 2_000);
+        arrive(&mut race, Source::Zdn, (1, false), 2_500);
+        // code 1 and data 2 from one source each
+        arrive(&mut race, Source::Zdn, (1, true), 4_000);
+        arrive(&mut race, Source::LocalTurbine, (2, false), 5_000);
+        // a later duplicate keeps the first arrival
+        arrive(&mut race, Source::Zdn, (0, false), 9_000);
+
+        let report = race.report(1);
+        assert_eq!(report.shreds, 4);
+        let zdn = &report.sources[Source::Zdn];
+        assert_eq!((zdn.shreds, zdn.exclusive, zdn.first_us, zdn.last_us), (3, 1, Some(0), Some(3_000)));
+        let turbine = &report.sources[Source::LocalTurbine];
+        assert_eq!((turbine.shreds, turbine.exclusive, turbine.first_us, turbine.last_us), (3, 1, Some(1_000), Some(4_000)));
+        let reference = &report.sources[Source::Reference];
+        assert_eq!((reference.shreds, reference.exclusive), (1, 0));
+
+        // zdn vs reference, zdn vs turbine, reference vs turbine
+        assert_eq!(report.leads[0], [400]);
+        assert_eq!(report.leads[1], [-500, 2_000]);
+        assert_eq!(report.leads[2], [1_600]);
+    }
+
+    #[test]
+    fn summary_reports_median_leads_and_exclusive_counts() {
+        let mut race = SlotRace::default();
+        arrive(&mut race, Source::Zdn, (0, false), 0);
+        arrive(&mut race, Source::LocalTurbine, (0, false), 1_500);
+        arrive(&mut race, Source::Zdn, (1, false), 0);
+
+        let mut summary = Summary::default();
+        summary.extend(&[race.report(1), SlotRace::default().report(2)]);
+        assert_eq!(summary.slots, 2);
+        assert_eq!(summary.medians[1], [1_500]);
+        assert!(summary.medians[0].is_empty());
+        assert_eq!(summary.exclusive[Source::Zdn], 1);
+        assert_eq!(summary.exclusive[Source::LocalTurbine], 0);
+    }
+}
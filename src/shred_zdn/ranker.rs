@@ -0,0 +1,103 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use tokio::{select, task::JoinHandle, time::{interval_at, Instant, MissedTickBehavior}};
+
+use crate::shred_zdn::{
+    calibration::{recalibrate, CalibrationPolicy},
+    lifecycle::Lifecycle,
+    probe::ProbeConfig,
+    stats::Stats,
+    zdn_ping::{format_ranking, rank_regions},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RerankPolicy {
+    /// how often region latency is measured again.
+    pub interval: Duration,
+    /// a region must be at least this much faster than the preferred one.
+    pub margin_ms: u128,
+    /// ... in this many consecutive measurements before switching.
+    pub rounds: usize,
+}
+
+/// re-measure region latency on a schedule and switch the preferred region when another region
+/// is consistently faster, the send-back resolver follows the new ranking.
+/// with `calibration`, `initial` is the calibrated ranking and a faster region is not taken as is:
+/// the new ranking's top regions are calibrated again and the earliest of them becomes preferred.
+/// regions let in by a new region filter are ranked right away, also without a `policy`.
+pub fn start_region_ranker(
+    policy: Option<RerankPolicy>,
+    probe: ProbeConfig,
+    calibration: Option<CalibrationPolicy>,
+    initial: Vec<(String, u128)>,
+    lifecycle: &Arc<Lifecycle>,
+    stats: &Arc<Stats>,
+) -> JoinHandle<()> {
+    let lifecycle = Arc::clone(lifecycle);
+    let stats = Arc::clone(stats);
+
+    tokio::spawn(async move {
+        let mut ranking = initial;
+        let mut streak = 0;
+        let (margin_ms, rounds) = policy.map_or((0, 1), |policy| (policy.margin_ms, policy.rounds));
+        let mut timer = policy.map(|policy| {
+            let mut timer = interval_at(Instant::now() + policy.interval, policy.interval);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            timer
+        });
+
+        loop {
+            let scheduled = async {
+                match &mut timer {
+                    Some(timer) => timer.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let regions_added = select! {
+                _ = scheduled => false,
+                _ = lifecycle.regions_added.notified() => true,
+            };
+            let host_map = lifecycle.region_map.borrow().clone();
+            let measured = rank_regions(&host_map, &probe).await;
+            if measured.is_empty() {
+                warn!("Region re-ranking measured no reachable region");
+                continue;
+            }
+
+            let preferred = lifecycle.regions.borrow().first().cloned();
+            let preferred_ms = preferred
+                .as_ref()
+                .and_then(|region| measured.iter().find(|(r, _)| r == region))
+                .map(|(_, ms)| *ms);
+            let (best, best_ms) = &measured[0];
+            let better = match (&preferred, preferred_ms) {
+                (Some(preferred), _) if preferred == best => false,
+                // the preferred region did not answer at all
+                (_, None) => true,
+                (_, Some(preferred_ms)) => best_ms + margin_ms <= preferred_ms,
+            };
+            streak = if better { streak + 1 } else { 0 };
+            if regions_added {
+                info!("region filter let regions in, taking the new ranking");
+            } else if streak < rounds {
+                if better {
+                    info!("region {} faster than {:?} ({}/{} rounds)", best, preferred, streak, rounds);
+                }
+                continue;
+            }
+            streak = 0;
+
+            // rtt is only a proxy for shred arrival, measure the arrival again
+            let measured = match &calibration {
+                Some(calibration) => recalibrate(calibration, measured, &lifecycle, &stats).await,
+                None => measured,
+            };
+            info!("🔀 region ranking changed, old: {}", format_ranking(&ranking));
+            info!("🔀 region ranking changed, new: {}", format_ranking(&measured));
+            let sorted_regions = measured.iter().map(|(region, _)| region.clone()).collect::<Vec<_>>();
+            lifecycle.regions.send_replace(sorted_regions);
+            ranking = measured;
+        }
+    })
+}
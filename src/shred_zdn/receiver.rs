@@ -8,20 +8,28 @@ use std::{
     io::ErrorKind,
     net::Ipv4Addr,
     sync::Arc,
+    time::{Instant, SystemTime},
 };
 
 use ahash::RandomState;
-use solana_ledger::shred::ShredType;
+use solana_ledger::shred::{ShredFlags, ShredType};
 use solana_sdk::signature::SIGNATURE_BYTES;
 use crate::shred_zdn::args::{Opts, Source};
-use crate::shred_zdn::stats::Stats;
-use log::{error, info};
+use crate::shred_zdn::entry_stream::EntryStream;
+use crate::shred_zdn::race::RaceAnalyzer;
+use crate::shred_zdn::rx_timestamp;
+use crate::shred_zdn::slots::SlotTracker;
+use crate::shred_zdn::stats::{Stage, Stats};
+use log::{error, info, warn};
 
 pub async fn start_receivers(
     opts: &Opts,
     stats: &Arc<Stats>,
-    sender: &UnboundedSender<(Source, Vec<u8>, u64)>,
-    sender_sl: &UnboundedSender<Vec<u8>>,
+    sender: &UnboundedSender<(Source, Vec<u8>, u64, SystemTime, Instant)>,
+    sender_sl: &UnboundedSender<(Vec<u8>, SystemTime)>,
+    slots: &Arc<SlotTracker>,
+    race: Option<&Arc<RaceAnalyzer>>,
+    entries: Option<&Arc<EntryStream>>,
 ) -> Vec<JoinHandle<()>> {    
     let state = RandomState::new();
     let mut handles = Vec::new();
@@ -32,19 +40,26 @@ pub async fn start_receivers(
     ];    
     for (source, port) in listeners.into_iter().flatten() {        
         let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await.expect("bind failed");
+        // kernel receive time, the wake-up time can be far behind under load
+        if let Err(e) = rx_timestamp::enable(&socket) {
+            warn!("Kernel timestamps unavailable on port {}: {}, using the wake-up time", port, e);
+        }
         let state = state.clone();
-        let stats = Arc::clone(&stats);
+        let stats = Arc::clone(stats);
         let sender = sender.clone();
         let sender_sl = sender_sl.clone();
+        let slots = Arc::clone(slots);
+        let race = race.cloned();
+        let entries = entries.cloned();
         info!("ready to receive shreds from {:?}:{}", source, port);
         handles.push(tokio::spawn(async move {
             let mut buf = vec![0u8; 2048];
             loop {
                 select! {
                     // listen shreds from 0slot.trade.                    
-                    recv = socket.recv_from(&mut buf) => {
-                        let (len, _) = match recv {                            
-                            Ok((len, _addr)) if len > 0 => (len, _addr),
+                    recv = rx_timestamp::recv(&socket, &mut buf) => {
+                        let (len, kernel_at) = match recv {                            
+                            Ok((len, kernel_at)) if len > 0 => (len, kernel_at),
                             Ok(_) => continue, // len is 0                           
                             Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
                             Err(e) => {
@@ -52,34 +67,34 @@ pub async fn start_receivers(
                                 std::process::exit(1);
                             }
                         };                        
+                        let read_at = Instant::now();
+                        let received_at = match kernel_at {
+                            Some(kernel_at) => {
+                                if let Ok(elapsed) = SystemTime::now().duration_since(kernel_at) {
+                                    stats.latency.record(Stage::KernelToReceiver, elapsed);
+                                }
+                                kernel_at
+                            }
+                            None => SystemTime::now(),
+                        };
                         let data_buf = &buf[..len];
                         stats.packets[source].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-                        let Some(sig) = data_buf.get(SIGNATURE_BYTES) else { continue };
-                        let slice = match *sig {
-                            b if b == u8::from(ShredType::Code) || b == u8::from(ShredType::Data) => {
-                                stats.invalids[source].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                                continue;
-                            }
-                            b => match b & 0xF0 {
-                                0x40 | 0x60 => data_buf.get(..1228),
-                                0x70 => data_buf.get(..1228-64),
-                                0x80|0x90 => data_buf.get(..1203),
-                                0xB0 => data_buf.get(..1203-64),
-                                _ => {
-                                    stats.invalids[source].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                                    continue;
-                                }
-                            },
-                        };
-                        let Some(slice) = slice else {
+                        let Some(slice) = dedup_slice(data_buf) else {
                             stats.invalids[source].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                             continue;
                         };
 
+                        slots.record(source, data_buf, received_at);
+                        if let Some(race) = &race {
+                            race.record(source, data_buf, received_at);
+                        }
+                        if let Some(entries) = &entries {
+                            entries.record(data_buf, received_at);
+                        }
                         let hash = state.hash_one(slice);                        
-                        sender.send((source, data_buf.to_vec(), hash)).unwrap();
-                        sender_sl.send(data_buf.to_vec()).unwrap();                        
+                        sender.send((source, data_buf.to_vec(), hash, received_at, read_at)).unwrap();
+                        sender_sl.send((data_buf.to_vec(), received_at)).unwrap();                        
                     }                    
                 }
             }
@@ -87,3 +102,67 @@ pub async fn start_receivers(
     }    
     handles
 }
+
+/// the signed part of a merkle shred, used as the dedup key so trailing bytes do not matter.
+/// returns None for legacy shreds and truncated packets.
+pub fn dedup_slice(data: &[u8]) -> Option<&[u8]> {
+    let sig = data.get(SIGNATURE_BYTES)?;
+    match *sig {
+        b if b == u8::from(ShredType::Code) || b == u8::from(ShredType::Data) => None,
+        b => match b & 0xF0 {
+            0x40 | 0x60 => data.get(..1228),
+            0x70 => data.get(..1228-64),
+            0x80|0x90 => data.get(..1203),
+            0xB0 => data.get(..1203-64),
+            _ => None,
+        },
+    }
+}
+
+/// the parts of a merkle shred header that slot tracking needs.
+#[derive(Debug, Clone, Copy)]
+pub struct ShredHeader {
+    pub slot: u64,
+    pub index: u32,
+    pub fec_set_index: u32,
+    pub kind: ShredKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShredKind {
+    /// `last_in_slot` is set on the slot's last data shred.
+    Data { last_in_slot: bool },
+    /// dimensions of the FEC set, `position` is this shred's place among its coding shreds.
+    Code { num_data: u16, num_coding: u16, position: u16 },
+}
+
+impl ShredHeader {
+    /// index and whether it is a coding shred, unique within a slot.
+    pub fn key(&self) -> (u32, bool) {
+        (self.index, matches!(self.kind, ShredKind::Code { .. }))
+    }
+}
+
+/// parse the common header and the data or coding header of a merkle shred.
+pub fn shred_header(data: &[u8]) -> Option<ShredHeader> {
+    dedup_slice(data)?;
+    let header = data.get(SIGNATURE_BYTES..SIGNATURE_BYTES + 25)?;
+    let u16_at = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let kind = if header[0] & 0xF0 < 0x80 {
+        ShredKind::Code {
+            num_data: u16_at(19),
+            num_coding: u16_at(21),
+            position: u16_at(23),
+        }
+    } else {
+        let last_in_slot = ShredFlags::LAST_SHRED_IN_SLOT.bits();
+        ShredKind::Data { last_in_slot: header[21] & last_in_slot == last_in_slot }
+    };
+    Some(ShredHeader {
+        slot: u64::from_le_bytes(header[1..9].try_into().unwrap()),
+        index: u32_at(9),
+        fec_set_index: u32_at(15),
+        kind,
+    })
+}
@@ -0,0 +1,210 @@
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::common::utils::set_log_filters;
+use crate::shred_zdn::{
+    args::Opts,
+    config::ConfigError,
+    lifecycle::Lifecycle,
+    send_back::SendBackConfig,
+};
+
+/// the settings that change without a restart, published to the tasks that use them.
+/// the stats interval is read back by the caller from the returned options.
+pub struct LiveConfig {
+    pub forwards: watch::Sender<Vec<SocketAddr>>,
+    pub send_back: watch::Sender<SendBackConfig>,
+    pub lifecycle: Arc<Lifecycle>,
+}
+
+impl LiveConfig {
+    pub fn new(opts: &Opts, lifecycle: &Arc<Lifecycle>) -> Self {
+        Self {
+            forwards: watch::channel(opts.forwards.clone()).0,
+            send_back: watch::channel(opts.send_back_config()).0,
+            lifecycle: Arc::clone(lifecycle),
+        }
+    }
+
+    /// read the config file again and apply what changed, returns the options now in effect.
+    /// changes that need a restart are logged and left out.
+    pub fn reload(&self, running: &Opts) -> Result<Opts, ConfigError> {
+        Ok(self.apply(running, Opts::reload()?))
+    }
+
+    /// publish the live changes from `running` to `loaded`, returns the options now in effect.
+    fn apply(&self, running: &Opts, loaded: Opts) -> Opts {
+        let mut applied = running.clone();
+        let mut diff = Diff::default();
+
+        if diff.check("forwards", &running.forwards, &loaded.forwards) {
+            applied.forwards = loaded.forwards.clone();
+            self.forwards.send_replace(applied.forwards.clone());
+        }
+
+        let include = diff.check("include_regions", &running.include_regions, &loaded.include_regions);
+        let exclude = diff.check("exclude_regions", &running.exclude_regions, &loaded.exclude_regions);
+        if include || exclude {
+            applied.include_regions = loaded.include_regions.clone();
+            applied.exclude_regions = loaded.exclude_regions.clone();
+            self.lifecycle.set_region_filter(applied.region_filter());
+        }
+
+        let send_back = [
+            diff.check("send_back_fan_out", &running.send_back_fan_out, &loaded.send_back_fan_out),
+            diff.check("send_back_port", &running.send_back_port, &loaded.send_back_port),
+            diff.check("send_back", &running.send_back, &loaded.send_back),
+            diff.check("dns_refresh_secs", &running.dns_refresh_secs, &loaded.dns_refresh_secs),
+        ];
+        if send_back.contains(&true) {
+            applied.send_back_fan_out = loaded.send_back_fan_out;
+            applied.send_back_port = loaded.send_back_port;
+            applied.send_back = loaded.send_back.clone();
+            applied.dns_refresh_secs = loaded.dns_refresh_secs;
+            self.send_back.send_replace(applied.send_back_config());
+        }
+
+        if diff.check("log_level", &running.log_level, &loaded.log_level) {
+            applied.log_level = loaded.log_level.clone();
+            set_log_filters(applied.log_level.as_deref().unwrap_or_default());
+        }
+
+        if diff.check("stats_interval_secs", &running.stats_interval_secs, &loaded.stats_interval_secs) {
+            applied.stats_interval_secs = loaded.stats_interval_secs;
+        }
+
+        if diff.check("drain_timeout_secs", &running.drain_timeout_secs, &loaded.drain_timeout_secs) {
+            applied.drain_timeout_secs = loaded.drain_timeout_secs;
+        }
+
+        // bound sockets, the session and the started tasks keep their values
+        macro_rules! restart_only {
+            ($($field:ident),* $(,)?) => {
+                $(diff.reject(stringify!($field), &running.$field, &loaded.$field);)*
+            };
+        }
+        restart_only!(
+            port, reference, protocol, interface, sniffer_port, sniff, no_sniff, relay, standalone,
+            static_region, auth_send_back, tx_proxy, admin, race_report, on_registration_error, discovery_timeout_secs,
+            probe_timeout_secs, probe_method, probe_count, probe_port, calibrate_top_k, calibrate_secs,
+            calibration_state, failover_no_shred_secs, failover_register_failures, failback_secs,
+            rerank_secs, rerank_margin_ms, rerank_rounds, register_interval_secs, dedup_rotation_secs,
+            entry_stream, entry_stream_buffer, shm_ring, shm_ring_capacity,
+        );
+        if running.auth != loaded.auth {
+            diff.rejected.push("auth".to_string());
+        }
+
+        if !diff.rejected.is_empty() {
+            warn!("reload: {} need a restart, not applied", diff.rejected.join(", "));
+        }
+        if diff.applied.is_empty() {
+            info!("reload: nothing to apply");
+        } else {
+            info!("reload: applied {}", diff.applied.join(", "));
+        }
+        applied
+    }
+}
+
+#[derive(Default)]
+struct Diff {
+    applied: Vec<String>,
+    rejected: Vec<String>,
+}
+
+impl Diff {
+    /// note a live change, returns whether the value changed.
+    fn check<T: PartialEq + Debug>(&mut self, name: &str, running: &T, loaded: &T) -> bool {
+        if running == loaded {
+            return false;
+        }
+        self.applied.push(format!("{} {:?} -> {:?}", name, running, loaded));
+        true
+    }
+
+    fn reject<T: PartialEq + Debug>(&mut self, name: &str, running: &T, loaded: &T) {
+        if running != loaded {
+            self.rejected.push(format!("{} {:?} -> {:?}", name, running, loaded));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use clap::Parser;
+    use futures::FutureExt;
+
+    use super::*;
+
+    /// forwarding to `forward`.
+    fn opts(forward: &str, args: &[&str]) -> Opts {
+        let _env = crate::shred_zdn::config::env_lock();
+        Opts::try_parse_from(["shred-zdn", "--port", "8002", "--forwards", forward].iter().chain(args)).unwrap()
+    }
+
+    fn live(running: &Opts) -> LiveConfig {
+        LiveConfig::new(running, &Lifecycle::new(running.region_filter()))
+    }
+
+    #[test]
+    fn live_changes_are_published_and_the_rest_kept() {
+        let running = opts("127.0.0.1:9000", &[]);
+        let live = live(&running);
+        let mut forwards = live.forwards.subscribe();
+        let mut send_back = live.send_back.subscribe();
+
+        let loaded = opts("127.0.0.1:9001", &["--send-back-port", "7000", "--stats-interval-secs", "5", "--reference", "8003"]);
+        let applied = live.apply(&running, loaded);
+        assert_eq!(applied.forwards, ["127.0.0.1:9001".parse().unwrap()]);
+        assert!(forwards.has_changed().unwrap());
+        assert_eq!(*forwards.borrow_and_update(), applied.forwards);
+        assert!(send_back.has_changed().unwrap());
+        assert_eq!(send_back.borrow_and_update().port, 7000);
+        assert_eq!(applied.stats_interval_secs, 5);
+        // the receive sockets are bound already
+        assert_eq!(applied.reference, None);
+
+        // the same config again changes nothing
+        let again = live.apply(&applied, opts("127.0.0.1:9001", &["--send-back-port", "7000", "--stats-interval-secs", "5"]));
+        assert_eq!(again.forwards, applied.forwards);
+        assert!(!forwards.has_changed().unwrap());
+        assert!(!send_back.has_changed().unwrap());
+    }
+
+    #[test]
+    fn a_filter_that_lets_regions_in_wakes_the_ranker() {
+        let running = opts("127.0.0.1:9000", &["--include-regions", "fra"]);
+        let live = live(&running);
+        let lifecycle = &live.lifecycle;
+        let host_map = ["ams", "fra", "ny"].map(|region| (region.to_string(), format!("{}.example.com", region)));
+        lifecycle.set_region_map(&HashMap::from(host_map));
+        lifecycle.regions.send_replace(vec!["fra".to_string()]);
+
+        let applied = live.apply(&running, opts("127.0.0.1:9000", &["--include-regions", "fra,ny"]));
+        assert_eq!(applied.include_regions, ["fra", "ny"]);
+        assert_eq!(lifecycle.region_map.borrow().len(), 2);
+        assert!(lifecycle.regions_added.notified().now_or_never().is_some());
+
+        // dropping a region takes it out of the ranking, nothing to rank
+        lifecycle.regions.send_replace(vec!["ny".to_string(), "fra".to_string()]);
+        live.apply(&applied, opts("127.0.0.1:9000", &["--include-regions", "fra"]));
+        assert_eq!(*lifecycle.regions.borrow(), ["fra"]);
+        assert!(lifecycle.regions_added.notified().now_or_never().is_none());
+    }
+
+    #[test]
+    fn diff_separates_applied_and_rejected_changes() {
+        let mut diff = Diff::default();
+        assert!(!diff.check("stats_interval_secs", &10, &10));
+        assert!(diff.check("stats_interval_secs", &10, &5));
+        diff.reject("port", &8002, &8002);
+        diff.reject("port", &8002, &9000);
+        assert_eq!(diff.applied, ["stats_interval_secs 10 -> 5"]);
+        assert_eq!(diff.rejected, ["port 8002 -> 9000"]);
+    }
+}
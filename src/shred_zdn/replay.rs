@@ -0,0 +1,79 @@
+use std::{
+    fmt,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::shred_zdn::sniffer::{packet_time, Recording};
+
+/// what a replay sent.
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub packets: usize,
+    /// too short to hold a UDP payload.
+    pub skipped: usize,
+    pub send_errors: usize,
+    pub elapsed: Duration,
+    /// from the first to the last packet of the recording.
+    pub recorded: Duration,
+}
+
+impl fmt::Display for ReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replayed {} packets in {:.2}s (recorded over {:.2}s), skipped {}, send errors {}",
+            self.packets,
+            self.elapsed.as_secs_f64(),
+            self.recorded.as_secs_f64(),
+            self.skipped,
+            self.send_errors,
+        )
+    }
+}
+
+/// send every UDP payload of the recording from `socket` to each of `to`, keeping the recorded
+/// gaps scaled by `speed`, 0 sends as fast as possible. blocks until the recording ends.
+pub fn replay(recording: Recording, socket: &UdpSocket, to: &[SocketAddr], speed: f64) -> Result<ReplaySummary, pcap::Error> {
+    let Recording { mut cap, payload_offset, nano_ts } = recording;
+    info!("replaying to {:?} at {}x", to, speed);
+
+    let mut summary = ReplaySummary::default();
+    let mut first = None;
+    let start = Instant::now();
+    loop {
+        let packet = match cap.next_packet() {
+            Ok(packet) => packet,
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => return Err(e),
+        };
+        if packet.data.len() <= payload_offset {
+            summary.skipped += 1;
+            continue;
+        }
+
+        // recorded offset of this packet, scaled to the playback speed
+        let at = packet_time(packet.header, nano_ts);
+        let first_at = *first.get_or_insert(at);
+        summary.recorded = at.duration_since(first_at).unwrap_or_default();
+        if speed > 0.0 {
+            let due = start + summary.recorded.div_f64(speed);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        let payload = &packet.data[payload_offset..];
+        for addr in to {
+            if let Err(e) = socket.send_to(payload, addr) {
+                summary.send_errors += 1;
+                warn!("Send to {} failed: {}", addr, e);
+            }
+        }
+        summary.packets += 1;
+    }
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
@@ -0,0 +1,81 @@
+//! Kernel receive timestamps (`SO_TIMESTAMPNS`) for UDP sockets.
+//!
+//! The kernel stamps each datagram with CLOCK_REALTIME when it arrives, the same clock pcap
+//! uses for captures, so ZDN, reference and turbine arrivals can be compared directly.
+
+use std::{io, time::SystemTime};
+
+use tokio::net::UdpSocket;
+
+/// ask the kernel to stamp every datagram received on `socket`.
+#[cfg(target_os = "linux")]
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            (&on as *const libc::c_int).cast(),
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_TIMESTAMPNS is only supported on Linux"))
+}
+
+/// receive one datagram with its kernel timestamp, None when the kernel attached none.
+#[cfg(target_os = "linux")]
+pub async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+    socket
+        .async_io(tokio::io::Interest::READABLE, || recvmsg(socket, buf))
+        .await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+    Ok((socket.recv(buf).await?, None))
+}
+
+#[cfg(target_os = "linux")]
+fn recvmsg(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+    use std::{os::fd::AsRawFd, time::Duration};
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_TIMESTAMPNS {
+            let ts = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec) };
+            let at = SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+            return Ok((len as usize, Some(at)));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok((len as usize, None))
+}
@@ -0,0 +1,234 @@
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use tokio::{select, sync::watch, task::JoinHandle, time::{interval_at, Instant, MissedTickBehavior}};
+use zdn_proto::{relay::SendBackMode, send_back};
+
+use crate::shred_zdn::{lifecycle::Lifecycle, zdn_ping::resolve_region_addrs};
+
+/// where sniffed shreds are sent back to.
+#[derive(Debug, Clone)]
+pub struct SendBackConfig {
+    /// send back to this many of the top regions.
+    pub fan_out: usize,
+    /// the regions' send-back port.
+    pub port: u16,
+    /// always sent to, in addition to the regions.
+    pub extra: Vec<SocketAddr>,
+    /// resolve the region hosts again this often, None to only follow ranking changes.
+    /// a fixed interval, the records' own TTLs are not known to the system resolver.
+    pub dns_refresh: Option<Duration>,
+}
+
+struct Signer {
+    mode: SendBackMode,
+    material: String,
+    key: [u8; 32],
+    key_id: u32,
+}
+
+/// send-back addresses and the format negotiated with the relay, shared by the register loop,
+/// the region ranker and the sniffer.
+pub struct SendBack {
+    addrs: RwLock<Arc<Vec<SocketAddr>>>,
+    signer: RwLock<Option<Signer>>,
+    seq: AtomicU64,
+}
+
+impl Default for SendBack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SendBack {
+    pub fn new() -> Self {
+        // start from the clock so a restart does not replay old sequence numbers
+        let seq = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        Self {
+            addrs: RwLock::new(Arc::new(Vec::new())),
+            signer: RwLock::new(None),
+            seq: AtomicU64::new(seq),
+        }
+    }
+
+    pub fn addrs(&self) -> Arc<Vec<SocketAddr>> {
+        Arc::clone(&self.addrs.read().unwrap())
+    }
+
+    /// replace all send-back addresses at once, in-flight sends keep the old list.
+    pub fn set_addrs(&self, addrs: Vec<SocketAddr>) {
+        info!(
+            "Send back to: {}",
+            addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")
+        );
+        *self.addrs.write().unwrap() = Arc::new(addrs);
+    }
+
+    /// modes offered in `RegisterRequest.send_back_modes`.
+    pub fn offered_modes(authenticated: bool) -> Vec<i32> {
+        if authenticated {
+            vec![SendBackMode::Jwt as i32, SendBackMode::AuthKey as i32]
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn mode(&self) -> SendBackMode {
+        self.signer
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(SendBackMode::Raw, |signer| signer.mode)
+    }
+
+    /// apply the mode chosen in `RegisterResponse.send_back_mode`, the key is only rebuilt when it changes.
+    pub fn negotiate(&self, mode: i32, auth_key: &str, jwt_token: &str) {
+        let mode = SendBackMode::try_from(mode).unwrap_or_else(|_| {
+            warn!("Unknown send-back mode {}, sending raw shreds", mode);
+            SendBackMode::Raw
+        });
+        let material = match mode {
+            SendBackMode::Raw => {
+                if self.signer.write().unwrap().take().is_some() {
+                    info!("send-back mode changed to {:?}", mode);
+                }
+                return;
+            }
+            SendBackMode::AuthKey => auth_key,
+            SendBackMode::Jwt if jwt_token.is_empty() => {
+                warn!("Relay chose jwt send-back without a jwt token, keeping {:?}", self.mode());
+                return;
+            }
+            SendBackMode::Jwt => jwt_token,
+        };
+
+        let mut signer = self.signer.write().unwrap();
+        if signer.as_ref().is_some_and(|s| s.mode == mode && s.material == material) {
+            return;
+        }
+        let key = send_back::derive_key(material.as_bytes());
+        let key_id = send_back::key_id(&key);
+        info!("send-back mode changed to {:?}, key id {:08x}", mode, key_id);
+        *signer = Some(Signer { mode, material: material.to_string(), key, key_id });
+    }
+
+    /// the packet to send back for `shred`, the shred itself without a copy in raw mode.
+    pub fn wrap<'a>(&self, shred: &'a [u8]) -> Cow<'a, [u8]> {
+        let signer = self.signer.read().unwrap();
+        let Some(signer) = signer.as_ref() else {
+            return Cow::Borrowed(shred);
+        };
+        let header = send_back::Header {
+            key_id: signer.key_id,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+        };
+        let mut packet = Vec::with_capacity(send_back::HEADER_LEN + shred.len());
+        send_back::seal(&signer.key, header, shred, &mut packet);
+        Cow::Owned(packet)
+    }
+}
+
+/// keep the send-back addresses on the top regions: resolve the hosts again whenever the ranking
+/// or the region map changes, and every `dns_refresh` so DNS changes on the ZDN side are picked up.
+/// a new ranking is passed on to registration through `applied_regions` right after the
+/// addresses moved, so shreds are not sent back to the old regions while registered with the new one.
+/// a reloaded `config` applies right away.
+pub fn start_send_back_resolver(
+    mut config: watch::Receiver<SendBackConfig>,
+    lifecycle: &Arc<Lifecycle>,
+    send_back: &Arc<SendBack>,
+) -> JoinHandle<()> {
+    let lifecycle = Arc::clone(lifecycle);
+    let send_back = Arc::clone(send_back);
+
+    tokio::spawn(async move {
+        let mut regions = lifecycle.regions.subscribe();
+        let mut region_map = lifecycle.region_map.subscribe();
+
+        loop {
+            let current = config.borrow_and_update().clone();
+            let mut refresh_timer = current.dns_refresh.map(|period| {
+                let mut timer = interval_at(Instant::now() + period, period);
+                timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                timer
+            });
+
+            // until the config is reloaded
+            loop {
+                let ranking = regions.borrow_and_update().clone();
+                let top = ranking.iter().take(current.fan_out).cloned().collect::<Vec<_>>();
+                let host_map = region_map.borrow_and_update().clone();
+                let mut addrs = resolve_region_addrs(&host_map, &top, current.port).await;
+                if addrs.is_empty() && !top.is_empty() {
+                    warn!("Failed to resolve send-back addresses of {:?}, keeping the old ones", top);
+                } else {
+                    addrs.extend(current.extra.iter().copied());
+                    if *send_back.addrs() != addrs {
+                        send_back.set_addrs(addrs);
+                    }
+                }
+                lifecycle.applied_regions.send_if_modified(|applied| {
+                    if *applied == ranking {
+                        return false;
+                    }
+                    *applied = ranking;
+                    true
+                });
+
+                let refresh = async {
+                    match refresh_timer.as_mut() {
+                        Some(timer) => timer.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let reloaded = async {
+                    // without a sender the config never changes
+                    if config.changed().await.is_err() {
+                        std::future::pending::<()>().await;
+                    }
+                };
+                select! {
+                    changed = regions.changed() => if changed.is_err() { return },
+                    changed = region_map.changed() => if changed.is_err() { return },
+                    _ = refresh => {}
+                    _ = reloaded => break,
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_mode_sends_the_shred_without_a_copy() {
+        let send_back = SendBack::new();
+        let shred = [7u8; 64];
+        assert!(matches!(send_back.wrap(&shred), Cow::Borrowed(packet) if packet == shred));
+    }
+
+    #[test]
+    fn signed_modes_seal_the_shred() {
+        let send_back = SendBack::new();
+        send_back.negotiate(SendBackMode::AuthKey as i32, "secret", "");
+        let shred = [7u8; 64];
+        let packet = send_back.wrap(&shred);
+        let key = send_back::derive_key(b"secret");
+        let (header, opened) = send_back::open(&key, &packet).unwrap();
+        assert_eq!((header.key_id, opened), (send_back::key_id(&key), &shred[..]));
+
+        send_back.negotiate(SendBackMode::Raw as i32, "", "");
+        assert!(matches!(send_back.wrap(&shred), Cow::Borrowed(_)));
+    }
+}
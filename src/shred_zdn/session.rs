@@ -0,0 +1,373 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{info, warn};
+use tonic::{transport::Channel, Code, Status};
+use zdn_proto::{
+    prost::Message,
+    relay::{
+        relay_client::RelayClient, RefreshTokenRequest, RegisterErrorDetail, RegisterErrorReason,
+        RegisterRequest, RegisterResponse,
+    },
+};
+
+use crate::common::net_utils::tonic::generate_channel;
+use crate::shred_zdn::{consts::VERSION, stats::Stats};
+
+/// refresh this long before the jwt expires, at most halfway through its lifetime.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// refresh interval for tokens without a readable `exp` claim.
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SessionState {
+    /// no jwt, the raw auth key is sent.
+    Unregistered = 0,
+    /// registered, keep-alives carry the jwt when one was issued.
+    Registered = 1,
+    /// the jwt is about to expire and is being refreshed.
+    Refreshing = 2,
+    /// the jwt was rejected or could not be refreshed, falling back to the raw auth key.
+    Expired = 3,
+}
+
+impl SessionState {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => SessionState::Registered,
+            2 => SessionState::Refreshing,
+            3 => SessionState::Expired,
+            _ => SessionState::Unregistered,
+        }
+    }
+}
+
+/// why registration failed, mapped from the status code and the `RegisterErrorDetail` in its details.
+#[derive(Debug)]
+pub enum RegistrationError {
+    Ipv6NotSupported(String),
+    InvalidAuthKey(String),
+    IpLimitExceeded(String),
+    VersionUnsupported(String),
+    /// connection failures and transient server errors, worth retrying.
+    Unavailable(String),
+    /// any other rejection.
+    Rejected(Status),
+}
+
+impl RegistrationError {
+    pub fn from_status(status: Status) -> Self {
+        let message = status.message().to_string();
+        let reason = RegisterErrorDetail::decode(status.details())
+            .ok()
+            .and_then(|detail| RegisterErrorReason::try_from(detail.reason).ok())
+            .unwrap_or(RegisterErrorReason::Unspecified);
+        match reason {
+            RegisterErrorReason::Ipv6NotSupported => return RegistrationError::Ipv6NotSupported(message),
+            RegisterErrorReason::InvalidAuthKey => return RegistrationError::InvalidAuthKey(message),
+            RegisterErrorReason::IpLimitExceeded => return RegistrationError::IpLimitExceeded(message),
+            RegisterErrorReason::VersionUnsupported => return RegistrationError::VersionUnsupported(message),
+            RegisterErrorReason::Unspecified => {}
+        }
+        match status.code() {
+            Code::Unauthenticated | Code::PermissionDenied => RegistrationError::InvalidAuthKey(message),
+            Code::ResourceExhausted => RegistrationError::IpLimitExceeded(message),
+            Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled | Code::Unknown | Code::Internal | Code::Aborted => {
+                RegistrationError::Unavailable(status.to_string())
+            }
+            _ => RegistrationError::Rejected(status),
+        }
+    }
+
+    /// retrying will not help until the configuration or the environment changes.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, RegistrationError::Unavailable(_) | RegistrationError::Rejected(_))
+    }
+
+    /// process exit code for embedders that exit on fatal errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RegistrationError::Ipv6NotSupported(_) => 1,
+            RegistrationError::InvalidAuthKey(_) => 2,
+            RegistrationError::IpLimitExceeded(_) => 3,
+            RegistrationError::VersionUnsupported(_) => 4,
+            RegistrationError::Unavailable(_) | RegistrationError::Rejected(_) => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistrationError::Ipv6NotSupported(msg) => write!(f, "IPv6 not supported, please switch to IPv4: {}", msg),
+            RegistrationError::InvalidAuthKey(msg) => write!(f, "Invalid auth key: {}", msg),
+            RegistrationError::IpLimitExceeded(msg) => write!(f, "Exceeded IP registration limit: {}", msg),
+            RegistrationError::VersionUnsupported(msg) => write!(f, "Version {} not supported: {}", VERSION, msg),
+            RegistrationError::Unavailable(msg) => write!(f, "ZDN unavailable: {}", msg),
+            RegistrationError::Rejected(status) => write!(f, "Register rejected: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+struct Jwt {
+    token: String,
+    refresh_at: SystemTime,
+}
+
+impl Jwt {
+    fn new(token: String) -> Self {
+        let refresh_at = refresh_at(jwt_expiry(&token), SystemTime::now());
+        Self { token, refresh_at }
+    }
+}
+
+/// when to refresh a token received at `now` that expires at `exp`. short-lived tokens are
+/// refreshed halfway, so a ttl below the margin does not mean a refresh on every keep-alive.
+fn refresh_at(exp: Option<SystemTime>, now: SystemTime) -> SystemTime {
+    let Some(exp) = exp else { return now + DEFAULT_TOKEN_TTL };
+    let ttl = exp.duration_since(now).unwrap_or_default();
+    exp - REFRESH_MARGIN.min(ttl / 2)
+}
+
+/// registration session with the relay: registers once with the auth key, then keeps alive
+/// with the jwt and refreshes it before expiry. one channel is reused until it fails.
+pub struct Session {
+    host: String,
+    auth_key: String,
+    port: u16,
+    send_back_modes: Vec<i32>,
+    client: Option<RelayClient<Channel>>,
+    jwt: Option<Jwt>,
+    stats: Arc<Stats>,
+}
+
+impl Session {
+    pub fn new(host: &str, auth_key: &str, port: u16, send_back_modes: Vec<i32>, stats: &Arc<Stats>) -> Self {
+        Self {
+            host: host.to_string(),
+            auth_key: auth_key.to_string(),
+            port,
+            send_back_modes,
+            client: None,
+            jwt: None,
+            stats: Arc::clone(stats),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        SessionState::from_u8(self.stats.session.state.load(Ordering::Relaxed))
+    }
+
+    pub fn auth_key(&self) -> &str {
+        &self.auth_key
+    }
+
+    /// current jwt, empty before the first successful registration.
+    pub fn jwt(&self) -> &str {
+        self.jwt.as_ref().map_or("", |jwt| jwt.token.as_str())
+    }
+
+    /// register (or keep alive) against `region`, refreshing the jwt first when it is due.
+    pub async fn keep_alive(&mut self, region: &str) -> Result<RegisterResponse, RegistrationError> {
+        if self.jwt.as_ref().is_some_and(|jwt| SystemTime::now() >= jwt.refresh_at) {
+            self.refresh().await;
+        }
+
+        let result = self.register(region).await;
+        let response = match result {
+            // the relay no longer accepts the jwt, register again with the raw key
+            Err(RegistrationError::InvalidAuthKey(msg)) if self.jwt.is_some() => {
+                warn!("jwt rejected: {}, registering with auth key", msg);
+                self.jwt = None;
+                self.set_state(SessionState::Expired);
+                self.register(region).await?
+            }
+            result => result?,
+        };
+
+        self.stats.session.registrations.fetch_add(1, Ordering::Relaxed);
+        self.stats.session.region_registrations.inc(region);
+        if !response.jwt_token.is_empty() && response.jwt_token != self.jwt() {
+            self.jwt = Some(Jwt::new(response.jwt_token.clone()));
+        }
+        self.set_state(SessionState::Registered);
+        Ok(response)
+    }
+
+    async fn client(&mut self) -> Result<&mut RelayClient<Channel>, RegistrationError> {
+        if self.client.is_none() {
+            let channel = generate_channel(self.host.as_str(), None)
+                .await
+                .map_err(|e| RegistrationError::Unavailable(format!("unable to connect: {}", e)))?;
+            self.client = Some(RelayClient::new(channel));
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    async fn register(&mut self, region: &str) -> Result<RegisterResponse, RegistrationError> {
+        let request = RegisterRequest {
+            auth_header: match &self.jwt {
+                Some(jwt) => jwt.token.clone(),
+                None => self.auth_key.clone(),
+            },
+            version: VERSION.to_string(),
+            server_port: self.port as _,
+            region: region.to_string(),
+            send_back_modes: self.send_back_modes.clone(),
+        };
+        let result = self.client().await?.register(request).await;
+        result.map(|response| response.into_inner()).map_err(|status| self.rpc_error(status))
+    }
+
+    async fn refresh(&mut self) {
+        self.set_state(SessionState::Refreshing);
+        let request = RefreshTokenRequest {
+            auth_header: String::new(),
+            jwt_token: self.jwt().to_string(),
+        };
+        let result = match self.client().await {
+            Ok(client) => client.refresh_token(request).await.map_err(|status| status.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(response) if !response.get_ref().jwt_token.is_empty() => {
+                self.stats.session.refreshes.fetch_add(1, Ordering::Relaxed);
+                self.jwt = Some(Jwt::new(response.into_inner().jwt_token));
+                self.set_state(SessionState::Registered);
+            }
+            result => {
+                let reason = result.err().unwrap_or_else(|| "empty token".to_string());
+                warn!("jwt refresh failed: {}, falling back to auth key", reason);
+                self.stats.session.refresh_failures.fetch_add(1, Ordering::Relaxed);
+                self.jwt = None;
+                self.set_state(SessionState::Expired);
+            }
+        }
+    }
+
+    /// drop the channel on transport failures so the next call reconnects.
+    fn rpc_error(&mut self, status: Status) -> RegistrationError {
+        if matches!(status.code(), Code::Unavailable | Code::Unknown) {
+            self.client = None;
+        }
+        RegistrationError::from_status(status)
+    }
+
+    fn set_state(&self, state: SessionState) {
+        let previous = self.stats.session.state.swap(state as u8, Ordering::Relaxed);
+        if previous != state as u8 {
+            info!("session {:?} -> {:?}", SessionState::from_u8(previous), state);
+        }
+    }
+}
+
+/// `exp` claim of a jwt, None if the token is not a readable jwt.
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(claims: &str) -> String {
+        format!("eyJhbGciOiJIUzI1NiJ9.{}.c2lnbmF0dXJl", URL_SAFE_NO_PAD.encode(claims))
+    }
+
+    #[test]
+    fn jwt_expiry_reads_exp() {
+        let exp = jwt_expiry(&token(r#"{"sub":"client","exp":1700000000}"#));
+        assert_eq!(exp, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+    }
+
+    #[test]
+    fn jwt_expiry_unreadable() {
+        for token in [
+            token(r#"{"sub":"client"}"#),
+            token(r#"{"exp":"tomorrow"}"#),
+            token(r#"{"exp":-1}"#),
+            token("not json"),
+            "eyJhbGciOiJIUzI1NiJ9.!!!.c2lnbmF0dXJl".to_string(),
+            "an-opaque-token".to_string(),
+            String::new(),
+        ] {
+            assert_eq!(jwt_expiry(&token), None, "{}", token);
+        }
+    }
+
+    fn status(code: Code, reason: i32) -> Status {
+        let detail = RegisterErrorDetail { reason, message: "detail".to_string() };
+        Status::with_details(code, "rejected", detail.encode_to_vec().into())
+    }
+
+    #[test]
+    fn registration_error_from_detail() {
+        // the detail wins over the code
+        for (reason, expected) in [
+            (RegisterErrorReason::Ipv6NotSupported, 1),
+            (RegisterErrorReason::InvalidAuthKey, 2),
+            (RegisterErrorReason::IpLimitExceeded, 3),
+            (RegisterErrorReason::VersionUnsupported, 4),
+        ] {
+            let err = RegistrationError::from_status(status(Code::FailedPrecondition, reason as i32));
+            assert_eq!(err.exit_code(), expected, "{:?}", reason);
+            assert!(err.is_fatal());
+            assert!(err.to_string().contains("rejected"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn registration_error_from_code() {
+        let unspecified = RegisterErrorReason::Unspecified as i32;
+        let err = RegistrationError::from_status(status(Code::Unauthenticated, unspecified));
+        assert!(matches!(err, RegistrationError::InvalidAuthKey(_)));
+        let err = RegistrationError::from_status(Status::permission_denied("no"));
+        assert!(matches!(err, RegistrationError::InvalidAuthKey(_)));
+        let err = RegistrationError::from_status(Status::resource_exhausted("too many"));
+        assert!(matches!(err, RegistrationError::IpLimitExceeded(_)));
+        for code in [Code::Unavailable, Code::DeadlineExceeded, Code::Cancelled, Code::Unknown, Code::Internal, Code::Aborted] {
+            let err = RegistrationError::from_status(Status::new(code, "down"));
+            assert!(matches!(err, RegistrationError::Unavailable(_)), "{:?}", code);
+            assert!(!err.is_fatal());
+        }
+        let err = RegistrationError::from_status(Status::invalid_argument("bad port"));
+        assert!(matches!(err, RegistrationError::Rejected(ref status) if status.code() == Code::InvalidArgument));
+        assert!(!err.is_fatal());
+        assert_eq!(err.exit_code(), 5);
+    }
+
+    #[test]
+    fn registration_error_malformed_detail() {
+        // not a RegisterErrorDetail, falls back to the code
+        let garbage = Status::with_details(Code::ResourceExhausted, "full", vec![0xff, 0xff, 0xff].into());
+        assert!(matches!(RegistrationError::from_status(garbage), RegistrationError::IpLimitExceeded(_)));
+        // a reason this build does not know
+        let unknown = status(Code::Unavailable, 99);
+        assert!(matches!(RegistrationError::from_status(unknown), RegistrationError::Unavailable(_)));
+        // empty details
+        let empty = Status::with_details(Code::Unauthenticated, "expired", Vec::new().into());
+        assert!(matches!(RegistrationError::from_status(empty), RegistrationError::InvalidAuthKey(_)));
+    }
+
+    #[test]
+    fn refresh_before_expiry() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let secs = Duration::from_secs;
+        assert_eq!(refresh_at(Some(now + secs(3600)), now), now + secs(3600) - REFRESH_MARGIN);
+        // short-lived tokens halfway through
+        assert_eq!(refresh_at(Some(now + secs(60)), now), now + secs(30));
+        assert_eq!(refresh_at(Some(now + secs(10)), now), now + secs(5));
+        // already expired, refresh right away
+        assert!(refresh_at(Some(now - secs(10)), now) <= now);
+        assert_eq!(refresh_at(None, now), now + DEFAULT_TOKEN_TTL);
+    }
+}
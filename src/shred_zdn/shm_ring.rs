@@ -0,0 +1,493 @@
+//! Single-producer, multi-consumer ring of shreds in a shared-memory file.
+//!
+//! The processor writes every shred that passed dedup with its metadata, readers on the same host map
+//! the file and poll it without syscalls or locks. Layout, all integers little endian:
+//!
+//! - header, `HEADER_SIZE` bytes: magic, version, slot size, capacity and, on its own cache
+//!   line, the number of shreds written so far.
+//! - `capacity` slots of `SLOT_SIZE` bytes: a stamp, the metadata and the payload.
+//!
+//! Shred `seq` goes to slot `seq % capacity`. Its stamp is `2 * seq + 1` while it is written and
+//! `2 * seq + 2` once complete, so a reader detects a slot that was overwritten before or while
+//! it copied it (an overrun), and how far it is behind the writer (its lag).
+
+use std::{
+    fs::OpenOptions,
+    io,
+    path::Path,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::shred_zdn::{
+    args::Source,
+    receiver::{shred_header, ShredKind},
+};
+
+const MAGIC: &[u8; 8] = b"ZDNRING\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 128;
+/// offset of the written count in the header.
+const WRITTEN_OFFSET: usize = 64;
+/// stamp and metadata in front of the payload.
+const SLOT_META: usize = 64;
+/// room for the largest shred.
+pub const MAX_PAYLOAD: usize = 1280;
+const SLOT_SIZE: usize = SLOT_META + MAX_PAYLOAD;
+
+// metadata offsets within a slot
+const STAMP: usize = 0;
+const RECEIVED_AT: usize = 8;
+const WRITTEN_AT: usize = 16;
+const SLOT: usize = 24;
+const INDEX: usize = 32;
+const LEN: usize = 36;
+const SOURCE: usize = 38;
+const KIND: usize = 39;
+
+/// what kind of shred a ring entry holds, from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RingShredKind {
+    Unknown = 0,
+    Data = 1,
+    Code = 2,
+}
+
+/// the metadata of one shred read from the ring, the payload is the reader's copy.
+#[derive(Debug)]
+pub struct RingShred<'a> {
+    pub seq: u64,
+    pub source: Option<Source>,
+    pub kind: RingShredKind,
+    pub slot: u64,
+    pub index: u32,
+    /// kernel receive time, nanoseconds since the epoch.
+    pub received_at_ns: u64,
+    /// when the processor wrote it, nanoseconds since the epoch.
+    pub written_at_ns: u64,
+    pub payload: &'a [u8],
+}
+
+/// the result of one poll.
+#[derive(Debug)]
+pub enum Poll<'a> {
+    Shred(RingShred<'a>),
+    /// nothing new since the last shred.
+    Empty,
+    /// the writer lapped the reader, `lost` shreds were skipped and the next poll continues
+    /// with the oldest one still in the ring.
+    Overrun { lost: u64 },
+}
+
+fn source_code(source: Source) -> u8 {
+    match source {
+        Source::Zdn => 0,
+        Source::Reference => 1,
+        Source::LocalTurbine => 2,
+    }
+}
+
+fn source_from_code(code: u8) -> Option<Source> {
+    match code {
+        0 => Some(Source::Zdn),
+        1 => Some(Source::Reference),
+        2 => Some(Source::LocalTurbine),
+        _ => None,
+    }
+}
+
+fn epoch_ns(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// a u64 of the mapping as an atomic, `offset` is 8-byte aligned and the mapping page aligned.
+fn atomic_at<'a>(base: *const u8, offset: usize) -> &'a AtomicU64 {
+    unsafe { &*(base.add(offset) as *const AtomicU64) }
+}
+
+/// copy into the mapping a word at a time with relaxed atomic stores, readers may be copying the
+/// same words. `offset` is 8-byte aligned, the last word is padded with zeros.
+fn store_words(base: *const u8, offset: usize, bytes: &[u8]) {
+    for (i, chunk) in bytes.chunks(8).enumerate() {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        atomic_at(base, offset + i * 8).store(u64::from_le_bytes(word), Ordering::Relaxed);
+    }
+}
+
+/// copy out of the mapping a word at a time with relaxed atomic loads, the writer may be
+/// overwriting the same words.
+fn load_words(base: *const u8, offset: usize, buf: &mut [u8]) {
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let word = atomic_at(base, offset + i * 8).load(Ordering::Relaxed).to_le_bytes();
+        chunk.copy_from_slice(&word[..chunk.len()]);
+    }
+}
+
+/// the writing side, owned by the processor.
+pub struct ShmRingWriter {
+    map: MmapMut,
+    capacity: u64,
+    written: u64,
+}
+
+impl ShmRingWriter {
+    /// create or reset the ring file with room for `capacity` shreds, a power of two.
+    /// readers of a previous run notice the reset and start over. a file of another size is
+    /// replaced instead of resized, so readers that still map it are not cut off.
+    pub fn create(path: &Path, capacity: usize) -> io::Result<Self> {
+        if !capacity.is_power_of_two() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the ring capacity must be a power of two"));
+        }
+        let len = (HEADER_SIZE + capacity * SLOT_SIZE) as u64;
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.len() != len => std::fs::remove_file(path)?,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        file.set_len(len)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+
+        let base = map.as_mut_ptr();
+        atomic_at(base, WRITTEN_OFFSET).store(0, Ordering::Release);
+        for slot in 0..capacity {
+            atomic_at(base, HEADER_SIZE + slot * SLOT_SIZE + STAMP).store(0, Ordering::Relaxed);
+        }
+        map[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        map[12..16].copy_from_slice(&(SLOT_SIZE as u32).to_le_bytes());
+        map[16..24].copy_from_slice(&(capacity as u64).to_le_bytes());
+        // the magic last, a reader never sees a half written header
+        fence(Ordering::Release);
+        map[..8].copy_from_slice(MAGIC);
+
+        Ok(Self { map, capacity: capacity as u64, written: 0 })
+    }
+
+    /// append a shred, payloads longer than `MAX_PAYLOAD` are not written.
+    pub fn push(&mut self, source: Source, received_at: SystemTime, payload: &[u8]) -> bool {
+        if payload.len() > MAX_PAYLOAD {
+            return false;
+        }
+        let seq = self.written;
+        let offset = HEADER_SIZE + (seq & (self.capacity - 1)) as usize * SLOT_SIZE;
+        let base = self.map.as_mut_ptr();
+        let stamp = atomic_at(base, offset + STAMP);
+
+        stamp.store(2 * seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let (kind, slot, index) = match shred_header(payload) {
+            Some(header) => match header.kind {
+                ShredKind::Data { .. } => (RingShredKind::Data, header.slot, header.index),
+                ShredKind::Code { .. } => (RingShredKind::Code, header.slot, header.index),
+            },
+            None => (RingShredKind::Unknown, 0, 0),
+        };
+        // the metadata words behind the stamp
+        let mut meta = [0; KIND + 1];
+        let mut put = |at: usize, bytes: &[u8]| meta[at..at + bytes.len()].copy_from_slice(bytes);
+        put(RECEIVED_AT, &epoch_ns(received_at).to_le_bytes());
+        put(WRITTEN_AT, &epoch_ns(SystemTime::now()).to_le_bytes());
+        put(SLOT, &slot.to_le_bytes());
+        put(INDEX, &index.to_le_bytes());
+        put(LEN, &(payload.len() as u16).to_le_bytes());
+        put(SOURCE, &[source_code(source), kind as u8]);
+        store_words(base, offset + RECEIVED_AT, &meta[RECEIVED_AT..]);
+        store_words(base, offset + SLOT_META, payload);
+        stamp.store(2 * seq + 2, Ordering::Release);
+
+        self.written = seq + 1;
+        atomic_at(base, WRITTEN_OFFSET).store(self.written, Ordering::Release);
+        true
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// a consumer of the ring, each reader keeps its own position.
+pub struct ShmRingReader {
+    map: Mmap,
+    capacity: u64,
+    next: u64,
+    overruns: u64,
+    lost: u64,
+    buf: Box<[u8; MAX_PAYLOAD]>,
+}
+
+impl ShmRingReader {
+    /// map the ring at `path`, reading starts with the next shred written.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg));
+        if map.len() < HEADER_SIZE || &map[..8] != MAGIC {
+            return Err(invalid("not a shred ring"));
+        }
+        fence(Ordering::Acquire);
+        let u32_at = |at: usize| u32::from_le_bytes(map[at..at + 4].try_into().unwrap());
+        if u32_at(8) != VERSION || u32_at(12) as usize != SLOT_SIZE {
+            return Err(invalid("unsupported ring version"));
+        }
+        let capacity = u64::from_le_bytes(map[16..24].try_into().unwrap());
+        if !capacity.is_power_of_two() || map.len() < HEADER_SIZE + capacity as usize * SLOT_SIZE {
+            return Err(invalid("truncated ring"));
+        }
+        let mut reader = Self {
+            map,
+            capacity,
+            next: 0,
+            overruns: 0,
+            lost: 0,
+            buf: Box::new([0; MAX_PAYLOAD]),
+        };
+        reader.next = reader.written();
+        Ok(reader)
+    }
+
+    /// shreds written by the writer so far.
+    pub fn written(&self) -> u64 {
+        atomic_at(self.map.as_ptr(), WRITTEN_OFFSET).load(Ordering::Acquire)
+    }
+
+    /// shreds written but not read yet.
+    pub fn lag(&self) -> u64 {
+        self.written().saturating_sub(self.next)
+    }
+
+    /// times the reader was lapped and the shreds it lost.
+    pub fn overruns(&self) -> (u64, u64) {
+        (self.overruns, self.lost)
+    }
+
+    /// the next shred if there is one, never blocks.
+    pub fn poll(&mut self) -> Poll<'_> {
+        let written = self.written();
+        if written < self.next {
+            // the writer restarted and reset the ring
+            self.next = written;
+        }
+        if written == self.next {
+            return Poll::Empty;
+        }
+        if written - self.next > self.capacity {
+            return self.skip_to(written - self.capacity);
+        }
+
+        let seq = self.next;
+        let offset = HEADER_SIZE + (seq & (self.capacity - 1)) as usize * SLOT_SIZE;
+        let base = self.map.as_ptr();
+        let stamp = atomic_at(base, offset + STAMP);
+        let before = stamp.load(Ordering::Acquire);
+        if before != 2 * seq + 2 {
+            // being written, or already overwritten by a later lap
+            if before < 2 * seq + 2 {
+                return Poll::Empty;
+            }
+            return self.skip_to(self.written().saturating_sub(self.capacity).max(seq + 1));
+        }
+
+        let mut meta = [0; KIND + 1];
+        load_words(base, offset + RECEIVED_AT, &mut meta[RECEIVED_AT..]);
+        let u64_at = |at: usize| u64::from_le_bytes(meta[at..at + 8].try_into().unwrap());
+        let len = (u16::from_le_bytes([meta[LEN], meta[LEN + 1]]) as usize).min(MAX_PAYLOAD);
+        let (received_at_ns, written_at_ns, slot) = (u64_at(RECEIVED_AT), u64_at(WRITTEN_AT), u64_at(SLOT));
+        let index = u32::from_le_bytes(meta[INDEX..INDEX + 4].try_into().unwrap());
+        let (source, kind) = (meta[SOURCE], meta[KIND]);
+        load_words(base, offset + SLOT_META, &mut self.buf[..len]);
+        fence(Ordering::Acquire);
+        if stamp.load(Ordering::Relaxed) != before {
+            // overwritten while copying
+            return self.skip_to(self.written().saturating_sub(self.capacity).max(seq + 1));
+        }
+
+        self.next = seq + 1;
+        Poll::Shred(RingShred {
+            seq,
+            source: source_from_code(source),
+            kind: match kind {
+                1 => RingShredKind::Data,
+                2 => RingShredKind::Code,
+                _ => RingShredKind::Unknown,
+            },
+            slot,
+            index,
+            received_at_ns,
+            written_at_ns,
+            payload: &self.buf[..len],
+        })
+    }
+
+    fn skip_to(&mut self, seq: u64) -> Poll<'_> {
+        let lost = seq - self.next;
+        self.next = seq;
+        self.overruns += 1;
+        self.lost += lost;
+        Poll::Overrun { lost }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, thread, time::Duration};
+
+    use super::*;
+
+    /// a ring file per test, removed when the test ends.
+    struct RingFile(PathBuf);
+
+    impl RingFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("shred-zdn-{}-{}.ring", name, std::process::id())))
+        }
+    }
+
+    impl Drop for RingFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// a merkle data shred whose signature holds `seq` and whose remaining bytes are all `seq as u8`.
+    fn shred(seq: u64) -> Vec<u8> {
+        let mut shred = vec![seq as u8; 1203];
+        shred[..8].copy_from_slice(&seq.to_le_bytes());
+        shred[64] = 0x86;
+        shred[65..73].copy_from_slice(&seq.to_le_bytes());
+        shred[73..77].copy_from_slice(&(seq as u32).to_le_bytes());
+        shred
+    }
+
+    fn received_at(seq: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_nanos(seq)
+    }
+
+    /// the shred read, or a panic on anything else.
+    fn next_shred(reader: &mut ShmRingReader) -> (u64, Vec<u8>) {
+        match reader.poll() {
+            Poll::Shred(shred) => {
+                assert_eq!((shred.slot, shred.index as u64), (shred.seq, shred.seq));
+                assert_eq!(shred.received_at_ns, epoch_ns(received_at(shred.seq)));
+                (shred.seq, shred.payload.to_vec())
+            }
+            other => panic!("expected a shred, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn shreds_round_trip() {
+        let file = RingFile::new("round-trip");
+        let mut writer = ShmRingWriter::create(&file.0, 8).unwrap();
+        let mut reader = ShmRingReader::open(&file.0).unwrap();
+        assert!(matches!(reader.poll(), Poll::Empty));
+
+        assert!(writer.push(Source::Reference, received_at(0), &shred(0)));
+        assert!(writer.push(Source::Zdn, received_at(1), &[7; 13]));
+        assert!(!writer.push(Source::Zdn, received_at(2), &[0; MAX_PAYLOAD + 1]));
+        assert_eq!((writer.written(), reader.lag()), (2, 2));
+
+        let Poll::Shred(first) = reader.poll() else { panic!("first shred missing") };
+        assert_eq!((first.seq, first.source, first.kind), (0, Some(Source::Reference), RingShredKind::Data));
+        assert_eq!((first.slot, first.index, first.received_at_ns), (0, 0, epoch_ns(received_at(0))));
+        assert!(first.written_at_ns > first.received_at_ns);
+        assert_eq!(first.payload, shred(0));
+
+        let Poll::Shred(second) = reader.poll() else { panic!("second shred missing") };
+        assert_eq!((second.seq, second.source, second.kind), (1, Some(Source::Zdn), RingShredKind::Unknown));
+        assert_eq!(second.payload, [7; 13]);
+
+        assert!(matches!(reader.poll(), Poll::Empty));
+        assert_eq!(reader.lag(), 0);
+    }
+
+    #[test]
+    fn a_lapped_reader_skips_to_the_oldest_shred() {
+        let file = RingFile::new("overrun");
+        let mut writer = ShmRingWriter::create(&file.0, 4).unwrap();
+        let mut reader = ShmRingReader::open(&file.0).unwrap();
+        for seq in 0..10 {
+            writer.push(Source::Zdn, received_at(seq), &shred(seq));
+        }
+        assert_eq!(reader.lag(), 10);
+
+        assert!(matches!(reader.poll(), Poll::Overrun { lost: 6 }));
+        for seq in 6..10 {
+            assert_eq!(next_shred(&mut reader), (seq, shred(seq)));
+        }
+        assert!(matches!(reader.poll(), Poll::Empty));
+        assert_eq!(reader.overruns(), (1, 6));
+    }
+
+    #[test]
+    fn a_reader_follows_a_writer_reset() {
+        let file = RingFile::new("reset");
+        let mut writer = ShmRingWriter::create(&file.0, 8).unwrap();
+        let mut reader = ShmRingReader::open(&file.0).unwrap();
+        for seq in 0..3 {
+            writer.push(Source::Zdn, received_at(seq), &shred(seq));
+            next_shred(&mut reader);
+        }
+
+        drop(writer);
+        let mut writer = ShmRingWriter::create(&file.0, 8).unwrap();
+        assert!(matches!(reader.poll(), Poll::Empty));
+        writer.push(Source::Zdn, received_at(0), &shred(0));
+        assert_eq!(next_shred(&mut reader), (0, shred(0)));
+        assert_eq!(reader.overruns(), (0, 0));
+    }
+
+    #[test]
+    fn a_capacity_change_replaces_the_file() {
+        let file = RingFile::new("capacity");
+        let mut writer = ShmRingWriter::create(&file.0, 8).unwrap();
+        let mut reader = ShmRingReader::open(&file.0).unwrap();
+        writer.push(Source::Zdn, received_at(0), &shred(0));
+        drop(writer);
+
+        // the old reader keeps the old file mapped instead of faulting on a shorter one
+        let mut writer = ShmRingWriter::create(&file.0, 4).unwrap();
+        writer.push(Source::Zdn, received_at(1), &shred(1));
+        assert_eq!(next_shred(&mut reader), (0, shred(0)));
+        assert!(matches!(reader.poll(), Poll::Empty));
+
+        let reopened = ShmRingReader::open(&file.0).unwrap();
+        assert_eq!((reopened.capacity, reopened.written()), (4, 1));
+    }
+
+    #[test]
+    fn a_racing_writer_never_hands_out_a_torn_shred() {
+        const SHREDS: u64 = 50_000;
+        let file = RingFile::new("stress");
+        let mut writer = ShmRingWriter::create(&file.0, 16).unwrap();
+        let mut reader = ShmRingReader::open(&file.0).unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for seq in 0..SHREDS {
+                    writer.push(Source::Zdn, received_at(seq), &shred(seq));
+                }
+            });
+
+            let (mut read, mut last) = (0, None);
+            while last != Some(SHREDS - 1) {
+                match reader.poll() {
+                    Poll::Shred(shred) => {
+                        let seq = u64::from_le_bytes(shred.payload[..8].try_into().unwrap());
+                        assert_eq!((seq, shred.slot), (shred.seq, shred.seq));
+                        assert!(shred.payload[77..].iter().all(|byte| *byte == seq as u8), "shred {} torn", seq);
+                        assert!(last.is_none_or(|last| seq > last));
+                        last = Some(seq);
+                        read += 1;
+                    }
+                    Poll::Overrun { .. } | Poll::Empty => std::hint::spin_loop(),
+                }
+            }
+            assert_eq!(read + reader.overruns().1, SHREDS);
+        });
+    }
+}
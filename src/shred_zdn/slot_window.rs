@@ -0,0 +1,242 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::{interval, MissedTickBehavior},
+};
+
+/// a slot without new shreds for this long is finished.
+const SLOT_IDLE: Duration = Duration::from_secs(3);
+/// finished slots remembered so late shreds do not open them again.
+const FINISHED_SLOTS: usize = 1024;
+
+/// folds shred arrivals into per-slot state and handles each slot once it is finished,
+/// run by `start_slot_aggregator` on its own task.
+pub trait SlotAggregator: Send + 'static {
+    type Arrival: Send + 'static;
+    type Slot: Send;
+
+    fn slot_of(arrival: &Self::Arrival) -> u64;
+
+    /// the state of a slot, on its first arrival.
+    fn open(&mut self, arrival: &Self::Arrival, now: Instant) -> Self::Slot;
+
+    fn record(&mut self, slot: &mut Self::Slot, arrival: Self::Arrival, now: Instant);
+
+    /// the finished slots, in slot order.
+    fn finish(&mut self, slots: Vec<(u64, Self::Slot)>);
+}
+
+struct OpenSlot<S> {
+    state: S,
+    last_seen: Instant,
+}
+
+/// the open slots of an aggregator and the recently finished ones.
+struct SlotWindow<S> {
+    open: HashMap<u64, OpenSlot<S>>,
+    finished: BTreeSet<u64>,
+}
+
+impl<S> SlotWindow<S> {
+    fn new() -> Self {
+        Self { open: HashMap::new(), finished: BTreeSet::new() }
+    }
+
+    /// arrivals of a finished slot are dropped.
+    fn record_at<G: SlotAggregator<Slot = S>>(&mut self, aggregator: &mut G, arrival: G::Arrival, now: Instant) {
+        let slot = G::slot_of(&arrival);
+        if self.finished.contains(&slot) {
+            return;
+        }
+        let open = self
+            .open
+            .entry(slot)
+            .or_insert_with(|| OpenSlot { state: aggregator.open(&arrival, now), last_seen: now });
+        open.last_seen = now;
+        aggregator.record(&mut open.state, arrival, now);
+    }
+
+    /// the slots without arrivals for `SLOT_IDLE`.
+    fn take_idle_at(&mut self, now: Instant) -> Vec<(u64, S)> {
+        let idle = self
+            .open
+            .iter()
+            .filter(|(_, open)| now.saturating_duration_since(open.last_seen) >= SLOT_IDLE)
+            .map(|(slot, _)| *slot)
+            .collect();
+        self.take(idle)
+    }
+
+    fn take_all(&mut self) -> Vec<(u64, S)> {
+        let open = self.open.keys().copied().collect();
+        self.take(open)
+    }
+
+    fn take(&mut self, mut slots: Vec<u64>) -> Vec<(u64, S)> {
+        slots.sort_unstable();
+        slots
+            .into_iter()
+            .map(|slot| {
+                let open = self.open.remove(&slot).unwrap();
+                self.finished.insert(slot);
+                if self.finished.len() > FINISHED_SLOTS {
+                    self.finished.pop_first();
+                }
+                (slot, open.state)
+            })
+            .collect()
+    }
+}
+
+enum Message<A> {
+    Arrival(A),
+    /// finish every open slot, then answer.
+    Flush(oneshot::Sender<()>),
+}
+
+/// feeds arrivals to an aggregator task.
+pub struct SlotFeed<A> {
+    tx: UnboundedSender<Message<A>>,
+}
+
+impl<A> SlotFeed<A> {
+    pub fn send(&self, arrival: A) {
+        let _ = self.tx.send(Message::Arrival(arrival));
+    }
+
+    /// finish the slots still open, e.g. before the final report.
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.tx.send(Message::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+}
+
+pub fn start_slot_aggregator<G: SlotAggregator>(aggregator: G) -> SlotFeed<G::Arrival> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(run(rx, aggregator));
+    SlotFeed { tx }
+}
+
+async fn run<G: SlotAggregator>(mut rx: UnboundedReceiver<Message<G::Arrival>>, mut aggregator: G) {
+    let mut window = SlotWindow::new();
+    let mut tick = interval(Duration::from_secs(1));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Arrival(arrival)) => window.record_at(&mut aggregator, arrival, Instant::now()),
+                Some(Message::Flush(done)) => {
+                    aggregator.finish(window.take_all());
+                    let _ = done.send(());
+                }
+                None => break,
+            },
+            _ = tick.tick() => {
+                let idle = window.take_idle_at(Instant::now());
+                if !idle.is_empty() {
+                    aggregator.finish(idle);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// arrivals are `(slot, value)`, a slot sums its values.
+    #[derive(Default)]
+    struct Sum {
+        finished: Arc<Mutex<Vec<(u64, u32)>>>,
+    }
+
+    impl SlotAggregator for Sum {
+        type Arrival = (u64, u32);
+        type Slot = u32;
+
+        fn slot_of(arrival: &Self::Arrival) -> u64 {
+            arrival.0
+        }
+
+        fn open(&mut self, _: &Self::Arrival, _: Instant) -> u32 {
+            0
+        }
+
+        fn record(&mut self, slot: &mut u32, arrival: Self::Arrival, _: Instant) {
+            *slot += arrival.1;
+        }
+
+        fn finish(&mut self, slots: Vec<(u64, u32)>) {
+            self.finished.lock().unwrap().extend(slots);
+        }
+    }
+
+    #[test]
+    fn idle_slots_finish_in_slot_order() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let (mut sum, mut window) = (Sum::default(), SlotWindow::new());
+        window.record_at(&mut sum, (11, 1), at(0));
+        window.record_at(&mut sum, (10, 2), at(0));
+        window.record_at(&mut sum, (12, 3), at(0));
+        window.record_at(&mut sum, (11, 4), at(2_000));
+
+        assert!(window.take_idle_at(at(2_999)).is_empty());
+        assert_eq!(window.take_idle_at(at(3_000)), [(10, 2), (12, 3)]);
+        assert_eq!(window.take_idle_at(at(5_000)), [(11, 5)]);
+        assert!(window.open.is_empty());
+    }
+
+    #[test]
+    fn finished_slots_stay_closed() {
+        let now = Instant::now();
+        let (mut sum, mut window) = (Sum::default(), SlotWindow::new());
+        window.record_at(&mut sum, (10, 1), now);
+        assert_eq!(window.take_all(), [(10, 1)]);
+
+        // a late shred does not reopen the slot
+        window.record_at(&mut sum, (10, 1), now);
+        assert!(window.take_all().is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_finished_slots_are_remembered() {
+        let now = Instant::now();
+        let (mut sum, mut window) = (Sum::default(), SlotWindow::new());
+        for slot in 0..FINISHED_SLOTS as u64 + 1 {
+            window.record_at(&mut sum, (slot, 1), now);
+        }
+        assert_eq!(window.take_all().len(), FINISHED_SLOTS + 1);
+        assert_eq!(window.finished.first(), Some(&1));
+
+        // the oldest one was forgotten
+        window.record_at(&mut sum, (0, 1), now);
+        window.record_at(&mut sum, (1, 1), now);
+        assert_eq!(window.take_all(), [(0, 1)]);
+    }
+
+    #[tokio::test]
+    async fn flush_finishes_open_slots() {
+        let sum = Sum::default();
+        let finished = Arc::clone(&sum.finished);
+        let feed = start_slot_aggregator(sum);
+        feed.send((7, 1));
+        feed.send((7, 2));
+        feed.send((5, 1));
+        feed.flush().await;
+        assert_eq!(*finished.lock().unwrap(), [(5, 1), (7, 3)]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+
+use enum_map::EnumMap;
+use log::debug;
+
+use crate::shred_zdn::{
+    args::Source,
+    receiver::{shred_header, ShredHeader, ShredKind},
+    slot_window::{start_slot_aggregator, SlotAggregator, SlotFeed},
+    stats::Stats,
+};
+
+/// what one source delivered of a slot.
+#[derive(Default)]
+struct SourceSlot {
+    data: HashSet<u32>,
+    coding: HashSet<u32>,
+    /// data and coding shreds received per FEC set.
+    per_fec_set: HashMap<u32, usize>,
+    /// arrival of the latest new data shred.
+    last_data_at: Option<SystemTime>,
+    /// `last_data_at` once every data shred arrived.
+    completed_at: Option<SystemTime>,
+}
+
+/// one slot as seen by every source, the slot's shape is learned from whichever source had it.
+struct SlotState {
+    first_at: SystemTime,
+    /// when the tracker saw the slot's first shred, sources active since then count for the slot.
+    opened: Instant,
+    /// index of the data shred flagged `LAST_SHRED_IN_SLOT`.
+    last_index: Option<u32>,
+    /// data shreds per FEC set, from the coding headers.
+    fec_sets: HashMap<u32, u16>,
+    sources: EnumMap<Source, Option<SourceSlot>>,
+}
+
+impl SlotState {
+    fn new(at: SystemTime, opened: Instant) -> Self {
+        Self {
+            first_at: at,
+            opened,
+            last_index: None,
+            fec_sets: HashMap::new(),
+            sources: EnumMap::default(),
+        }
+    }
+
+    fn record(&mut self, source: Source, header: &ShredHeader, at: SystemTime) {
+        self.first_at = self.first_at.min(at);
+        let seen = self.sources[source].get_or_insert_with(SourceSlot::default);
+        let new = match header.kind {
+            ShredKind::Data { last_in_slot } => {
+                if last_in_slot {
+                    self.last_index = Some(header.index);
+                }
+                let new = seen.data.insert(header.index);
+                if new {
+                    seen.last_data_at = Some(seen.last_data_at.map_or(at, |last| last.max(at)));
+                }
+                new
+            }
+            ShredKind::Code { num_data, .. } => {
+                self.fec_sets.insert(header.fec_set_index, num_data);
+                seen.coding.insert(header.index)
+            }
+        };
+        if new {
+            *seen.per_fec_set.entry(header.fec_set_index).or_default() += 1;
+        }
+
+        // the last shred can come from another source, so every source is checked,
+        // each completes when its own last missing data shred arrived
+        let Some(last_index) = self.last_index else { return };
+        for seen in self.sources.values_mut().flatten() {
+            if seen.completed_at.is_none() && seen.data.len() == last_index as usize + 1 {
+                seen.completed_at = seen.last_data_at;
+            }
+        }
+    }
+
+    /// since the slot's first shred from any source, once the source had every data shred.
+    fn completed_after(&self, seen: &SourceSlot) -> Option<Duration> {
+        Some(seen.completed_at?.duration_since(self.first_at).unwrap_or_default())
+    }
+
+    /// data shreds in the slot, a lower bound when no source got the last one.
+    fn expected(&self) -> usize {
+        match self.last_index {
+            Some(last_index) => last_index as usize + 1,
+            None => self
+                .sources
+                .values()
+                .flatten()
+                .filter_map(|seen| seen.data.iter().max())
+                .max()
+                .map_or(0, |max| *max as usize + 1),
+        }
+    }
+
+    /// FEC set of a data shred index, if a coding shred of that set was seen.
+    fn fec_set_of(&self, index: u32) -> Option<(u32, u16)> {
+        self.fec_sets
+            .iter()
+            .find(|(start, num_data)| (**start..**start + **num_data as u32).contains(&index))
+            .map(|(start, num_data)| (*start, *num_data))
+    }
+}
+
+/// per-source slot completeness and data shred loss, counted in `Stats::slots`.
+pub struct SlotTracker {
+    feed: SlotFeed<(Source, ShredHeader, SystemTime)>,
+}
+
+impl SlotTracker {
+    /// note the arrival of `shred` from `source`, packets that are not merkle shreds are ignored.
+    pub fn record(&self, source: Source, shred: &[u8], at: SystemTime) {
+        if let Some(header) = shred_header(shred) {
+            self.feed.send((source, header, at));
+        }
+    }
+
+    /// count the slots still open, e.g. before the final stats.
+    pub async fn flush(&self) {
+        self.feed.flush().await;
+    }
+}
+
+pub fn start_slot_tracker(stats: &Arc<Stats>) -> Arc<SlotTracker> {
+    let feed = start_slot_aggregator(SlotCounter { stats: Arc::clone(stats), last_arrival: EnumMap::default() });
+    Arc::new(SlotTracker { feed })
+}
+
+/// adds the finished slots to `Stats::slots`.
+struct SlotCounter {
+    stats: Arc<Stats>,
+    /// latest arrival of any shred per source.
+    last_arrival: EnumMap<Source, Option<Instant>>,
+}
+
+impl SlotAggregator for SlotCounter {
+    type Arrival = (Source, ShredHeader, SystemTime);
+    type Slot = SlotState;
+
+    fn slot_of((_, header, _): &Self::Arrival) -> u64 {
+        header.slot
+    }
+
+    fn open(&mut self, (_, _, at): &Self::Arrival, now: Instant) -> SlotState {
+        SlotState::new(*at, now)
+    }
+
+    fn record(&mut self, state: &mut SlotState, (source, header, at): Self::Arrival, now: Instant) {
+        self.last_arrival[source] = Some(now);
+        state.record(source, &header, at);
+    }
+
+    fn finish(&mut self, slots: Vec<(u64, SlotState)>) {
+        for (slot, state) in slots {
+            let active = EnumMap::from_fn(|source| self.last_arrival[source].is_some_and(|last| last >= state.opened));
+            count(&self.stats, slot, &state, &active);
+        }
+    }
+}
+
+/// add a finished slot to the counters of every source that delivered any of it or was `active`
+/// while it was open, a source that delivered nothing of it missed all of it.
+fn count(stats: &Stats, slot: u64, state: &SlotState, active: &EnumMap<Source, bool>) {
+    let expected = state.expected();
+    let nothing = SourceSlot::default();
+    for (source, seen) in &state.sources {
+        let seen = match seen {
+            Some(seen) => seen,
+            None if active[source] => &nothing,
+            None => continue,
+        };
+        let received = seen.data.len().min(expected);
+        let missing = (0..expected as u32).filter(|index| !seen.data.contains(index)).collect::<Vec<_>>();
+        // erasure coding restores a FEC set once as many of its shreds arrived as it has data shreds
+        let recoverable = missing
+            .iter()
+            .filter(|index| match state.fec_set_of(**index) {
+                Some((start, num_data)) => seen.per_fec_set.get(&start).copied().unwrap_or(0) >= num_data as usize,
+                None => false,
+            })
+            .count();
+
+        let counters = &stats.slots;
+        counters.slots[source].fetch_add(1, Ordering::Relaxed);
+        counters.expected[source].fetch_add(expected, Ordering::Relaxed);
+        counters.received[source].fetch_add(received, Ordering::Relaxed);
+        counters.missing[source].fetch_add(missing.len(), Ordering::Relaxed);
+        counters.recoverable[source].fetch_add(recoverable, Ordering::Relaxed);
+        let completed_after = state.completed_after(seen);
+        if let Some(after) = completed_after {
+            counters.completed[source].fetch_add(1, Ordering::Relaxed);
+            counters.completion_us[source].fetch_add(after.as_micros() as usize, Ordering::Relaxed);
+        }
+        debug!(
+            "slot {} {}: {}/{} data shreds, missing {}, recoverable {}, {}",
+            slot,
+            source.as_str(),
+            received,
+            expected,
+            missing.len(),
+            recoverable,
+            match completed_after {
+                Some(after) => format!("completed after {:.1}ms", after.as_secs_f64() * 1000.0),
+                None if state.last_index.is_none() => "last shred never seen".to_string(),
+                None => "never completed".to_string(),
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis(ms)
+    }
+
+    fn data(index: u32, fec_set_index: u32, last_in_slot: bool) -> ShredHeader {
+        ShredHeader { slot: 1, index, fec_set_index, kind: ShredKind::Data { last_in_slot } }
+    }
+
+    fn code(index: u32, fec_set_index: u32, num_data: u16, num_coding: u16, position: u16) -> ShredHeader {
+        ShredHeader { slot: 1, index, fec_set_index, kind: ShredKind::Code { num_data, num_coding, position } }
+    }
+
+    fn counted(stats: &Stats, source: Source) -> [usize; 7] {
+        let counters = &stats.slots;
+        [
+            &counters.slots,
+            &counters.completed,
+            &counters.completion_us,
+            &counters.expected,
+            &counters.received,
+            &counters.missing,
+            &counters.recoverable,
+        ]
+        .map(|counter| counter[source].load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn expected_is_a_lower_bound_until_the_last_shred() {
+        let mut state = SlotState::new(at(0), Instant::now());
+        assert_eq!(state.expected(), 0);
+        state.record(Source::Zdn, &data(4, 0, false), at(0));
+        state.record(Source::Reference, &data(6, 0, false), at(0));
+        assert_eq!(state.expected(), 7);
+        state.record(Source::Zdn, &data(9, 8, true), at(0));
+        assert_eq!(state.expected(), 10);
+    }
+
+    #[test]
+    fn fec_sets_are_learned_from_coding_shreds() {
+        let mut state = SlotState::new(at(0), Instant::now());
+        state.record(Source::Zdn, &code(0, 0, 32, 32, 0), at(0));
+        state.record(Source::Reference, &code(40, 32, 8, 8, 3), at(0));
+        assert_eq!(state.fec_set_of(0), Some((0, 32)));
+        assert_eq!(state.fec_set_of(31), Some((0, 32)));
+        assert_eq!(state.fec_set_of(32), Some((32, 8)));
+        assert_eq!(state.fec_set_of(39), Some((32, 8)));
+        assert_eq!(state.fec_set_of(40), None);
+    }
+
+    #[test]
+    fn missing_shreds_are_recoverable_with_enough_coding_shreds() {
+        let mut state = SlotState::new(at(0), Instant::now());
+        // FEC set 0 has data 0..4, zdn misses 1 and 2 but has two coding shreds
+        for index in [0, 3] {
+            state.record(Source::Zdn, &data(index, 0, false), at(0));
+        }
+        for position in 0..2 {
+            state.record(Source::Zdn, &code(position as u32, 0, 4, 4, position), at(0));
+        }
+        // FEC set 4 has data 4..8, zdn misses 5 and 6 with one coding shred
+        for index in [4, 7] {
+            state.record(Source::Zdn, &data(index, 4, index == 7), at(0));
+        }
+        state.record(Source::Zdn, &code(4, 4, 4, 4, 0), at(0));
+
+        let stats = Stats::new();
+        count(&stats, 1, &state, &EnumMap::default());
+        // slots, completed, completion, expected, received, missing, recoverable
+        assert_eq!(counted(&stats, Source::Zdn), [1, 0, 0, 8, 4, 4, 2]);
+    }
+
+    #[test]
+    fn completion_is_timed_by_the_source_own_shreds() {
+        let mut state = SlotState::new(at(0), Instant::now());
+        state.record(Source::Reference, &data(0, 0, false), at(0));
+        state.record(Source::Zdn, &data(0, 0, false), at(2));
+        state.record(Source::Zdn, &data(1, 0, false), at(5));
+        // the last shred from reference completes zdn, which already had every data shred
+        state.record(Source::Reference, &data(1, 0, true), at(30));
+
+        let stats = Stats::new();
+        count(&stats, 1, &state, &EnumMap::default());
+        assert_eq!(counted(&stats, Source::Zdn), [1, 1, 5_000, 2, 2, 0, 0]);
+        assert_eq!(counted(&stats, Source::Reference), [1, 1, 30_000, 2, 2, 0, 0]);
+    }
+
+    #[test]
+    fn an_active_source_without_shreds_missed_the_slot() {
+        let mut state = SlotState::new(at(0), Instant::now());
+        state.record(Source::Zdn, &data(0, 0, false), at(0));
+        state.record(Source::Zdn, &data(1, 0, true), at(1));
+
+        let stats = Stats::new();
+        let active = EnumMap::from_fn(|source| source == Source::Reference);
+        count(&stats, 1, &state, &active);
+        assert_eq!(counted(&stats, Source::Zdn), [1, 1, 1_000, 2, 2, 0, 0]);
+        assert_eq!(counted(&stats, Source::Reference), [1, 0, 0, 2, 0, 2, 0]);
+        assert_eq!(counted(&stats, Source::LocalTurbine), [0; 7]);
+    }
+
+    #[test]
+    fn sources_count_once_active_while_the_slot_was_open() {
+        let mut counter = SlotCounter { stats: Arc::new(Stats::new()), last_arrival: EnumMap::default() };
+        let start = Instant::now();
+        let arrival = |source, index| (source, data(index, 0, index == 1), at(0));
+        // turbine was last heard of before the slot opened
+        counter.last_arrival[Source::LocalTurbine] = Some(start);
+        let opened = start + Duration::from_millis(10);
+        let mut state = counter.open(&arrival(Source::Zdn, 0), opened);
+        counter.record(&mut state, arrival(Source::Zdn, 0), opened);
+        counter.record(&mut state, arrival(Source::Zdn, 1), opened);
+        // reference only delivered shreds of another slot meanwhile
+        counter.last_arrival[Source::Reference] = Some(opened + Duration::from_millis(5));
+        counter.finish(vec![(1, state)]);
+
+        let stats = &counter.stats;
+        assert_eq!(counted(stats, Source::Zdn)[..2], [1, 1]);
+        assert_eq!(counted(stats, Source::Reference)[..6], [1, 0, 0, 2, 0, 2]);
+        assert_eq!(counted(stats, Source::LocalTurbine), [0; 7]);
+    }
+}
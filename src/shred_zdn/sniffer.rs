@@ -1,8 +1,9 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    path::Path,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{Duration, Instant, SystemTime},
 };
 
 use ahash::RandomState;
@@ -13,141 +14,362 @@ use solana_ledger::shred::Shred;
 use tokio::{
     net::UdpSocket,
     sync::mpsc::{self, UnboundedReceiver},
+    task::{JoinHandle, JoinSet},
     time::{interval, MissedTickBehavior},
 };
 
-use crate::shred_zdn::{args::Source};
+use crate::common::utils::diff_time;
+use crate::shred_zdn::{
+    args::{Protocol, SniffSpec, Source},
+    entry_stream::EntryStream,
+    race::RaceAnalyzer,
+    receiver::dedup_slice,
+    send_back::SendBack,
+    slots::SlotTracker,
+    stats::{CaptureStats, Stage, Stats},
+};
+
+/// first sighting of a shred inside the sniffer's dedup window.
+#[derive(Clone, Copy)]
+enum Seen {
+    Zdn(SystemTime),
+    Turbine(SystemTime),
+    Both,
+}
+
+/// an opened capture with the offset of the transport payload in each packet.
+pub struct SnifferCapture {
+    pub spec: SniffSpec,
+    pub cap: Capture<pcap::Active>,
+    pub payload_offset: usize,
+    /// the header timestamps carry nanoseconds instead of microseconds.
+    pub nano_ts: bool,
+}
+
+/// open a non-blocking capture on the spec's interface with the generated BPF filter applied.
+pub fn open_capture(spec: &SniffSpec, protocol: &Protocol) -> Result<SnifferCapture, pcap::Error> {
+    let mut cap = Capture::from_device(spec.interface.as_str())?
+        .promisc(true)
+        .immediate_mode(true)
+        // same resolution as the receivers' kernel timestamps where libpcap supports it
+        .precision(pcap::Precision::Nano)
+        .snaplen(65535)
+        .open()?
+        .setnonblock()?;
+
+    let header_len = link_header_len(cap.get_datalink(), &spec.interface);
+    let filter = spec.bpf_filter(protocol);
+    cap.filter(&filter, true)?;
+    info!("capture on {} with filter `{}`", spec.interface, filter);
+
+    let nano_ts = has_nano_ts(&cap);
+    if !nano_ts {
+        warn!("no nanosecond timestamps on {}, capture times have microsecond resolution", spec.interface);
+    }
+    Ok(SnifferCapture {
+        spec: spec.clone(),
+        cap,
+        payload_offset: header_len + 20 + 8,
+        nano_ts,
+    })
+}
+
+unsafe extern "C" {
+    /// libpcap >= 1.5, which `Capture::precision` needs as well; the crate does not expose it.
+    fn pcap_get_tstamp_precision(p: *mut libc::c_void) -> libc::c_int;
+}
+
+const PCAP_TSTAMP_PRECISION_NANO: libc::c_int = 1;
+
+/// whether the open capture's header timestamps carry nanoseconds, the precision setter is not
+/// checked and libpcap stays at microseconds where the platform has no nanosecond stamps.
+fn has_nano_ts(cap: &Capture<pcap::Active>) -> bool {
+    unsafe { pcap_get_tstamp_precision(cap.as_ptr().cast()) == PCAP_TSTAMP_PRECISION_NANO }
+}
+
+/// a pcap file opened for reading, e.g. a `tcpdump -w` recording of the forwarded shreds.
+pub struct Recording {
+    pub cap: Capture<pcap::Offline>,
+    pub payload_offset: usize,
+    pub nano_ts: bool,
+}
+
+/// open a recording with an optional BPF filter, packets are read in recorded order.
+pub fn open_recording(path: &Path, filter: Option<&str>) -> Result<Recording, pcap::Error> {
+    let mut cap = Capture::from_file_with_precision(path, pcap::Precision::Nano)?;
+    let header_len = link_header_len(cap.get_datalink(), &path.display().to_string());
+    if let Some(filter) = filter {
+        cap.filter(filter, true)?;
+    }
+    Ok(Recording {
+        cap,
+        payload_offset: header_len + 20 + 8,
+        // libpcap scales microsecond files up to the requested precision
+        nano_ts: true,
+    })
+}
+
+/// header length of different links：Ethernet=14，Loopback=NULL=4，Linux cooked=16
+fn link_header_len(link: pcap::Linktype, name: &str) -> usize {
+    match link.0 {
+        1 => 14,
+        0 => 4,
+        113 => 16,
+        other => {
+            warn!("Unknown link type {} on {}, default to 4-byte", other, name);
+            4
+        }
+    }
+}
+
+/// capture time of a packet, the same clock as the receivers' kernel timestamps.
+pub fn packet_time(header: &pcap::PacketHeader, nano_ts: bool) -> SystemTime {
+    let ts = header.ts;
+    let subsec_ns = if nano_ts { ts.tv_usec as u32 } else { ts.tv_usec as u32 * 1000 };
+    SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, subsec_ns)
+}
+
+/// the running sniffer, `stop` ends the capture threads and `join` waits for them, for the
+/// captured and ZDN shreds already queued and for their send-backs.
+pub struct Sniffer {
+    stop: Arc<AtomicBool>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    task: JoinHandle<()>,
+}
+
+impl Sniffer {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// after `stop`, wait until the capture threads ended and the queued shreds are processed and
+    /// sent back.
+    /// the threads are polled instead of joined on a blocking thread, so a drain timeout applies.
+    pub async fn join(self) {
+        for thread in self.threads {
+            while !thread.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            if thread.join().is_err() {
+                error!("a pcap thread panicked");
+            }
+        }
+        let _ = self.task.await;
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_sniffer(
-    cap: Capture<pcap::Active>,
-    payload_offset: usize,
+    captures: Vec<SnifferCapture>,
+    stats: &Arc<Stats>,
     socket: Arc<UdpSocket>,
-    addrs: Arc<Vec<SocketAddr>>,
-    mut zdn_receiver: UnboundedReceiver<Vec<u8>>,
-) {
-    let mut current = HashMap::<u64, Source, BuildNoHashHasher<u64>>::default();
-    let mut preparing = HashMap::<u64, Source, BuildNoHashHasher<u64>>::default();
+    send_back: &Arc<SendBack>,
+    mut zdn_receiver: UnboundedReceiver<(Vec<u8>, SystemTime)>,
+    slots: Arc<SlotTracker>,
+    race: Option<Arc<RaceAnalyzer>>,
+    entries: Option<Arc<EntryStream>>,
+    dedup_rotation: Duration,
+) -> Sniffer {
+    let mut current = HashMap::<u64, Seen, BuildNoHashHasher<u64>>::default();
+    let mut preparing = HashMap::<u64, Seen, BuildNoHashHasher<u64>>::default();
+    let stats = Arc::clone(stats);
+    let send_back = Arc::clone(send_back);
     let mut total_send_back_count = 0;
     let state = RandomState::new();
+    let stop = Arc::new(AtomicBool::new(false));
 
     info!("✅ Packet listener started.");
-    info!(
-        "Send back to: {}",
-        addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")
-    );
-
-    // channel for pcap
-    let (pcap_tx, mut pcap_rx) = mpsc::unbounded_channel::<Vec<u8>>();
-
-    // pcap thread
-    std::thread::spawn(move || {
-        let mut cap = cap;        
-        info!("sniffer thread started");
-        
-        loop {
-            match cap.next_packet() {
-                Ok(packet) => {
-                    // send to forwarder
-                    if pcap_tx.send(packet.data.to_vec()).is_err() {
-                        info!("Pcap channel closed, stopping pcap thread");
-                        break;
+
+    // channel for pcap, shared by all capture threads so they merge into one dedup stage
+    let (pcap_tx, mut pcap_rx) = mpsc::unbounded_channel::<(Vec<u8>, SystemTime)>();
+    let mut capture_stats = Vec::with_capacity(captures.len());
+    let mut threads = Vec::with_capacity(captures.len());
+
+    // one pcap thread per capture
+    for SnifferCapture { spec, cap, payload_offset, nano_ts } in captures {
+        let pcap_tx = pcap_tx.clone();
+        let stats = Arc::clone(&stats);
+        let stop = Arc::clone(&stop);
+        let cap_stats = Arc::new(CaptureStats::new(&spec.interface));
+        capture_stats.push(Arc::clone(&cap_stats));
+        stats.captures.write().unwrap().push(Arc::clone(&cap_stats));
+
+        let thread = std::thread::Builder::new()
+            .name(format!("sniffer-{}", spec.interface))
+            .spawn(move || {
+                let mut cap = cap;
+                let mut last_stat = Instant::now();
+                info!("sniffer thread started on {}", spec.interface);
+
+                while !stop.load(Ordering::Relaxed) {
+                    // kernel counters are refreshed once a second
+                    if last_stat.elapsed() >= Duration::from_secs(1) {
+                        last_stat = Instant::now();
+                        match cap.stats() {
+                            Ok(stat) => cap_stats.update(&stat),
+                            Err(e) => warn!("Failed to read capture stats on {}: {:?}", spec.interface, e),
+                        }
+                    }
+                    match cap.next_packet() {
+                        Ok(packet) => {
+                            // invalid packet
+                            if packet.data.len() <= payload_offset {
+                                continue;
+                            }
+                            cap_stats.captured.fetch_add(1, Ordering::Relaxed);
+                            // kernel receive time, the clock of the receivers' timestamps
+                            let captured_at = packet_time(packet.header, nano_ts);
+                            if let Ok(elapsed) = SystemTime::now().duration_since(captured_at) {
+                                stats.latency.record(Stage::KernelToSniffer, elapsed);
+                            }
+                            // send to forwarder
+                            if pcap_tx.send((packet.data[payload_offset..].to_vec(), captured_at)).is_err() {
+                                info!("Pcap channel closed, stopping pcap thread on {}", spec.interface);
+                                break;
+                            }
+                        }
+                        Err(pcap::Error::NoMorePackets) | Err(pcap::Error::TimeoutExpired) => {
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("❌ Packet listener error on {}: {:?}", spec.interface, e);
+                            break;
+                        }
                     }
                 }
-                Err(pcap::Error::NoMorePackets) | Err(pcap::Error::TimeoutExpired) => {
-                    continue;
-                }
-                Err(e) => {
-                    error!("❌ Packet listener error: {:?}", e);
-                    break;
-                }
-            }
-        }
-        info!("🛑 Pcap thread terminated");
-    });
+                info!("🛑 Pcap thread terminated on {}", spec.interface);
+            })
+            .expect("Failed to spawn sniffer thread");
+        threads.push(thread);
+    }
+    drop(pcap_tx);
     
-    let mut interval_timer = interval(Duration::from_secs(15));
+    let mut interval_timer = interval(dedup_rotation);
     interval_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
     let mut stats_timer = interval(Duration::from_secs(60));
     stats_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
     
-    tokio::spawn(async move {
-        loop {
+    let task = tokio::spawn(async move {
+        // send-back tasks in flight, joined before the task ends
+        let mut sending = JoinSet::new();
+        // runs until both channels are closed and drained
+        let (mut zdn_open, mut pcap_open) = (true, true);
+        while zdn_open || pcap_open {
             tokio::select! {
                 // 0slot.trade shreds for checking duplication
-                zdn_data = zdn_receiver.recv() => {
+                zdn_data = zdn_receiver.recv(), if zdn_open => {
                     match zdn_data {
-                        Some(shred_data) if !shred_data.is_empty() => {
-                            let hash = state.hash_one(&shred_data);
-                            if !current.contains_key(&hash) {
-                                current.insert(hash, Source::Zdn);
-                                preparing.insert(hash, Source::Zdn);
+                        Some((shred_data, received_at)) => {
+                            let Some(slice) = dedup_slice(&shred_data) else { continue };
+                            let hash = state.hash_one(slice);
+                            match current.get(&hash) {
+                                None => mark(&mut current, &mut preparing, hash, Seen::Zdn(received_at)),
+                                Some(&Seen::Turbine(captured_at)) => {
+                                    // turbine won, lead is negative
+                                    let lead_us = diff_time(received_at, captured_at) as i64;
+                                    stats.turbine_race.record(lead_us);
+                                    stats.calibration.record(lead_us);
+                                    mark(&mut current, &mut preparing, hash, Seen::Both);
+                                }
+                                Some(_) => {}, // duplicated
                             }
                         }
-                        Some(_) => {}, // no data
                         None => {
                             info!("ZDN receiver channel closed");
-                            break;
+                            zdn_open = false;
                         }
                     }
                 }
 
                 // process pcap data
-                packet_data = pcap_rx.recv() => {
+                packet_data = pcap_rx.recv(), if pcap_open => {
                     match packet_data {
-                        Some(data) => {
-                            // invalid packet
-                            if data.len() <= payload_offset {
-                                continue;
-                            }
-                            
-                            let udp_payload = &data[payload_offset..];
-                            
-                            // check duplication
-                            match Shred::new_from_serialized_shred(udp_payload.to_vec()) {
-                                Ok(shred) => {
-                                    let hash = state.hash_one(shred.payload());
-                                    if current.contains_key(&hash) {
-                                        // warn!("duplicated");
+                        Some((udp_payload, captured_at)) => {
+                            stats.packets[Source::LocalTurbine].fetch_add(1, Ordering::Relaxed);
+                            let hash = match Shred::new_from_serialized_shred(udp_payload.clone()) {
+                                Ok(_) => match dedup_slice(&udp_payload) {
+                                    Some(slice) => state.hash_one(slice),
+                                    None => {
+                                        stats.invalids[Source::LocalTurbine].fetch_add(1, Ordering::Relaxed);
                                         continue;
                                     }
-                                    
-                                    total_send_back_count += 1;
-                                    
-                                    // forward to validators
-                                    let send_futures: Vec<_> = addrs.iter().map(|&addr| {
-                                        let socket = Arc::clone(&socket);
-                                        let payload = udp_payload.to_vec();
-                                        async move {
-                                            match socket.send_to(&payload, addr).await {
-                                                Ok(_) => {},
-                                                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                                    error!("System buffer full for {}", addr);
-                                                }
-                                                Err(e) => {
-                                                    error!("Send to {} failed: {}", addr, e);
-                                                }
-                                            }
-                                        }
-                                    }).collect();
-                                    
-                                    // forwarding in threads
-                                    tokio::spawn(async move {
-                                        futures::future::join_all(send_futures).await;
-                                    });
-                                }
+                                },
                                 Err(_) => {
+                                    stats.invalids[Source::LocalTurbine].fetch_add(1, Ordering::Relaxed);
                                     warn!("⚠️ Invalid Shred data, length = {} bytes", udp_payload.len());
+                                    continue;
+                                }
+                            };
+
+                            slots.record(Source::LocalTurbine, &udp_payload, captured_at);
+                            if let Some(race) = &race {
+                                race.record(Source::LocalTurbine, &udp_payload, captured_at);
+                            }
+                            if let Some(entries) = &entries {
+                                entries.record(&udp_payload, captured_at);
+                            }
+
+                            // check duplication
+                            match current.get(&hash) {
+                                None => mark(&mut current, &mut preparing, hash, Seen::Turbine(captured_at)),
+                                Some(&Seen::Zdn(received_at)) => {
+                                    // ZDN won, no need to send it back
+                                    let lead_us = diff_time(received_at, captured_at) as i64;
+                                    stats.turbine_race.record(lead_us);
+                                    stats.calibration.record(lead_us);
+                                    mark(&mut current, &mut preparing, hash, Seen::Both);
+                                    continue;
                                 }
+                                Some(_) => continue, // duplicated, e.g. captured on several interfaces
                             }
+
+                            total_send_back_count += 1;
+                            stats.sent_back.fetch_add(1, Ordering::Relaxed);
+
+                            // forward to validators
+                            // raw mode sends the captured buffer itself
+                            let sealed = match send_back.wrap(&udp_payload) {
+                                Cow::Owned(packet) => Some(packet),
+                                Cow::Borrowed(_) => None,
+                            };
+                            let payload = Arc::new(sealed.unwrap_or(udp_payload));
+                            let send_futures: Vec<_> = send_back.addrs().iter().map(|&addr| {
+                                let socket = Arc::clone(&socket);
+                                let payload = Arc::clone(&payload);
+                                let stats = Arc::clone(&stats);
+                                async move {
+                                    match socket.send_to(&payload, addr).await {
+                                        Ok(_) => {},
+                                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                            stats.send_back_errors.fetch_add(1, Ordering::Relaxed);
+                                            error!("System buffer full for {}", addr);
+                                        }
+                                        Err(e) => {
+                                            stats.send_back_errors.fetch_add(1, Ordering::Relaxed);
+                                            error!("Send to {} failed: {}", addr, e);
+                                        }
+                                    }
+                                }
+                            }).collect();
+
+                            // forwarding in threads
+                            let stats = Arc::clone(&stats);
+                            while sending.try_join_next().is_some() {}
+                            sending.spawn(async move {
+                                futures::future::join_all(send_futures).await;
+                                if let Ok(elapsed) = SystemTime::now().duration_since(captured_at) {
+                                    stats.latency.record(Stage::CaptureToSendBack, elapsed);
+                                }
+                            });
                         }
                         None => {
                             info!("Pcap receiver channel closed");
-                            break;
+                            pcap_open = false;
                         }
                     }
                 }
 
-                // swap every 15secs
+                // swap every dedup rotation
                 _ = interval_timer.tick() => {
                     std::mem::swap(&mut current, &mut preparing);
                     preparing.clear();
@@ -158,11 +380,27 @@ pub async fn start_sniffer(
                     info!(
                         "📊 Total send back count last one minute = {} to addrs: {}",
                         total_send_back_count,
-                        addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")
+                        send_back.addrs().iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")
                     );
                     total_send_back_count = 0;
+                    for cap_stats in &capture_stats {
+                        info!("📊 capture {}", cap_stats.report());
+                    }
                 }
             }
         }
-    });    
-}
\ No newline at end of file
+        while sending.join_next().await.is_some() {}
+    });
+    Sniffer { stop, threads, task }
+}
+
+#[inline]
+fn mark(
+    current: &mut HashMap<u64, Seen, BuildNoHashHasher<u64>>,
+    preparing: &mut HashMap<u64, Seen, BuildNoHashHasher<u64>>,
+    hash: u64,
+    seen: Seen,
+) {
+    current.insert(hash, seen);
+    preparing.insert(hash, seen);
+}
@@ -1,14 +1,58 @@
 use enum_map::{enum_map, EnumMap};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 
-use crate::shred_zdn::args::Source;
+use crate::shred_zdn::{args::Source, session::SessionState};
 
+/// upper bounds of the zdn lead histogram buckets in microseconds, negative means turbine was first.
+pub const LEAD_BUCKETS_US: [i64; 12] = [-50_000, -10_000, -5_000, -1_000, 0, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000];
+
+/// all counters are monotonic, `/metrics` reads them as they are and the periodic log line
+/// shows the increase since the previous one.
 pub struct Stats {
     pub packets: EnumMap<Source, AtomicUsize>,
     pub invalids: EnumMap<Source, AtomicUsize>,
     pub firsts: EnumMap<Source, AtomicUsize>,
     pub forwarded: AtomicUsize,
+    /// packets and send errors per forward target.
+    pub forward_sent: LabeledCounter,
+    pub forward_errors: LabeledCounter,
+    /// sniffed shreds sent back and failed sends.
+    pub sent_back: AtomicUsize,
+    pub send_back_errors: AtomicUsize,
+    /// shreds too long for a shared-memory ring slot, not written to the ring.
+    pub ring_oversized: AtomicUsize,
     pub nanos: AtomicU64,
+    pub latency: LatencyStats,
+    pub turbine_race: TurbineRace,
+    pub calibration: CalibrationSamples,
+    pub session: SessionStats,
+    pub slots: SlotStats,
+    /// one per capture, registered by the sniffer.
+    pub captures: RwLock<Vec<Arc<CaptureStats>>>,
+    reported: Mutex<Reported>,
+}
+
+/// counter values at the previous log line.
+#[derive(Default)]
+struct Reported {
+    packets: EnumMap<Source, usize>,
+    invalids: EnumMap<Source, usize>,
+    firsts: EnumMap<Source, usize>,
+    forwarded: usize,
+    nanos: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Stats {
@@ -18,27 +62,608 @@ impl Stats {
             invalids: enum_map! { _ => AtomicUsize::new(0) },
             firsts: enum_map! { _ => AtomicUsize::new(0) },
             forwarded: AtomicUsize::new(0),
+            forward_sent: LabeledCounter::default(),
+            forward_errors: LabeledCounter::default(),
+            sent_back: AtomicUsize::new(0),
+            send_back_errors: AtomicUsize::new(0),
+            ring_oversized: AtomicUsize::new(0),
             nanos: AtomicU64::new(0),
+            latency: LatencyStats::new(),
+            turbine_race: TurbineRace::new(),
+            calibration: CalibrationSamples::new(),
+            session: SessionStats::new(),
+            slots: SlotStats::new(),
+            captures: RwLock::new(Vec::new()),
+            reported: Mutex::new(Reported::default()),
         }
     }
 
+    /// the periodic log line, counts since the previous call.
     pub fn report(&self) -> String {
-        let zdn_packet = self.packets[Source::Zdn].swap(0, Ordering::Relaxed);
-        let reference_packet = self.packets[Source::Reference].swap(0, Ordering::Relaxed);
-        let zdn_invalid = self.invalids[Source::Zdn].swap(0, Ordering::Relaxed);
-        let reference_invalid = self.invalids[Source::Reference].swap(0, Ordering::Relaxed);
-        let zdn_first = self.firsts[Source::Zdn].swap(0, Ordering::Relaxed);
-        let reference_first = self.firsts[Source::Reference].swap(0, Ordering::Relaxed);
-        let forwarded = self.forwarded.swap(0, Ordering::Relaxed);
-        let nanos = self.nanos.swap(0, Ordering::Relaxed);
+        let mut guard = self.reported.lock().unwrap();
+        let reported = &mut *guard;
+        let zdn_packet = since(&self.packets[Source::Zdn], &mut reported.packets[Source::Zdn]);
+        let reference_packet = since(&self.packets[Source::Reference], &mut reported.packets[Source::Reference]);
+        let zdn_invalid = since(&self.invalids[Source::Zdn], &mut reported.invalids[Source::Zdn]);
+        let reference_invalid = since(&self.invalids[Source::Reference], &mut reported.invalids[Source::Reference]);
+        let zdn_first = since(&self.firsts[Source::Zdn], &mut reported.firsts[Source::Zdn]);
+        let reference_first = since(&self.firsts[Source::Reference], &mut reported.firsts[Source::Reference]);
+        let forwarded = since(&self.forwarded, &mut reported.forwarded);
+        let nanos = self.nanos.load(Ordering::Relaxed);
+        let nanos = nanos.wrapping_sub(std::mem::replace(&mut reported.nanos, nanos));
+        let turbine_packet = since(&self.packets[Source::LocalTurbine], &mut reported.packets[Source::LocalTurbine]);
+        let turbine_invalid = since(&self.invalids[Source::LocalTurbine], &mut reported.invalids[Source::LocalTurbine]);
 
         format!(
-            "zdn-packet {zdn_packet}, reference-packet {reference_packet}, \
-            zdn-invalid {zdn_invalid}, reference-invalid {reference_invalid}, \
+            "zdn-packet {zdn_packet}, reference-packet {reference_packet}, turbine-packet {turbine_packet}, \
+            zdn-invalid {zdn_invalid}, reference-invalid {reference_invalid}, turbine-invalid {turbine_invalid}, \
             zdn-first {zdn_first}, reference-first {reference_first}, \
             forwarded {forwarded}, \
-            ms {:.2}",
-            nanos as f64 / 1e6
+            ms {:.2}, {}, {}, {}",
+            nanos as f64 / 1e6,
+            self.turbine_race.report(),
+            self.session.report(),
+            self.slots.report(),
+        )
+    }
+}
+
+/// ZDN vs local turbine race, lead is how many microseconds ZDN arrived before turbine (negative if later).
+/// `leads` holds the samples since the previous log line, the histogram is cumulative.
+pub struct TurbineRace {
+    pub zdn_first: AtomicUsize,
+    pub turbine_first: AtomicUsize,
+    pub leads: Mutex<Vec<i64>>,
+    /// per `LEAD_BUCKETS_US` bound, samples above the last bound only count in `lead_count`.
+    pub lead_buckets: [AtomicUsize; LEAD_BUCKETS_US.len()],
+    pub lead_count: AtomicUsize,
+    pub lead_sum_us: AtomicI64,
+    reported: Mutex<(usize, usize)>,
+}
+
+impl Default for TurbineRace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TurbineRace {
+    pub fn new() -> Self {
+        Self {
+            zdn_first: AtomicUsize::new(0),
+            turbine_first: AtomicUsize::new(0),
+            leads: Mutex::new(Vec::new()),
+            lead_buckets: std::array::from_fn(|_| AtomicUsize::new(0)),
+            lead_count: AtomicUsize::new(0),
+            lead_sum_us: AtomicI64::new(0),
+            reported: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn record(&self, lead_us: i64) {
+        if lead_us >= 0 {
+            self.zdn_first.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.turbine_first.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(bucket) = LEAD_BUCKETS_US.iter().position(|bound| lead_us <= *bound) {
+            self.lead_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.lead_count.fetch_add(1, Ordering::Relaxed);
+        self.lead_sum_us.fetch_add(lead_us, Ordering::Relaxed);
+        self.leads.lock().unwrap().push(lead_us);
+    }
+
+    pub fn report(&self) -> String {
+        let mut guard = self.reported.lock().unwrap();
+        let (reported_zdn, reported_turbine) = &mut *guard;
+        let zdn_first = since(&self.zdn_first, reported_zdn);
+        let turbine_first = since(&self.turbine_first, reported_turbine);
+        let mut leads = std::mem::take(&mut *self.leads.lock().unwrap());
+        leads.sort_unstable();
+
+        let total = zdn_first + turbine_first;
+        let won = if total > 0 { zdn_first as f64 * 100.0 / total as f64 } else { 0.0 };
+        format!(
+            "zdn-beat-turbine {zdn_first}/{total} ({won:.1}%), \
+            zdn-lead-us p10 {} p50 {} p90 {} p99 {}",
+            percentile(&leads, 0.10),
+            percentile(&leads, 0.50),
+            percentile(&leads, 0.90),
+            percentile(&leads, 0.99),
         )
     }
 }
+
+/// ZDN lead samples against turbine and the reference feed, only collected while a region is calibrated.
+pub struct CalibrationSamples {
+    active: AtomicBool,
+    leads: Mutex<Vec<i64>>,
+}
+
+impl Default for CalibrationSamples {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CalibrationSamples {
+    pub fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            leads: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn start(&self) {
+        self.leads.lock().unwrap().clear();
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, lead_us: i64) {
+        if self.active.load(Ordering::Relaxed) {
+            self.leads.lock().unwrap().push(lead_us);
+        }
+    }
+
+    /// stop collecting and return the samples since `start`.
+    pub fn finish(&self) -> Vec<i64> {
+        self.active.store(false, Ordering::Relaxed);
+        std::mem::take(&mut *self.leads.lock().unwrap())
+    }
+}
+
+/// registration session counters, `state` holds a `SessionState`.
+pub struct SessionStats {
+    pub state: AtomicU8,
+    pub registrations: AtomicUsize,
+    /// successful registrations per region.
+    pub region_registrations: LabeledCounter,
+    pub refreshes: AtomicUsize,
+    pub refresh_failures: AtomicUsize,
+    reported: Mutex<[usize; 3]>,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            state: AtomicU8::new(SessionState::Unregistered as u8),
+            registrations: AtomicUsize::new(0),
+            region_registrations: LabeledCounter::default(),
+            refreshes: AtomicUsize::new(0),
+            refresh_failures: AtomicUsize::new(0),
+            reported: Mutex::new([0; 3]),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let state = SessionState::from_u8(self.state.load(Ordering::Relaxed));
+        let mut reported = self.reported.lock().unwrap();
+        let registrations = since(&self.registrations, &mut reported[0]);
+        let refreshes = since(&self.refreshes, &mut reported[1]);
+        let refresh_failures = since(&self.refresh_failures, &mut reported[2]);
+
+        format!(
+            "session {state:?}, keep-alive {registrations}, \
+            jwt-refresh {refreshes}, jwt-refresh-failed {refresh_failures}"
+        )
+    }
+}
+
+/// per-source completeness of finished slots, only sources that delivered part of a slot count it.
+pub struct SlotStats {
+    pub slots: EnumMap<Source, AtomicUsize>,
+    /// slots the source delivered every data shred of.
+    pub completed: EnumMap<Source, AtomicUsize>,
+    /// microseconds from a slot's first shred (any source) until the source completed it.
+    pub completion_us: EnumMap<Source, AtomicUsize>,
+    /// data shreds per slot, up to the one flagged `LAST_SHRED_IN_SLOT`.
+    pub expected: EnumMap<Source, AtomicUsize>,
+    pub received: EnumMap<Source, AtomicUsize>,
+    pub missing: EnumMap<Source, AtomicUsize>,
+    /// missing data shreds the received coding shreds could restore.
+    pub recoverable: EnumMap<Source, AtomicUsize>,
+    reported: Mutex<EnumMap<Source, [usize; 5]>>,
+}
+
+impl Default for SlotStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlotStats {
+    pub fn new() -> Self {
+        Self {
+            slots: enum_map! { _ => AtomicUsize::new(0) },
+            completed: enum_map! { _ => AtomicUsize::new(0) },
+            completion_us: enum_map! { _ => AtomicUsize::new(0) },
+            expected: enum_map! { _ => AtomicUsize::new(0) },
+            received: enum_map! { _ => AtomicUsize::new(0) },
+            missing: enum_map! { _ => AtomicUsize::new(0) },
+            recoverable: enum_map! { _ => AtomicUsize::new(0) },
+            reported: Mutex::new(EnumMap::default()),
+        }
+    }
+
+    /// missing data shreds over expected ones since the start, 0 before the first slot.
+    pub fn loss(&self, source: Source) -> f64 {
+        let expected = self.expected[source].load(Ordering::Relaxed);
+        if expected == 0 {
+            return 0.0;
+        }
+        self.missing[source].load(Ordering::Relaxed) as f64 / expected as f64
+    }
+
+    pub fn report(&self) -> String {
+        let mut guard = self.reported.lock().unwrap();
+        let mut parts = Vec::new();
+        for (source, reported) in guard.iter_mut() {
+            let slots = since(&self.slots[source], &mut reported[0]);
+            let completed = since(&self.completed[source], &mut reported[1]);
+            let expected = since(&self.expected[source], &mut reported[2]);
+            let missing = since(&self.missing[source], &mut reported[3]);
+            let recoverable = since(&self.recoverable[source], &mut reported[4]);
+            if slots == 0 {
+                continue;
+            }
+            let pct = |n: usize| if expected > 0 { n as f64 * 100.0 / expected as f64 } else { 0.0 };
+            parts.push(format!(
+                "{}-slots {completed}/{slots} complete, {}-loss {:.2}% ({:.2}% recoverable)",
+                source.as_str(),
+                source.as_str(),
+                pct(missing),
+                pct(recoverable),
+            ));
+        }
+        if parts.is_empty() {
+            return "slots 0".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+/// pipeline stages with a latency histogram.
+#[derive(enum_map::Enum, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Stage {
+    /// kernel receive timestamp until a receiver reads the packet from its socket.
+    KernelToReceiver,
+    /// kernel receive timestamp until the sniffer reads the packet from its capture.
+    KernelToSniffer,
+    /// socket read until the processor's dedup decision.
+    ReceiveToDedup,
+    /// dedup decision until the last forward `send_to` completed.
+    DedupToSend,
+    /// sniffer capture until the send-back sends completed.
+    CaptureToSendBack,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::KernelToReceiver => "kernel_to_receiver",
+            Stage::KernelToSniffer => "kernel_to_sniffer",
+            Stage::ReceiveToDedup => "receive_to_dedup",
+            Stage::DedupToSend => "dedup_to_send",
+            Stage::CaptureToSendBack => "capture_to_send_back",
+        }
+    }
+}
+
+/// quantiles the log line and `/metrics` show, with their labels.
+pub const LATENCY_QUANTILES: [(f64, &str); 4] = [(0.5, "0.5"), (0.9, "0.9"), (0.99, "0.99"), (0.999, "0.999")];
+
+/// latencies are counted up to 60s, longer ones as 60s.
+const LATENCY_MAX_NS: u64 = 60_000_000_000;
+/// values below this have a bucket each, above it every power of two is split into half as many
+/// buckets, so a bucket is within 1% of its values.
+const SUB_BUCKETS: u64 = 256;
+const LATENCY_BUCKETS: usize = bucket_of(LATENCY_MAX_NS) + 1;
+
+/// log-linear bucket of a nanosecond latency.
+const fn bucket_of(ns: u64) -> usize {
+    if ns < SUB_BUCKETS {
+        return ns as usize;
+    }
+    let shift = 63 - ns.leading_zeros() as u64 - (SUB_BUCKETS.trailing_zeros() as u64 - 1);
+    let top = ns >> shift;
+    (SUB_BUCKETS + (shift - 1) * SUB_BUCKETS / 2 + top - SUB_BUCKETS / 2) as usize
+}
+
+/// highest latency counted in `bucket`.
+fn bucket_value(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = (bucket - SUB_BUCKETS) / (SUB_BUCKETS / 2) + 1;
+    let top = (bucket - SUB_BUCKETS) % (SUB_BUCKETS / 2) + SUB_BUCKETS / 2;
+    ((top + 1) << shift) - 1
+}
+
+/// latency at quantile `q` of the bucket `counts`, at most `max_ns`.
+fn quantile(counts: &[u64], q: f64, max_ns: u64) -> u64 {
+    let total = counts.iter().sum::<u64>();
+    let rank = ((q * total as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return bucket_value(bucket).min(max_ns);
+        }
+    }
+    0
+}
+
+/// atomic nanosecond buckets of one stage, recorded from any thread without a lock; the log line
+/// and `/metrics` read them, the log line as the increase since the previous one.
+struct StageLatency {
+    buckets: Box<[AtomicU64]>,
+    sum_ns: AtomicU64,
+    max_ns: AtomicU64,
+    /// highest latency since the previous log line.
+    window_max_ns: AtomicU64,
+    /// bucket counts at the previous log line.
+    reported: Mutex<Vec<u64>>,
+}
+
+impl StageLatency {
+    fn new() -> Self {
+        Self {
+            buckets: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sum_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            window_max_ns: AtomicU64::new(0),
+            reported: Mutex::new(vec![0; LATENCY_BUCKETS]),
+        }
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+    }
+}
+
+/// a stage's cumulative latency distribution.
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub sum_ns: u64,
+    /// nanoseconds per entry of `LATENCY_QUANTILES`.
+    pub quantiles_ns: [u64; LATENCY_QUANTILES.len()],
+    pub max_ns: u64,
+}
+
+pub struct LatencyStats(EnumMap<Stage, StageLatency>);
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        Self(enum_map! { _ => StageLatency::new() })
+    }
+
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        let ns = (elapsed.as_nanos() as u64).min(LATENCY_MAX_NS);
+        let latency = &self.0[stage];
+        latency.buckets[bucket_of(ns)].fetch_add(1, Ordering::Relaxed);
+        latency.sum_ns.fetch_add(ns, Ordering::Relaxed);
+        // a new maximum is rare, skip the read-modify-write otherwise
+        for max in [&latency.max_ns, &latency.window_max_ns] {
+            if ns > max.load(Ordering::Relaxed) {
+                max.fetch_max(ns, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn snapshot(&self, stage: Stage) -> LatencySnapshot {
+        let latency = &self.0[stage];
+        let counts = latency.counts();
+        let max_ns = latency.max_ns.load(Ordering::Relaxed);
+        LatencySnapshot {
+            count: counts.iter().sum(),
+            sum_ns: latency.sum_ns.load(Ordering::Relaxed),
+            quantiles_ns: LATENCY_QUANTILES.map(|(q, _)| quantile(&counts, q, max_ns)),
+            max_ns,
+        }
+    }
+
+    /// per-stage quantiles since the previous call, stages without samples are left out.
+    pub fn report(&self) -> String {
+        let us = |ns: u64| ns as f64 / 1000.0;
+        let mut parts = Vec::new();
+        for (stage, latency) in &self.0 {
+            // the maximum first, a sample recorded in between is counted now and its maximum next time
+            let max_ns = latency.window_max_ns.swap(0, Ordering::Relaxed);
+            let counts = latency.counts();
+            let window = {
+                let mut reported = latency.reported.lock().unwrap();
+                let window = counts.iter().zip(reported.iter()).map(|(now, then)| now - then).collect::<Vec<_>>();
+                *reported = counts;
+                window
+            };
+            if window.iter().all(|count| *count == 0) {
+                continue;
+            }
+            let [p50, p90, p99, p999] = LATENCY_QUANTILES.map(|(q, _)| us(quantile(&window, q, max_ns)));
+            parts.push(format!(
+                "{}-us p50 {:.1} p90 {:.1} p99 {:.1} p99.9 {:.1} max {:.1}",
+                stage.as_str().replace('_', "-"),
+                p50,
+                p90,
+                p99,
+                p999,
+                us(max_ns),
+            ));
+        }
+        if parts.is_empty() {
+            return "no samples".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+/// counters keyed by a label value such as a forward target or a region, created on first use.
+#[derive(Default)]
+pub struct LabeledCounter(RwLock<BTreeMap<String, Arc<AtomicUsize>>>);
+
+impl LabeledCounter {
+    /// the counter of `label`, hot paths keep it instead of looking it up per packet.
+    pub fn counter(&self, label: &str) -> Arc<AtomicUsize> {
+        if let Some(counter) = self.0.read().unwrap().get(label) {
+            return Arc::clone(counter);
+        }
+        Arc::clone(self.0.write().unwrap().entry(label.to_string()).or_default())
+    }
+
+    pub fn inc(&self, label: &str) {
+        self.counter(label).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn values(&self) -> Vec<(String, usize)> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(label, counter)| (label.clone(), counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// increase of a monotonic counter since the previous call, `reported` keeps the previous value.
+pub(crate) fn since(counter: &AtomicUsize, reported: &mut usize) -> usize {
+    let current = counter.load(Ordering::Relaxed);
+    current.wrapping_sub(std::mem::replace(reported, current))
+}
+
+/// nearest-rank percentile of a sorted slice, 0 if empty.
+pub fn percentile(sorted: &[i64], p: f64) -> i64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+/// per-interface capture counters, kernel counters are cumulative values from `pcap::Stat`.
+pub struct CaptureStats {
+    pub interface: String,
+    pub captured: AtomicUsize,
+    reported_captured: AtomicUsize,
+    pub kernel_received: AtomicU64,
+    pub kernel_dropped: AtomicU64,
+    pub if_dropped: AtomicU64,
+}
+
+impl CaptureStats {
+    pub fn new(interface: &str) -> Self {
+        Self {
+            interface: interface.to_string(),
+            captured: AtomicUsize::new(0),
+            reported_captured: AtomicUsize::new(0),
+            kernel_received: AtomicU64::new(0),
+            kernel_dropped: AtomicU64::new(0),
+            if_dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn update(&self, stat: &pcap::Stat) {
+        self.kernel_received.store(stat.received as u64, Ordering::Relaxed);
+        self.kernel_dropped.store(stat.dropped as u64, Ordering::Relaxed);
+        self.if_dropped.store(stat.if_dropped as u64, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> String {
+        let captured = self.captured.load(Ordering::Relaxed);
+        let captured = captured.wrapping_sub(self.reported_captured.swap(captured, Ordering::Relaxed));
+        let kernel_received = self.kernel_received.load(Ordering::Relaxed);
+        let kernel_dropped = self.kernel_dropped.load(Ordering::Relaxed);
+        let if_dropped = self.if_dropped.load(Ordering::Relaxed);
+
+        format!(
+            "{}: captured {captured}, kernel-received {kernel_received}, \
+            kernel-dropped {kernel_dropped}, if-dropped {if_dropped}",
+            self.interface
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn buckets_are_within_one_percent() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(SUB_BUCKETS - 1), SUB_BUCKETS as usize - 1);
+        // buckets are contiguous
+        for ns in 1..20_000 {
+            assert!(bucket_of(ns) - bucket_of(ns - 1) <= 1);
+        }
+        for ns in (0..20_000).chain((1..64).map(|shift| (LATENCY_MAX_NS >> shift) | 1)).chain([LATENCY_MAX_NS]) {
+            let bucket = bucket_of(ns);
+            assert!(bucket < LATENCY_BUCKETS);
+            let value = bucket_value(bucket);
+            assert!(value >= ns && value - ns <= ns / 100, "{} in bucket {} up to {}", ns, bucket, value);
+        }
+    }
+
+    #[test]
+    fn quantiles_of_the_buckets() {
+        let mut counts = vec![0; LATENCY_BUCKETS];
+        for ns in 1..=1000 {
+            counts[bucket_of(ns * 1000)] += 1;
+        }
+        let close = |actual: u64, expected: u64| actual.abs_diff(expected) <= expected / 100;
+        assert!(close(quantile(&counts, 0.5, 1_000_000), 500_000));
+        assert!(close(quantile(&counts, 0.99, 1_000_000), 990_000));
+        assert_eq!(quantile(&counts, 1.0, 1_000_000), 1_000_000);
+        assert_eq!(quantile(&vec![0; LATENCY_BUCKETS], 0.5, 0), 0);
+    }
+
+    #[test]
+    fn report_covers_the_samples_since_the_previous_one() {
+        let latency = LatencyStats::new();
+        assert_eq!(latency.report(), "no samples");
+        latency.record(Stage::DedupToSend, Duration::from_micros(10));
+        latency.record(Stage::DedupToSend, Duration::from_micros(30));
+        assert_eq!(latency.report(), "dedup-to-send-us p50 10.0 p90 30.0 p99 30.0 p99.9 30.0 max 30.0");
+        assert_eq!(latency.report(), "no samples");
+        latency.record(Stage::DedupToSend, Duration::from_micros(20));
+        assert_eq!(latency.report(), "dedup-to-send-us p50 20.0 p90 20.0 p99 20.0 p99.9 20.0 max 20.0");
+
+        // the snapshot covers every sample since the start
+        let snapshot = latency.snapshot(Stage::DedupToSend);
+        assert_eq!((snapshot.count, snapshot.sum_ns, snapshot.max_ns), (3, 60_000, 30_000));
+        assert_eq!(latency.snapshot(Stage::KernelToSniffer).count, 0);
+    }
+
+    #[test]
+    fn threads_record_concurrently() {
+        let latency = LatencyStats::new();
+        thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let latency = &latency;
+                scope.spawn(move || {
+                    for ns in 0..10_000 {
+                        latency.record(Stage::KernelToReceiver, Duration::from_nanos(thread * 10_000 + ns));
+                    }
+                });
+            }
+        });
+        let snapshot = latency.snapshot(Stage::KernelToReceiver);
+        assert_eq!(snapshot.count, 40_000);
+        assert_eq!(snapshot.sum_ns, (0..40_000).sum::<u64>());
+        assert_eq!(snapshot.max_ns, 39_999);
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+    body::HttpBody,
+    header::CONTENT_LENGTH,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use solana_connection_cache::{
+    connection_cache_stats::ConnectionCacheStats,
+    nonblocking::client_connection::ClientConnection,
+};
+use solana_quic_client::nonblocking::quic_client::{QuicClientConnection, QuicLazyInitializedEndpoint};
+use solana_sdk::transaction::VersionedTransaction;
+use tokio::{net::UdpSocket, sync::watch, task::JoinHandle};
+use zdn_proto::types::TxPropagationConfig;
+
+use crate::common::net_utils::request::generate_client;
+use crate::shred_zdn::stats::since;
+
+/// largest request accepted, a transaction is at most 1232 bytes before encoding.
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardProtocol {
+    Udp,
+    Quic,
+    Http,
+}
+
+impl ForwardProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardProtocol::Udp => "udp",
+            ForwardProtocol::Quic => "quic",
+            ForwardProtocol::Http => "http",
+        }
+    }
+
+    fn parse(protocol: &str) -> Option<Self> {
+        match protocol.to_ascii_lowercase().as_str() {
+            "udp" => Some(ForwardProtocol::Udp),
+            "quic" => Some(ForwardProtocol::Quic),
+            "http" | "https" => Some(ForwardProtocol::Http),
+            _ => None,
+        }
+    }
+
+    /// pick one of the advertised protocols, quic first, then udp, then http.
+    fn choose(supported: &[String]) -> Option<Self> {
+        let supported = supported.iter().filter_map(|p| Self::parse(p)).collect::<Vec<_>>();
+        [ForwardProtocol::Quic, ForwardProtocol::Udp, ForwardProtocol::Http]
+            .into_iter()
+            .find(|p| supported.contains(p))
+    }
+}
+
+enum Endpoint {
+    Udp(SocketAddr),
+    Quic(QuicClientConnection),
+    Http(String),
+}
+
+/// one forwarder from the propagation config with its latency and error counters, monotonic
+/// for `/metrics`.
+pub struct Target {
+    pub addr: String,
+    pub protocol: ForwardProtocol,
+    endpoint: Endpoint,
+    pub sent: AtomicUsize,
+    pub errors: AtomicUsize,
+    pub nanos: AtomicU64,
+    reported: Mutex<(usize, usize, u64)>,
+}
+
+impl Target {
+    /// counts since the previous report.
+    pub fn report(&self) -> String {
+        let mut guard = self.reported.lock().unwrap();
+        let (reported_sent, reported_errors, reported_nanos) = &mut *guard;
+        let sent = since(&self.sent, reported_sent);
+        let errors = since(&self.errors, reported_errors);
+        let nanos = self.nanos.load(Ordering::Relaxed);
+        let nanos = nanos.wrapping_sub(std::mem::replace(reported_nanos, nanos));
+        let avg_ms = if sent > 0 { nanos as f64 / sent as f64 / 1e6 } else { 0.0 };
+        format!("{}({:?}) sent {sent}, errors {errors}, avg-ms {avg_ms:.2}", self.addr, self.protocol)
+    }
+}
+
+/// forwards `sendTransaction` requests to the trader apis and tx forwarders advertised in
+/// `RegisterResponse.tx_propagation_config`.
+pub struct TxProxy {
+    config: RwLock<Option<TxPropagationConfig>>,
+    targets: RwLock<Arc<Vec<Arc<Target>>>>,
+    socket: UdpSocket,
+    quic_endpoint: Arc<QuicLazyInitializedEndpoint>,
+    http: reqwest::Client,
+    pub requests: AtomicUsize,
+    pub rejected: AtomicUsize,
+    reported: Mutex<[usize; 2]>,
+    /// set by `stop`, the server stops accepting requests.
+    stopping: watch::Sender<bool>,
+    /// every forward in flight holds a receiver, `drain` waits until none is left.
+    in_flight: watch::Sender<()>,
+}
+
+impl TxProxy {
+    pub async fn new() -> Self {
+        Self {
+            config: RwLock::new(None),
+            targets: RwLock::new(Arc::new(Vec::new())),
+            socket: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.expect("bind failed"),
+            quic_endpoint: Arc::new(QuicLazyInitializedEndpoint::default()),
+            http: generate_client(None),
+            requests: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            reported: Mutex::new([0; 2]),
+            stopping: watch::channel(false).0,
+            in_flight: watch::channel(()).0,
+        }
+    }
+
+    /// stop accepting requests, the ones being read are still answered.
+    pub fn stop(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    /// wait until the transactions already accepted are forwarded.
+    pub async fn drain(&self) {
+        self.in_flight.closed().await;
+    }
+
+    /// the current forwarders, rebuilt with fresh counters when the config changes.
+    pub fn targets(&self) -> Arc<Vec<Arc<Target>>> {
+        Arc::clone(&self.targets.read().unwrap())
+    }
+
+    /// rebuild the forwarder list when registration returns a different config.
+    pub async fn update(&self, config: &TxPropagationConfig) {
+        if self.config.read().unwrap().as_ref() == Some(config) {
+            return;
+        }
+
+        let mut targets = Vec::new();
+        for api in &config.trader_apis {
+            targets.push(self.target(api, ForwardProtocol::Http).await);
+        }
+        for forwarder in &config.tx_forwarders {
+            match ForwardProtocol::choose(&forwarder.supported_protocols) {
+                Some(protocol) => targets.push(self.target(&forwarder.addr, protocol).await),
+                None => warn!(
+                    "tx forwarder {} has no supported protocol in {:?}",
+                    forwarder.addr, forwarder.supported_protocols
+                ),
+            }
+        }
+        let targets = targets.into_iter().flatten().map(Arc::new).collect::<Vec<_>>();
+        info!(
+            "tx forwarders updated: {}",
+            targets.iter().map(|t| format!("{}({:?})", t.addr, t.protocol)).collect::<Vec<_>>().join(", ")
+        );
+
+        *self.targets.write().unwrap() = Arc::new(targets);
+        *self.config.write().unwrap() = Some(config.clone());
+    }
+
+    async fn target(&self, addr: &str, protocol: ForwardProtocol) -> Option<Target> {
+        let endpoint = match protocol {
+            ForwardProtocol::Http if addr.starts_with("http://") || addr.starts_with("https://") => {
+                Endpoint::Http(addr.to_string())
+            }
+            ForwardProtocol::Http => Endpoint::Http(format!("http://{}", addr)),
+            ForwardProtocol::Udp | ForwardProtocol::Quic => {
+                let socket_addr = match tokio::net::lookup_host(addr).await.map(|mut addrs| addrs.next()) {
+                    Ok(Some(socket_addr)) => socket_addr,
+                    Ok(None) | Err(_) => {
+                        warn!("Failed to resolve tx forwarder {}", addr);
+                        return None;
+                    }
+                };
+                if protocol == ForwardProtocol::Udp {
+                    Endpoint::Udp(socket_addr)
+                } else {
+                    Endpoint::Quic(QuicClientConnection::new(
+                        Arc::clone(&self.quic_endpoint),
+                        socket_addr,
+                        Arc::new(ConnectionCacheStats::default()),
+                    ))
+                }
+            }
+        };
+        Some(Target {
+            addr: addr.to_string(),
+            protocol,
+            endpoint,
+            sent: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            nanos: AtomicU64::new(0),
+            reported: Mutex::new((0, 0, 0)),
+        })
+    }
+
+    /// send the wire transaction to every target concurrently.
+    async fn forward(&self, wire_tx: Arc<Vec<u8>>) {
+        let targets = self.targets();
+        let sends = targets.iter().map(|target| {
+            let wire_tx = Arc::clone(&wire_tx);
+            async move {
+                let now = Instant::now();
+                let result = match &target.endpoint {
+                    Endpoint::Udp(addr) => self.socket.send_to(&wire_tx, addr).await.map(|_| ()).map_err(|e| e.to_string()),
+                    Endpoint::Quic(connection) => connection.send_data(&wire_tx).await.map_err(|e| e.to_string()),
+                    Endpoint::Http(url) => self.send_http(url, &wire_tx).await,
+                };
+                target.nanos.fetch_add(now.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                match result {
+                    Ok(()) => { target.sent.fetch_add(1, Ordering::Relaxed); }
+                    Err(e) => {
+                        target.errors.fetch_add(1, Ordering::Relaxed);
+                        warn!("Forward tx to {} failed: {}", target.addr, e);
+                    }
+                }
+            }
+        });
+        futures::future::join_all(sends).await;
+    }
+
+    async fn send_http(&self, url: &str, wire_tx: &[u8]) -> Result<(), String> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "sendTransaction",
+            "params": [STANDARD.encode(wire_tx), { "encoding": "base64", "skipPreflight": true }],
+        });
+        let response = self.http.post(url).json(&body).send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("http status {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// counts since the previous report.
+    pub fn report(&self) -> String {
+        let mut reported = self.reported.lock().unwrap();
+        let requests = since(&self.requests, &mut reported[0]);
+        let rejected = since(&self.rejected, &mut reported[1]);
+        let targets = self.targets();
+        format!(
+            "tx-request {requests}, tx-rejected {rejected}, {}",
+            targets.iter().map(|t| t.report()).collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+/// serve a Solana-compatible JSON-RPC `sendTransaction` endpoint on `addr` until `TxProxy::stop`,
+/// the task ends once the open requests are answered.
+pub async fn start_tx_proxy(addr: SocketAddr, proxy: &Arc<TxProxy>) -> JoinHandle<()> {
+    let mut stopping = proxy.stopping.subscribe();
+    let proxy = Arc::clone(proxy);
+    let make_svc = make_service_fn(move |_| {
+        let proxy = Arc::clone(&proxy);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&proxy), req)))
+        }
+    });
+    let server = Server::bind(&addr).serve(make_svc).with_graceful_shutdown(async move {
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    });
+    info!("tx proxy listening on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("tx proxy server failed: {}", e);
+        }
+    })
+}
+
+async fn handle(proxy: Arc<TxProxy>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(Response::builder().status(405).body(Body::empty()).unwrap());
+    }
+    proxy.requests.fetch_add(1, Ordering::Relaxed);
+    let body = match read_body(req).await {
+        Ok(body) => body,
+        Err(BodyError::TooLarge) => {
+            proxy.rejected.fetch_add(1, Ordering::Relaxed);
+            let body = Body::from(format!("request body over {} bytes", MAX_BODY));
+            return Ok(Response::builder().status(StatusCode::PAYLOAD_TOO_LARGE).body(body).unwrap());
+        }
+        Err(BodyError::Read(e)) => return Ok(rpc_response(json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32700, "message": e.to_string()}}))),
+    };
+
+    let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let reply = match parse_send_transaction(&request) {
+        Ok(wire_tx) => {
+            let signature = bincode::deserialize::<VersionedTransaction>(&wire_tx)
+                .ok()
+                .and_then(|tx| tx.signatures.first().map(|sig| sig.to_string()));
+            match signature {
+                Some(signature) => {
+                    let proxy = Arc::clone(&proxy);
+                    let in_flight = proxy.in_flight.subscribe();
+                    tokio::spawn(async move {
+                        proxy.forward(Arc::new(wire_tx)).await;
+                        drop(in_flight);
+                    });
+                    json!({"jsonrpc": "2.0", "id": id, "result": signature})
+                }
+                None => {
+                    proxy.rejected.fetch_add(1, Ordering::Relaxed);
+                    json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32602, "message": "invalid transaction"}})
+                }
+            }
+        }
+        Err((code, message)) => {
+            proxy.rejected.fetch_add(1, Ordering::Relaxed);
+            json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+        }
+    };
+    Ok(rpc_response(reply))
+}
+
+enum BodyError {
+    TooLarge,
+    Read(hyper::Error),
+}
+
+/// the request body, at most `MAX_BODY` bytes whatever `Content-Length` says.
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, BodyError> {
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > MAX_BODY as u64) {
+        return Err(BodyError::TooLarge);
+    }
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BodyError::Read)?;
+        if buf.len() + chunk.len() > MAX_BODY {
+            return Err(BodyError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// wire transaction of a `sendTransaction` request, base58 unless `encoding` says base64.
+fn parse_send_transaction(request: &Value) -> Result<Vec<u8>, (i64, String)> {
+    match request.get("method").and_then(Value::as_str) {
+        Some("sendTransaction") => {}
+        Some(method) => return Err((-32601, format!("method {} not supported", method))),
+        None => return Err((-32600, "invalid request".to_string())),
+    }
+    let params = request.get("params").and_then(Value::as_array).ok_or((-32602, "missing params".to_string()))?;
+    let encoded = params.first().and_then(Value::as_str).ok_or((-32602, "missing transaction".to_string()))?;
+    let encoding = params
+        .get(1)
+        .and_then(|config| config.get("encoding"))
+        .and_then(Value::as_str)
+        .unwrap_or("base58");
+    match encoding {
+        "base58" => bs58::decode(encoded).into_vec().map_err(|e| (-32602, e.to_string())),
+        "base64" => STANDARD.decode(encoded).map_err(|e| (-32602, e.to_string())),
+        other => Err((-32602, format!("unsupported encoding {}", other))),
+    }
+}
+
+fn rpc_response(body: Value) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Vec<u8>, content_length: Option<usize>) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::POST);
+        if let Some(len) = content_length {
+            builder = builder.header(CONTENT_LENGTH, len);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn body_within_limit() {
+        let body = read_body(request(vec![1; MAX_BODY], Some(MAX_BODY))).await;
+        assert!(matches!(body, Ok(body) if body.len() == MAX_BODY));
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        // declared, before reading anything
+        let body = read_body(request(Vec::new(), Some(MAX_BODY + 1))).await;
+        assert!(matches!(body, Err(BodyError::TooLarge)));
+        // undeclared or understated, while reading
+        let body = read_body(request(vec![1; MAX_BODY + 1], None)).await;
+        assert!(matches!(body, Err(BodyError::TooLarge)));
+    }
+}
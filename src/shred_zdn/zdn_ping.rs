@@ -1,118 +1,128 @@
 use std::{
-    process::Command,
-    sync::Mutex,
     collections::HashMap,
-    net::{SocketAddr, ToSocketAddrs},
+    net::SocketAddr,
 };
-use rayon::prelude::*;
-
-/// return the region with the lowest average ping latency.
-pub fn find_nearest_region(host_map: &HashMap<String, String>) -> Option<String> {
-    //let host_map = get_region_host_map();
-    let results = Mutex::new(Vec::new());
-
-    host_map.par_iter().for_each(|(region, host)| {
-        let avg = ping_avg_latency(host, 4);
-        log::info!("Region: {}, Host: {}, Avg Latency: {:?}", region, host, avg);
-        if let Some(ms) = avg {
-            results.lock().unwrap().push((region.to_string(), ms));
-        }
-    });
+use futures::future::join_all;
+use tokio::net::lookup_host;
 
-    results
-        .lock()
-        .unwrap()
-        .iter()
-        .min_by_key(|(_, ms)| *ms)
-        .map(|(region, _)| region.clone())
-}
+use crate::shred_zdn::probe::{probe_host, ProbeConfig};
 
-/// regions order by average ping latency asc.
-pub fn sort_regions(host_map: &HashMap<String, String>) -> Vec<String> {    
-    let results = Mutex::new(Vec::new());
+/// regions with their average probe latency in ms, order by latency asc. unreachable regions are left out.
+/// all regions are probed concurrently.
+pub async fn rank_regions(host_map: &HashMap<String, String>, config: &ProbeConfig) -> Vec<(String, u128)> {
+    let probes = host_map
+        .iter()
+        .map(|(region, host)| async move { (region, host, probe_host(host, config).await) });
 
-    // ping hosts
-    host_map.par_iter().for_each(|(region, host)| {
-        let avg = ping_avg_latency(host, 4);
-        // log::info! aple_log::info!("Region: {}, Host: {}, Avg Latency: {:?}", region, host, avg);
-        if let Some(ms) = avg {
-            results.lock().unwrap().push((region.to_string(), ms));
+    let mut ranking = Vec::new();
+    for (region, host, result) in join_all(probes).await {
+        match result {
+            Ok(stats) if stats.received > 0 => {
+                log::info!("Region: {}, Host: {}, {:?}: {}", region, host, config.method, stats);
+                ranking.push((region.to_string(), stats.avg_ms()));
+            }
+            Ok(stats) => log::warn!("Region: {}, Host: {}, no answer: {}", region, host, stats),
+            Err(e) => log::warn!("Region: {}, Host: {}, probe failed: {}", region, host, e),
         }
-    });
+    }
 
-    let mut collected_results = results.into_inner().unwrap();
+    // sort by probe latency asc
+    ranking.sort_by_key(|(_, ms)| *ms);
+    ranking
+}
 
-    // sort by ping latency asc
-    collected_results.sort_by_key(|(_, ms)| *ms);
-    collected_results
-        .into_iter()
-        .map(|(region, _)| region)
-        .collect()
+/// e.g. `tokyo 3ms, frankfurt 160ms`
+pub fn format_ranking(ranking: &[(String, u128)]) -> String {
+    ranking
+        .iter()
+        .map(|(region, ms)| format!("{} {}ms", region, ms))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-pub fn resolve_nearest_n_region_addrs(
+/// resolve the hosts of `regions` with `port`, in order. regions that fail to resolve are skipped.
+pub async fn resolve_region_addrs(
     host_map: &HashMap<String, String>,
-    sorted_regions: &[String],
-    n: usize,
+    regions: &[String],
+    port: u16,
 ) -> Vec<SocketAddr> {
-    let port = 8002;
     let mut result = Vec::new();
 
-    for region_name in sorted_regions.iter().take(n) {
+    for region_name in regions {
         // get host domain name by region name
-        if let Some(host) = host_map.get(region_name) {
-            log::info!(
-                "Resolving domain for top region '{}': {}:{}",
-                region_name,
-                host,
-                port
-            );
-            // Resolve domain + port into a SocketAddr.
-            match (host.as_str(), port).to_socket_addrs() {
-                Ok(addrs_iter) => {
-                    // A domain name may resolve to multiple IP addresses (e.g., both IPv4 and IPv6).
-                    result.extend(addrs_iter);
-                }
-                Err(e) => {
-                    log::warn!(
-                        "Failed to resolve domain name '{}:{}': {}",
-                        host,
-                        port,
-                        e
-                    );
-                }
-            }
-        } else {
-            log::warn!(
-                "Region '{}' from sorted list not found in host_map.",
-                region_name
-            );
+        let Some(host) = host_map.get(region_name) else {
+            log::warn!("Region '{}' from sorted list not found in host_map.", region_name);
+            continue;
+        };
+        // A domain name may resolve to multiple IP addresses (e.g., both IPv4 and IPv6).
+        match lookup_host((host.as_str(), port)).await {
+            Ok(addrs) => result.extend(addrs),
+            Err(e) => log::warn!("Failed to resolve domain name '{}:{}': {}", host, port, e),
         }
     }
 
     result
 }
 
+/// `--include-regions` / `--exclude-regions` applied to the region map.
+#[derive(Debug, Clone, Default)]
+pub struct RegionFilter {
+    /// only these regions when not empty.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
 
-/// exec ping -c <count> <host>, and then return the average latency.
-fn ping_avg_latency(host: &str, count: usize) -> Option<u128> {
-    let output = Command::new("ping")
-        .args(["-c", &count.to_string(), host])
-        .output()
-        .ok()?;
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.lines() {
-        if line.contains("rtt") || line.contains("round-trip") {
-            // e.g：rtt min/avg/max/mdev = 23.456/56.789/90.123/12.345 ms
-            let parts: Vec<&str> = line.split('=').collect();
-            if parts.len() >= 2 {
-                let stats = parts[1].trim().split('/').collect::<Vec<&str>>();
-                if stats.len() >= 2 {
-                    return stats[1].parse::<f64>().ok().map(|v| v.round() as u128);
-                }
-            }
+impl RegionFilter {
+    pub fn apply(&self, host_map: &HashMap<String, String>) -> HashMap<String, String> {
+        host_map
+            .iter()
+            .filter(|(region, _)| self.include.is_empty() || self.include.contains(region))
+            .filter(|(region, _)| !self.exclude.contains(region))
+            .map(|(region, host)| (region.clone(), host.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_map(regions: &[&str]) -> HashMap<String, String> {
+        regions.iter().map(|region| (region.to_string(), format!("{}.example.com", region))).collect()
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> RegionFilter {
+        RegionFilter {
+            include: include.iter().map(|region| region.to_string()).collect(),
+            exclude: exclude.iter().map(|region| region.to_string()).collect(),
         }
     }
-    None
+
+    fn regions(map: HashMap<String, String>) -> Vec<String> {
+        let mut regions = map.into_keys().collect::<Vec<_>>();
+        regions.sort();
+        regions
+    }
+
+    #[test]
+    fn no_filter_keeps_every_region() {
+        let map = host_map(&["ams", "fra", "ny"]);
+        assert_eq!(RegionFilter::default().apply(&map), map);
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let map = host_map(&["ams", "fra", "ny"]);
+        assert_eq!(regions(filter(&["fra", "ny"], &[]).apply(&map)), ["fra", "ny"]);
+        assert_eq!(regions(filter(&[], &["fra"]).apply(&map)), ["ams", "ny"]);
+        assert_eq!(regions(filter(&["fra", "ny"], &["ny"]).apply(&map)), ["fra"]);
+    }
+
+    #[test]
+    fn unknown_regions_filter_everything() {
+        let map = host_map(&["ams", "fra"]);
+        assert!(filter(&["tokyo"], &[]).apply(&map).is_empty());
+        assert!(filter(&[], &["ams", "fra"]).apply(&map).is_empty());
+        assert!(filter(&["ams"], &[]).apply(&HashMap::new()).is_empty());
+    }
 }
@@ -0,0 +1,297 @@
+//! End-to-end tests: the shred-zdn binary against the in-process mock relay on localhost.
+//!
+//! The binary runs with `--no-sniff` unless a test is about the capture itself: those need a pcap
+//! capture on `lo`, are ignored by default and fail without the privileges to open one
+//! (run them with CAP_NET_RAW or as root, `cargo test --test e2e -- --ignored`).
+
+use std::{
+    collections::HashMap,
+    net::UdpSocket,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use tonic::Code;
+use zdn_proto::{
+    mock::{error_status, register_ok, MockRelay, Reply},
+    relay::RegisterErrorReason,
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_shred-zdn");
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// kills the binary when the test ends, however it ends.
+struct ShredZdn(Child);
+
+impl Drop for ShredZdn {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl ShredZdn {
+    fn spawn(args: &[String]) -> Self {
+        let child = Command::new(BIN)
+            .args(args)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("spawn shred-zdn");
+        Self(child)
+    }
+
+    fn wait_exit(&mut self) -> Option<ExitStatus> {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        None
+    }
+}
+
+fn assert_can_capture() {
+    if let Err(e) = pcap::Capture::from_device("lo").and_then(|cap| cap.open()) {
+        panic!("cannot capture on lo, run with CAP_NET_RAW or as root: {}", e);
+    }
+}
+
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// common arguments: receive on `port`, forward to `forward`, no capture.
+fn args(relay: &str, port: u16, forward: u16, extra: &[&str]) -> Vec<String> {
+    let mut args = vec![
+        "--relay".to_string(), relay.to_string(),
+        "--port".to_string(), port.to_string(),
+        "--forwards".to_string(), format!("127.0.0.1:{}", forward),
+        "--probe-timeout-secs".to_string(), "5".to_string(),
+    ];
+    if !extra.iter().any(|arg| *arg == "--interface" || *arg == "--sniff") {
+        args.push("--no-sniff".to_string());
+    }
+    args.extend(extra.iter().map(|arg| arg.to_string()));
+    args
+}
+
+fn local_regions() -> HashMap<String, String> {
+    HashMap::from([
+        ("local-a".to_string(), "127.0.0.1".to_string()),
+        ("local-b".to_string(), "127.0.0.1".to_string()),
+    ])
+}
+
+/// a merkle data shred the receiver accepts, distinct per `index`.
+fn shred(index: u32) -> Vec<u8> {
+    let mut shred = vec![0u8; 1228];
+    shred[..4].copy_from_slice(&index.to_le_bytes());
+    shred[64] = 0x86;
+    shred
+}
+
+async fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        if done() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+async fn admin_state(admin: &str) -> Option<serde_json::Value> {
+    let response = reqwest::get(format!("http://{}/state", admin)).await.ok()?;
+    response.json().await.ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwards_shreds_pushed_after_registration() {
+    let relay = MockRelay::new(Reply::Ok(register_ok(local_regions(), "")));
+    let (url, _server) = relay.serve().await.unwrap();
+    let validator = UdpSocket::bind("127.0.0.1:0").unwrap();
+    validator.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let forward = validator.local_addr().unwrap().port();
+
+    let _bin = ShredZdn::spawn(&args(&url, free_port(), forward, &["--auth", "test-key"]));
+    assert!(wait_for(|| relay.registered_addr().is_some()).await, "never registered");
+
+    let shreds = (0..10).map(shred).collect::<Vec<_>>();
+    relay.push_shreds(&shreds).await.unwrap();
+    // pushed twice, the duplicates must be dropped
+    relay.push_shreds(&shreds).await.unwrap();
+
+    let mut buf = [0u8; 2048];
+    let mut received = Vec::new();
+    while let Ok(len) = validator.recv(&mut buf) {
+        received.push(buf[..len].to_vec());
+        if received.len() == shreds.len() {
+            break;
+        }
+    }
+    received.sort();
+    assert_eq!(received, shreds);
+    validator.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(validator.recv(&mut buf).is_err(), "duplicate shred forwarded");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn registers_with_a_mapped_region_and_the_jwt() {
+    let relay = MockRelay::new(Reply::Ok(register_ok(local_regions(), "mock-jwt")));
+    let (url, _server) = relay.serve().await.unwrap();
+
+    let _bin = ShredZdn::spawn(&args(&url, free_port(), free_port(), &["--auth", "test-key"]));
+    let registered_region = || relay.registrations().iter().any(|r| local_regions().contains_key(&r.region));
+    assert!(wait_for(registered_region).await, "never registered with a mapped region");
+
+    let registrations = relay.registrations();
+    assert_eq!(registrations[0].region, "request-region");
+    assert_eq!(registrations[0].auth_header, "test-key");
+    let regional = registrations.iter().find(|r| r.region != "request-region").unwrap();
+    assert_eq!(regional.auth_header, "mock-jwt");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exits_with_the_code_of_the_registration_error() {
+    let cases = [
+        (Code::FailedPrecondition, RegisterErrorReason::Ipv6NotSupported, 1),
+        (Code::Unauthenticated, RegisterErrorReason::InvalidAuthKey, 2),
+        (Code::ResourceExhausted, RegisterErrorReason::IpLimitExceeded, 3),
+        (Code::FailedPrecondition, RegisterErrorReason::VersionUnsupported, 4),
+    ];
+    for (code, reason, exit_code) in cases {
+        let relay = MockRelay::new(Reply::Err(error_status(code, reason, "scripted")));
+        let (url, server) = relay.serve().await.unwrap();
+
+        let mut bin = ShredZdn::spawn(&args(&url, free_port(), free_port(), &["--auth", "test-key"]));
+        let status = bin.wait_exit().expect("shred-zdn did not exit");
+        assert_eq!(status.code(), Some(exit_code), "{:?}", reason);
+        server.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sniff_only_keeps_running_after_a_fatal_error() {
+    let relay = MockRelay::new(Reply::Ok(register_ok(local_regions(), "")));
+    relay.script([Reply::Err(error_status(Code::Unauthenticated, RegisterErrorReason::InvalidAuthKey, "scripted"))]);
+    let (url, _server) = relay.serve().await.unwrap();
+    let admin = format!("127.0.0.1:{}", free_port());
+
+    let mut bin = ShredZdn::spawn(&args(
+        &url, free_port(), free_port(),
+        &["--auth", "test-key", "--on-registration-error", "sniff-only", "--admin", &admin],
+    ));
+    let mut state = None;
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline && state.is_none() {
+        state = admin_state(&admin).await.filter(|state| state["reason"] == "sniff-only");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(state.expect("never degraded to sniff-only")["state"], "degraded");
+    assert!(bin.0.try_wait().unwrap().is_none(), "shred-zdn exited");
+
+    // registration stopped for good
+    let registrations = relay.registrations().len();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(relay.registrations().len(), registrations);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn standalone_never_registers() {
+    let relay = MockRelay::new(Reply::Ok(register_ok(local_regions(), "")));
+    let (url, _server) = relay.serve().await.unwrap();
+    let admin = format!("127.0.0.1:{}", free_port());
+
+    let validator = UdpSocket::bind("127.0.0.1:0").unwrap();
+    validator.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let port = free_port();
+    let _bin = ShredZdn::spawn(&args(
+        &url, port, validator.local_addr().unwrap().port(),
+        &["--standalone", "--static-region", "local=127.0.0.1", "--admin", &admin],
+    ));
+
+    let mut state = None;
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline && state.is_none() {
+        state = admin_state(&admin).await.filter(|state| state["regions"][0] == "local");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(state.expect("admin api never came up")["state"], "standalone");
+
+    // the receivers and the processor run without registration
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.send_to(&shred(7), ("127.0.0.1", port)).unwrap();
+    let mut buf = [0u8; 2048];
+    let len = validator.recv(&mut buf).expect("shred not forwarded");
+    assert_eq!(buf[..len], shred(7)[..]);
+    assert!(relay.registrations().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "captures on lo, needs CAP_NET_RAW or root"]
+async fn sends_sniffed_shreds_back_to_the_static_region() {
+    assert_can_capture();
+    let relay = MockRelay::new(Reply::Ok(register_ok(local_regions(), "")));
+    let (url, _server) = relay.serve().await.unwrap();
+
+    let region = UdpSocket::bind("127.0.0.1:0").unwrap();
+    region.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let send_back_port = region.local_addr().unwrap().port().to_string();
+    let sniffed = free_port();
+    let sniffed_port = sniffed.to_string();
+    let _bin = ShredZdn::spawn(&args(
+        &url, free_port(), free_port(),
+        &[
+            "--standalone", "--static-region", "local=127.0.0.1", "--send-back-port", &send_back_port,
+            "--interface", "lo", "--sniffer-port", &sniffed_port,
+        ],
+    ));
+
+    // the validator's turbine traffic, resent until the capture is up
+    let turbine = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut buf = [0u8; 2048];
+    let deadline = Instant::now() + TIMEOUT;
+    let (mut index, mut sent_back) = (0, None);
+    while Instant::now() < deadline && sent_back.is_none() {
+        turbine.send_to(&shred(index), ("127.0.0.1", sniffed)).unwrap();
+        index += 1;
+        sent_back = region.recv(&mut buf).ok().map(|len| buf[..len].to_vec());
+    }
+    // raw send-back in standalone mode: one of the shreds as sent
+    let sent_back = sent_back.expect("no sniffed shred sent back");
+    assert!((0..index).any(|index| shred(index) == sent_back), "not a sniffed shred");
+    assert!(relay.registrations().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inspect_prints_received_shred_headers() {
+    let port = free_port();
+    let mut bin = ShredZdn(
+        Command::new(BIN)
+            .args(["inspect", "--port", &port.to_string(), "--count", "1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("spawn shred-zdn"),
+    );
+
+    // resent until the subcommand is listening and exits after the first packet
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    let mut status = None;
+    while Instant::now() < deadline && status.is_none() {
+        sender.send_to(&shred(7), ("127.0.0.1", port)).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        status = bin.0.try_wait().unwrap();
+    }
+    assert!(status.expect("inspect did not exit").success());
+
+    let mut stdout = String::new();
+    std::io::Read::read_to_string(bin.0.stdout.as_mut().unwrap(), &mut stdout).unwrap();
+    assert!(stdout.contains("1228B slot 0 data 0 fec 0"), "{}", stdout);
+}